@@ -0,0 +1,156 @@
+use axum::handler::Handler;
+use axum::http::header::LINK;
+use axum::http::{HeaderValue, Method, Request};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, on, MethodFilter, MethodRouter};
+use axum::Router;
+
+use crate::archive::{download_report_handler, list_reports_handler};
+use crate::batch::batch_handler;
+use crate::constants::{API_V1, DEPRECATED_SINCE, DEPRECATION_HEADER, OPENAPI_PATH};
+use crate::forecast::forecast_handler;
+use crate::inventory::{
+    list_robots_handler, remove_robot_handler, restore_robot_handler, robot_details_handler,
+};
+use crate::openapi;
+use crate::plan::{
+    create_plan_handler, delete_plan_handler, get_plan_handler, list_plans_handler,
+    update_plan_handler,
+};
+use crate::processing::order_robot;
+use crate::report::report_handler;
+use crate::robot::{create_robot_handler, remove_robot_request_handler};
+use crate::sales::sales_report_handler;
+use crate::state::AppState;
+use crate::stock::{delete_threshold_handler, list_thresholds_handler, set_threshold_handler};
+use crate::user::create_customer;
+use crate::v2;
+
+// Every version is nested under its own prefix in `router_with_state`.
+// A route whose payload changes in a new version moves from `common` into
+// `v1` and the new version's own table, the others stay shared.
+// The tests compare the same tables with the OpenAPI document of each version.
+
+// One method of one path and the handler that answers it
+pub struct Operation {
+    // Only read by tests, the handler already answers this method alone
+    #[cfg(test)]
+    pub method: Method,
+    pub path: &'static str,
+    handler: MethodRouter<AppState>,
+}
+
+fn operation<H, T>(method: Method, path: &'static str, handler: H) -> Operation
+where
+    H: Handler<T, AppState>,
+    T: 'static,
+{
+    let filter = MethodFilter::try_from(method.clone()).expect("Unsupported method");
+    Operation {
+        #[cfg(test)]
+        method,
+        path,
+        handler: on(filter, handler),
+    }
+}
+
+// Operations of the same path are merged into one route
+fn router(operations: Vec<Operation>) -> Router<AppState> {
+    operations
+        .into_iter()
+        .fold(Router::new(), |router, operation| {
+            router.route(operation.path, operation.handler)
+        })
+}
+
+// Routes that are the same in every version
+fn common() -> Vec<Operation> {
+    vec![
+        operation(Method::GET, "/robots", list_robots_handler),
+        operation(Method::GET, "/robots/report", report_handler),
+        operation(Method::GET, "/reports", list_reports_handler),
+        operation(Method::GET, "/reports/sales", sales_report_handler),
+        operation(Method::GET, "/reports/:id", download_report_handler),
+        operation(Method::POST, "/robots/remove", remove_robot_request_handler),
+        operation(Method::POST, "/robots/batch", batch_handler),
+        operation(Method::POST, "/robots/order", order_robot),
+        operation(Method::GET, "/robots/:serial", robot_details_handler),
+        operation(Method::DELETE, "/robots/:serial", remove_robot_handler),
+        operation(
+            Method::POST,
+            "/robots/:serial/restore",
+            restore_robot_handler,
+        ),
+        operation(Method::GET, "/analytics/forecast", forecast_handler),
+        operation(Method::GET, "/plans", list_plans_handler),
+        operation(Method::POST, "/plans", create_plan_handler),
+        operation(Method::GET, "/plans/:id", get_plan_handler),
+        operation(Method::PUT, "/plans/:id", update_plan_handler),
+        operation(Method::DELETE, "/plans/:id", delete_plan_handler),
+        operation(Method::GET, "/stock/thresholds", list_thresholds_handler),
+        operation(
+            Method::PUT,
+            "/stock/thresholds/:model/:version",
+            set_threshold_handler,
+        ),
+        operation(
+            Method::DELETE,
+            "/stock/thresholds/:model/:version",
+            delete_threshold_handler,
+        ),
+        operation(Method::POST, "/user/create", create_customer),
+    ]
+}
+
+pub fn v1_operations() -> Vec<Operation> {
+    let mut operations = common();
+    operations.push(operation(
+        Method::POST,
+        "/robots/create",
+        create_robot_handler,
+    ));
+    operations
+}
+
+pub fn v2_operations() -> Vec<Operation> {
+    let mut operations = common();
+    operations.push(operation(
+        Method::POST,
+        "/robots/create",
+        v2::create_robot_handler,
+    ));
+    operations
+}
+
+pub fn v1() -> Router<AppState> {
+    router(v1_operations())
+}
+
+pub fn v2() -> Router<AppState> {
+    router(v2_operations())
+}
+
+// The unversioned paths firmware and older clients still call, they answer like v1
+pub fn legacy() -> Router<AppState> {
+    v1().route(OPENAPI_PATH, get(openapi::v1_handler))
+        .layer(middleware::from_fn(deprecated))
+}
+
+// Marks the response as deprecated since DEPRECATED_SINCE and links the same path under /api/v1
+async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+    let successor = format!(
+        "<{API_V1}{}>; rel=\"successor-version\"",
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    if let Ok(since) = HeaderValue::from_str(&format!("@{DEPRECATED_SINCE}")) {
+        headers.insert(DEPRECATION_HEADER, since);
+    }
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(LINK, link);
+    }
+    response
+}
@@ -0,0 +1,199 @@
+use axum::extract::State;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::constants::REPORT_ID_HEADER;
+use crate::db::insert_returning_id;
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppPath, AppQuery};
+use crate::report_format::{Report, ReportFormat};
+use crate::repository::{ArchiveRepository, Repositories, RepositoryResult};
+
+// Archive entry without its content
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct ArchivedReport {
+    pub id: i32,
+    pub kind: String,
+    pub format: String,
+    pub period_from: NaiveDate,
+    pub period_to: NaiveDate,
+    pub timezone: String,
+    pub generated_at: NaiveDateTime,
+    pub source: String,
+    pub filename: String,
+    pub size: i32,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArchiveQuery {
+    // `production` or `sales`
+    pub kind: Option<String>,
+}
+
+// Who asked for the report to be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportSource {
+    Scheduled,
+    Manual,
+}
+
+impl ReportSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReportSource::Scheduled => "scheduled",
+            ReportSource::Manual => "manual",
+        }
+    }
+}
+
+// A rendered report on its way into the archive
+pub struct ReportEntry<'a> {
+    pub kind: &'static str,
+    pub format: ReportFormat,
+    pub period_from: NaiveDate,
+    pub period_to: NaiveDate,
+    pub timezone: &'a str,
+    pub generated_at: NaiveDateTime,
+    pub source: ReportSource,
+    pub filename: String,
+    pub content: &'a [u8],
+}
+
+// Stores a rendered report in the archive and returns its id
+pub async fn archive_report<R: Report>(
+    archive: &dyn ArchiveRepository,
+    report: &R,
+    format: ReportFormat,
+    source: ReportSource,
+    content: &[u8],
+) -> RepositoryResult<i32> {
+    let range = report.range();
+    let entry = ReportEntry {
+        kind: report.kind(),
+        format,
+        period_from: range.from,
+        period_to: range.to,
+        timezone: range.tz.name(),
+        generated_at: Utc::now().naive_utc(),
+        source,
+        filename: format!("{}.{}", report.file_stem(), format.extension()),
+        content,
+    };
+    archive.archive_report(&entry).await
+}
+
+// Stores the entry in the `reports` table and returns its id
+pub async fn insert_report(pool: &DbPool, entry: &ReportEntry<'_>) -> sqlx::Result<i32> {
+    let sql = "INSERT INTO reports
+        (kind, format, period_from, period_to, timezone, generated_at, source, filename, content_type, content)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id";
+
+    let query = sqlx::query_scalar(sql)
+        .bind(entry.kind)
+        .bind(entry.format.extension())
+        .bind(entry.period_from)
+        .bind(entry.period_to)
+        .bind(entry.timezone)
+        .bind(entry.generated_at)
+        .bind(entry.source.as_str())
+        .bind(&entry.filename)
+        .bind(entry.format.content_type())
+        .bind(entry.content);
+    insert_returning_id(query, pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+// Renders the report, stores it in the archive and returns the download response.
+// The archive id is sent in the X-Report-Id header.
+pub async fn archived_response<R: Report>(
+    archive: &dyn ArchiveRepository,
+    report: &R,
+    format: ReportFormat,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let content = format.render(report).map_err(|err| {
+        AppError::internal(format!(
+            "Failed to create {} report: {}",
+            format.extension(),
+            err
+        ))
+    })?;
+
+    let id = archive_report(archive, report, format, ReportSource::Manual, &content).await?;
+
+    let mut headers = format.headers(report);
+    headers.insert(REPORT_ID_HEADER, HeaderValue::from(id));
+
+    Ok((headers, content))
+}
+
+pub async fn list_reports(pool: &DbPool, kind: Option<&str>) -> sqlx::Result<Vec<ArchivedReport>> {
+    let sql = "SELECT id, kind, format, period_from, period_to, timezone, generated_at, source,
+        filename, length(content) AS size FROM reports
+        WHERE CAST($1 AS TEXT) IS NULL OR kind = $1 ORDER BY generated_at DESC, id DESC";
+
+    sqlx::query_as(sql).bind(kind).fetch_all(pool).await
+}
+
+// Returns (filename, content type, content)
+pub async fn fetch_report(
+    pool: &DbPool,
+    id: i32,
+) -> sqlx::Result<Option<(String, String, Vec<u8>)>> {
+    let sql = "SELECT filename, content_type, content FROM reports WHERE id = $1";
+
+    sqlx::query_as(sql).bind(id).fetch_optional(pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/reports",
+    tag = "reports",
+    params(ArchiveQuery),
+    responses((status = 200, description = "Archived reports, newest first", body = [ArchivedReport]))
+)]
+pub async fn list_reports_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<ArchiveQuery>,
+) -> Result<Json<Vec<ArchivedReport>>, AppError> {
+    Ok(Json(
+        repos.archive.list_reports(query.kind.as_deref()).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/reports/{id}",
+    tag = "reports",
+    params(("id" = i32, Path, description = "Id of the archived report")),
+    responses(
+        (status = 200, description = "The report exactly as it was generated", body = [u8], content_type = [
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "text/csv", "application/json", "application/pdf"
+        ]),
+        (status = 404, description = "No such report", body = AppError),
+    )
+)]
+pub async fn download_report_handler(
+    State(repos): State<Repositories>,
+    AppPath(id): AppPath<i32>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some((filename, content_type, content)) = repos.archive.fetch_report(id).await? else {
+        return Err(AppError::not_found(format!("Report {id} not found")));
+    };
+
+    let mut headers = HeaderMap::new();
+    let content_type =
+        HeaderValue::from_str(&content_type).map_err(|e| AppError::internal(e.to_string()))?;
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{filename}\""))
+        .map_err(|e| AppError::internal(e.to_string()))?;
+    headers.insert(CONTENT_TYPE, content_type);
+    headers.insert(CONTENT_DISPOSITION, disposition);
+
+    Ok((headers, content))
+}
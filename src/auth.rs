@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+use crate::constants::API_TOKENS;
+use crate::error::{AppError, ErrorCode};
+
+// Staff roles, carried by bearer tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Technician,
+    Manager,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role.trim().to_ascii_lowercase().as_str() {
+            "technician" => Some(Role::Technician),
+            "manager" => Some(Role::Manager),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Technician => "technician",
+            Role::Manager => "manager",
+        }
+    }
+
+    // Only managers may remove robots that are sold or reserved
+    pub fn can_force_removal(self) -> bool {
+        self == Role::Manager
+    }
+}
+
+// Comma separated `token:role` pairs, overridden with the API_TOKENS environment variable.
+// Entries with an unknown role are skipped.
+pub fn api_tokens() -> HashMap<String, Role> {
+    std::env::var("API_TOKENS")
+        .unwrap_or_else(|_| API_TOKENS.to_string())
+        .split(',')
+        .filter_map(|entry| {
+            let (token, role) = entry.trim().split_once(':')?;
+            match Role::parse(role) {
+                Some(role) if !token.is_empty() => Some((token.to_string(), role)),
+                _ => {
+                    eprintln!("Skipping invalid API token entry for role {role}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Role of the `Authorization: Bearer <token>` header, 401 if it is missing or unknown
+pub fn role_from_headers(headers: &HeaderMap) -> Result<Role, AppError> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| AppError::new(ErrorCode::Unauthorized, "A bearer token is required"))?;
+
+    api_tokens()
+        .get(token)
+        .copied()
+        .ok_or_else(|| AppError::new(ErrorCode::Unauthorized, "Unknown API token"))
+}
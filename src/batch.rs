@@ -0,0 +1,258 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::constants::BATCH_MAX_ROWS;
+use crate::error::{AppError, AppQuery, ErrorCode};
+use crate::metrics;
+use crate::repository::{Repositories, RepositoryResult, RobotRepository};
+use crate::robot::{format_serial, Robot};
+
+// Formats accepted by POST /robots/batch, chosen by the Content-Type header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl BatchFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/json" => Some(BatchFormat::Json),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Some(BatchFormat::Ndjson)
+            }
+            "text/csv" => Some(BatchFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+// `atomic` creates every robot or none of them, `partial` creates the valid rows
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchMode {
+    #[default]
+    Atomic,
+    Partial,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BatchQuery {
+    pub mode: Option<BatchMode>,
+}
+
+// `row` is the 1-based position of the robot in the upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct RowError {
+    pub row: usize,
+    pub serial: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BatchSummary {
+    pub mode: BatchMode,
+    pub received: usize,
+    pub created: Vec<String>,
+    pub errors: Vec<RowError>,
+}
+
+// A robot of the upload with its row number, counted from 1
+pub type BatchRow = (usize, Result<Robot, String>);
+
+// Splits the upload into robots, rows that cannot be read are returned as errors.
+// Fails only if the upload as a whole is malformed.
+pub fn parse_batch(format: BatchFormat, body: &[u8]) -> Result<Vec<BatchRow>, String> {
+    let rows = match format {
+        BatchFormat::Json => {
+            let values: Vec<serde_json::Value> = serde_json::from_slice(body)
+                .map_err(|e| format!("Expected a JSON array of robots: {e}"))?;
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    let robot = serde_json::from_value(value).map_err(|e| e.to_string());
+                    (index + 1, robot)
+                })
+                .collect()
+        }
+        BatchFormat::Ndjson => {
+            let text = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+            // Blank lines are skipped but still counted, rows keep their line number
+            text.lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| {
+                    let robot = serde_json::from_str(line).map_err(|e| e.to_string());
+                    (index + 1, robot)
+                })
+                .collect()
+        }
+        BatchFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body)
+            .deserialize()
+            .enumerate()
+            .map(|(index, row)| (index + 1, row.map_err(|e| e.to_string())))
+            .collect(),
+    };
+    Ok(rows)
+}
+
+// Checks every row and assigns serial numbers to robots sent with serial "0".
+// `existing` holds the serials already stored that the upload uses,
+// `counts` the number of robots per model used to number the new ones.
+pub fn prepare_rows(
+    rows: Vec<BatchRow>,
+    existing: &HashSet<String>,
+    counts: &mut HashMap<String, i64>,
+) -> (Vec<Robot>, Vec<RowError>) {
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (row_number, row) in rows {
+        let mut robot = match row {
+            Ok(robot) => robot,
+            Err(message) => {
+                errors.push(RowError {
+                    row: row_number,
+                    serial: None,
+                    message,
+                });
+                continue;
+            }
+        };
+        if let Err(e) = robot.validate() {
+            errors.push(RowError {
+                row: row_number,
+                serial: Some(robot.serial),
+                message: e.to_string(),
+            });
+            continue;
+        }
+
+        if robot.serial == "0" {
+            let count = counts.entry(robot.model.clone()).or_default();
+            *count += 1;
+            robot.serial = format_serial(&robot.model, *count);
+        }
+        if existing.contains(&robot.serial) || !seen.insert(robot.serial.clone()) {
+            errors.push(RowError {
+                row: row_number,
+                message: format!("Serial {} already exists", robot.serial),
+                serial: Some(robot.serial),
+            });
+            continue;
+        }
+        valid.push(robot);
+    }
+
+    (valid, errors)
+}
+
+// Validates and inserts the robots in one step, nothing is inserted in atomic
+// mode if any row is invalid. The stock of every version made is checked afterwards.
+pub async fn import_robots(
+    robots: &dyn RobotRepository,
+    rows: Vec<BatchRow>,
+    mode: BatchMode,
+) -> RepositoryResult<BatchSummary> {
+    let received = rows.len();
+    let (created, errors) = robots
+        .insert_robots(rows, mode, Utc::now().naive_utc())
+        .await?;
+    for robot in &created {
+        metrics::robot_created(&robot.model);
+    }
+
+    let produced: BTreeSet<(&String, &String)> = created
+        .iter()
+        .map(|robot| (&robot.model, &robot.version))
+        .collect();
+    for (model, version) in produced {
+        if let Err(e) = robots.check_stock(model, version).await {
+            eprintln!("Stock check failed: {e}");
+        }
+    }
+
+    Ok(BatchSummary {
+        mode,
+        received,
+        created: created.into_iter().map(|robot| robot.serial).collect(),
+        errors,
+    })
+}
+
+// The body extractor must be the last argument
+#[utoipa::path(
+    post,
+    path = "/robots/batch",
+    tag = "robots",
+    params(BatchQuery),
+    request_body(
+        content = [Robot],
+        description = "A JSON array, or the same robots as application/x-ndjson or as text/csv \
+            with the columns serial,model,version",
+        content_type = "application/json"
+    ),
+    responses(
+        (status = 201, description = "Every robot created", body = BatchSummary),
+        (status = 200, description = "Some robots created, the rest are listed in `errors`", body = BatchSummary),
+        (status = 400, description = "Malformed or empty upload", body = AppError),
+        (status = 413, description = "Too many robots", body = AppError),
+        (status = 415, description = "Unsupported Content-Type", body = AppError),
+        (status = 422, description = "No robot created", body = BatchSummary),
+    )
+)]
+pub async fn batch_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<BatchQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<BatchSummary>), AppError> {
+    let format = BatchFormat::from_headers(&headers).ok_or_else(|| {
+        AppError::new(
+            ErrorCode::UnsupportedMediaType,
+            "Expected application/json, application/x-ndjson or text/csv",
+        )
+    })?;
+    let rows = parse_batch(format, &body).map_err(AppError::bad_request)?;
+    if rows.is_empty() {
+        return Err(AppError::bad_request("The batch is empty"));
+    }
+    if rows.len() > BATCH_MAX_ROWS {
+        return Err(AppError::new(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "At most {BATCH_MAX_ROWS} robots per batch, got {}",
+                rows.len()
+            ),
+        ));
+    }
+
+    let mode = query.mode.unwrap_or_default();
+    let summary = import_robots(repos.robots.as_ref(), rows, mode).await?;
+
+    let status = if summary.created.is_empty() && !summary.errors.is_empty() {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else if summary.errors.is_empty() {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(summary)))
+}
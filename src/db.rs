@@ -1,9 +1,36 @@
-use axum::http::StatusCode;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
 use regex::Regex;
-use sqlx::{postgres::PgPool, Error, Executor};
+use sqlx::database::HasArguments;
+use sqlx::query::QueryScalar;
+use sqlx::{Error, Executor, Transaction};
 use validator::ValidationError;
 
-use crate::constants::DATABASE_URL;
+use crate::archive::{self, ArchivedReport, ReportEntry};
+use crate::batch::{prepare_rows, BatchMode, BatchRow, RowError};
+use crate::constants::{BATCH_INSERT_ROWS, SCHEMA_VERSION, SELL_ATTEMPTS};
+use crate::db_pool::{Backend, DbPool};
+use crate::error::is_unique_violation;
+use crate::forecast::{self, Demand};
+use crate::inventory::{cursor_time, RobotDetails, RobotRecord, RobotSort, SaleRecord, SortOrder};
+use crate::notification::{Mailer, SmtpMailer};
+use crate::order::Order;
+use crate::period::ReportRange;
+use crate::plan::{self, PlanInput, PlanQuery, ProductionPlan};
+use crate::report;
+use crate::repository::{
+    ArchiveRepository, CustomerRepository, Decommission, OrderRepository, PlanRepository,
+    ReportRepository, RepositoryError, RepositoryResult, RobotFilter, RobotRepository,
+    StockRepository,
+};
+use crate::robot::Robot;
+use crate::sales::{self, SalesFigures};
+use crate::stock::{self, StockThreshold};
+use crate::user::Customer;
 
 pub fn validate_model_version(value: &str) -> Result<(), ValidationError> {
     let re = Regex::new(r"^[A-Za-z][0-9]$").unwrap();
@@ -16,80 +43,323 @@ pub fn validate_model_version(value: &str) -> Result<(), ValidationError> {
     Ok(())
 }
 
+// "$first, $first+1, ..." for `count` bind parameters, for IN lists and multi-row inserts
+pub fn placeholders(first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|i| format!("${i}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Runs an INSERT ... RETURNING id and returns the id, None if nothing was inserted.
+// SQLite commits such an INSERT only once all of its rows are read, so they all are.
+pub async fn insert_returning_id<'q, 'c, E>(
+    query: QueryScalar<'q, Backend, i32, <Backend as HasArguments<'q>>::Arguments>,
+    executor: E,
+) -> sqlx::Result<Option<i32>>
+where
+    E: Executor<'c, Database = Backend>,
+{
+    Ok(query.fetch_all(executor).await?.pop())
+}
+
+// Makes other writers of `table` wait until the transaction ends, readers go on.
+// SQLite has no table locks, the first write of a transaction locks the whole
+// database until it ends, even if it changes nothing. The table needs an `id` column.
+pub async fn lock_table(tx: &mut Transaction<'_, Backend>, table: &str) -> sqlx::Result<()> {
+    let sql = if cfg!(feature = "sqlite") {
+        format!("UPDATE {table} SET id = id WHERE FALSE")
+    } else {
+        format!("LOCK TABLE {table} IN SHARE ROW EXCLUSIVE MODE")
+    };
+
+    sqlx::query(&sql).execute(tx).await?;
+    Ok(())
+}
+
+// Whether setup has created the schema_version table, in the current search_path on Postgres
+#[cfg(not(feature = "sqlite"))]
+const HAS_SCHEMA_VERSION: &str = "SELECT to_regclass('schema_version') IS NOT NULL";
+#[cfg(feature = "sqlite")]
+const HAS_SCHEMA_VERSION: &str =
+    "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')";
+
+// Whether the robot is kept for a waiting customer: fewer robots in stock
+// are ahead of it than customers are waiting
+const IS_RESERVED: &str = "SELECT r.decommissioned_at IS NULL
+        AND NOT EXISTS (SELECT 1 FROM sold WHERE sold.robot_id = r.id)
+        AND (SELECT COUNT(*) FROM robots s
+            WHERE s.model = r.model AND s.version = r.version AND s.decommissioned_at IS NULL
+            AND NOT EXISTS (SELECT 1 FROM sold WHERE sold.robot_id = s.id)
+            AND (s.created, s.id) < (r.created, r.id))
+        < (SELECT COUNT(*) FROM waitlist w
+            WHERE w.model = r.model AND w.version = r.version AND w.notified IS NULL)
+    FROM robots r WHERE r.id = $1";
+
+// Column types and statements that differ between the backends
+#[cfg(not(feature = "sqlite"))]
+const ID_COLUMN: &str = "id SERIAL PRIMARY KEY";
+#[cfg(feature = "sqlite")]
+const ID_COLUMN: &str = "id INTEGER PRIMARY KEY AUTOINCREMENT";
+
+#[cfg(not(feature = "sqlite"))]
+const BYTES_TYPE: &str = "BYTEA";
+#[cfg(feature = "sqlite")]
+const BYTES_TYPE: &str = "BLOB";
+
+#[cfg(not(feature = "sqlite"))]
+const REPORTS_IMMUTABLE: &str =
+    "CREATE OR REPLACE FUNCTION reports_immutable() RETURNS trigger AS $$
+    BEGIN
+        RAISE EXCEPTION 'archived reports are immutable';
+    END;
+    $$ LANGUAGE plpgsql;
+
+    DROP TRIGGER IF EXISTS reports_no_change ON reports;
+    CREATE TRIGGER reports_no_change BEFORE UPDATE OR DELETE ON reports
+        FOR EACH ROW EXECUTE FUNCTION reports_immutable();
+
+    DROP TRIGGER IF EXISTS reports_no_truncate ON reports;
+    CREATE TRIGGER reports_no_truncate BEFORE TRUNCATE ON reports
+        FOR EACH STATEMENT EXECUTE FUNCTION reports_immutable();";
+// SQLite has no TRUNCATE, a DELETE without WHERE still fires the row triggers
+#[cfg(feature = "sqlite")]
+const REPORTS_IMMUTABLE: &str =
+    "CREATE TRIGGER IF NOT EXISTS reports_no_update BEFORE UPDATE ON reports
+    BEGIN SELECT RAISE(ABORT, 'archived reports are immutable'); END;
+
+    CREATE TRIGGER IF NOT EXISTS reports_no_delete BEFORE DELETE ON reports
+    BEGIN SELECT RAISE(ABORT, 'archived reports are immutable'); END;";
+
 pub struct Database {
-    pub pool: PgPool,
+    pub pool: DbPool,
+    // Sends the low stock alerts of `check_stock`
+    mailer: Arc<dyn Mailer>,
 }
 
 impl Database {
-    pub async fn new() -> Result<Self, StatusCode> {
-        match PgPool::connect(DATABASE_URL).await {
-            Ok(pool) => Ok(Self { pool }),
-            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-        }
+    // Cloning a pool only copies a handle to it
+    pub fn new(pool: DbPool) -> Self {
+        Self::with_mailer(pool, Arc::new(SmtpMailer))
+    }
+
+    pub fn with_mailer(pool: DbPool, mailer: Arc<dyn Mailer>) -> Self {
+        Self { pool, mailer }
     }
 
     pub async fn setup_database(&self) -> Result<(), Error> {
         self.pool
             .execute(
-                "CREATE TABLE IF NOT EXISTS robots (
-            id SERIAL PRIMARY KEY,
+                format!(
+                    "CREATE TABLE IF NOT EXISTS robots (
+            {ID_COLUMN},
             serial TEXT NOT NULL,
             model TEXT NOT NULL,
             version TEXT NOT NULL,
             created TIMESTAMP NOT NULL
-            )",
+            )"
+                )
+                .as_str(),
             )
             .await?;
 
+        // Removed robots are only marked, so that their sales and reports stay intact
+        self.add_column("robots", "decommissioned_at", "TIMESTAMP")
+            .await?;
+        self.add_column("robots", "decommission_reason", "TEXT")
+            .await?;
+
         self.pool
             .execute(
-                "CREATE TABLE IF NOT EXISTS customers (
-            id SERIAL PRIMARY KEY,
+                format!(
+                    "CREATE TABLE IF NOT EXISTS customers (
+            {ID_COLUMN},
             name TEXT NOT NULL,
             email TEXT NOT NULL UNIQUE,
             login TEXT NOT NULL UNIQUE,
             password TEXT NOT NULL
-            )",
+            )"
+                )
+                .as_str(),
             )
             .await?;
 
         self.pool
             .execute(
-                "CREATE TABLE IF NOT EXISTS orders (
-            id SERIAL PRIMARY KEY,
+                format!(
+                    "CREATE TABLE IF NOT EXISTS orders (
+            {ID_COLUMN},
             customer_name TEXT NOT NULL,
             robot_model TEXT NOT NULL,
             order_date TIMESTAMP NOT NULL
-            )",
+            )"
+                )
+                .as_str(),
             )
             .await?;
 
         // SOLD related to robots and customers
         self.pool
             .execute(
-                "CREATE TABLE IF NOT EXISTS sold (
-            id SERIAL PRIMARY KEY,
+                format!(
+                    "CREATE TABLE IF NOT EXISTS sold (
+            {ID_COLUMN},
             robot_id INTEGER NOT NULL,
             customer_id INTEGER NOT NULL,
             sold_date TIMESTAMP NOT NULL,
             FOREIGN KEY (robot_id) REFERENCES robots (id) ON DELETE CASCADE,
             FOREIGN KEY (customer_id) REFERENCES customers (id)
+            )"
+                )
+                .as_str(),
+            )
+            .await?;
+
+        // A robot is sold at most once, concurrent orders for the same robot conflict here
+        self.pool
+            .execute("CREATE UNIQUE INDEX IF NOT EXISTS sold_robot_id ON sold (robot_id)")
+            .await?;
+
+        // Orders that could not be served from stock, `notified` is set once the customer is emailed
+        self.pool
+            .execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS waitlist (
+            {ID_COLUMN},
+            login TEXT NOT NULL,
+            model TEXT NOT NULL,
+            version TEXT NOT NULL,
+            requested TIMESTAMP NOT NULL,
+            notified TIMESTAMP
+            )"
+                )
+                .as_str(),
+            )
+            .await?;
+
+        // Production quota per model/version, `period_end` is inclusive
+        self.pool
+            .execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS production_plans (
+            {ID_COLUMN},
+            model TEXT NOT NULL,
+            version TEXT NOT NULL,
+            period_start DATE NOT NULL,
+            period_end DATE NOT NULL,
+            target INTEGER NOT NULL CHECK (target >= 0),
+            CHECK (period_end >= period_start)
+            )"
+                )
+                .as_str(),
+            )
+            .await?;
+
+        // Minimum stock per model/version, `alerting` is set while a low stock alert is active
+        self.pool
+            .execute(
+                "CREATE TABLE IF NOT EXISTS stock_thresholds (
+            model TEXT NOT NULL,
+            version TEXT NOT NULL,
+            minimum INTEGER NOT NULL CHECK (minimum >= 0),
+            recovery INTEGER NOT NULL CHECK (recovery >= minimum),
+            alerting BOOLEAN NOT NULL DEFAULT FALSE,
+            alerted_at TIMESTAMP,
+            PRIMARY KEY (model, version)
             )",
             )
             .await?;
 
-        Ok(())
-    }
+        // Generated reports, kept so that past figures can be reproduced
+        self.pool
+            .execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS reports (
+            {ID_COLUMN},
+            kind TEXT NOT NULL,
+            format TEXT NOT NULL,
+            period_from DATE NOT NULL,
+            period_to DATE NOT NULL,
+            timezone TEXT NOT NULL,
+            generated_at TIMESTAMP NOT NULL,
+            source TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            content {BYTES_TYPE} NOT NULL
+            )"
+                )
+                .as_str(),
+            )
+            .await?;
+
+        // Archived reports are audit records and must never change once generated
+        self.pool.execute(REPORTS_IMMUTABLE).await?;
 
-    pub async fn get_robots_by_date(&self, date: &str) -> Result<i64, sqlx::Error> {
-        let count: (i64,) = sqlx::query_as(
-            r"SELECT COUNT(*) FROM robots WHERE created <= TO_TIMESTAMP($1, 'YYYY-MM-DD HH24:MI:SS')",
+        // Versions the setup above has been run for, checked by /health/ready
+        self.pool
+            .execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TIMESTAMP NOT NULL
+            )",
+            )
+            .await?;
+        sqlx::query(
+            "INSERT INTO schema_version (version, applied_at) SELECT $1, $2
+            WHERE NOT EXISTS (SELECT 1 FROM schema_version WHERE version = $1)",
         )
-        .bind(date)
-        .fetch_one(&self.pool)
+        .bind(SCHEMA_VERSION)
+        .bind(Utc::now().naive_utc())
+        .execute(&self.pool)
         .await?;
 
-        Ok(count.0)
+        Ok(())
+    }
+
+    // Latest version the database was set up with, None before the first setup
+    pub async fn schema_version(&self) -> Result<Option<i32>, Error> {
+        let exists: bool = sqlx::query_scalar(HAS_SCHEMA_VERSION)
+            .fetch_one(&self.pool)
+            .await?;
+        if !exists {
+            return Ok(None);
+        }
+        sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    async fn add_column(&self, table: &str, column: &str, column_type: &str) -> Result<(), Error> {
+        let sql = format!("ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} {column_type}");
+
+        self.pool.execute(sql.as_str()).await?;
+        Ok(())
+    }
+
+    // SQLite cannot add a column only if it is missing
+    #[cfg(feature = "sqlite")]
+    async fn add_column(&self, table: &str, column: &str, column_type: &str) -> Result<(), Error> {
+        let sql = "SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2";
+        let exists: i64 = sqlx::query_scalar(sql)
+            .bind(table)
+            .bind(column)
+            .fetch_one(&self.pool)
+            .await?;
+
+        if exists == 0 {
+            let sql = format!("ALTER TABLE {table} ADD COLUMN {column} {column_type}");
+            self.pool.execute(sql.as_str()).await?;
+        }
+        Ok(())
+    }
+
+    // Robots made by `at` and not decommissioned by then, times are in UTC
+    pub async fn get_robots_by_date(&self, at: NaiveDateTime) -> Result<i64, sqlx::Error> {
+        let sql = "SELECT COUNT(*) FROM robots WHERE created <= $1
+            AND (decommissioned_at IS NULL OR decommissioned_at > $1)";
+
+        sqlx::query_scalar(sql).bind(at).fetch_one(&self.pool).await
     }
 
     // Check login and password in the database if found - return user email
@@ -107,8 +377,10 @@ impl Database {
             .await
     }
 
+    // Robots of the model and version that are in stock, i.e. neither sold nor decommissioned
     pub async fn find_robot(&self, model: &str, version: &str) -> sqlx::Result<i64> {
-        let sql = "SELECT COUNT (*) FROM robots WHERE model = $1 AND version = $2";
+        let sql = "SELECT COUNT (*) FROM robots r WHERE model = $1 AND version = $2
+            AND r.decommissioned_at IS NULL AND NOT EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id)";
 
         sqlx::query_scalar(sql)
             .bind(model)
@@ -116,4 +388,556 @@ impl Database {
             .fetch_one(&self.pool)
             .await
     }
+
+    // Records the sale of the oldest robot in stock to the customer, returns the number of robots sold
+    pub async fn sell_robot(&self, login: &str, model: &str, version: &str) -> sqlx::Result<u64> {
+        let sold = self
+            .sell_robot_at(login, model, version, Utc::now().naive_utc())
+            .await?;
+        Ok(sold.map_or(0, |_| 1))
+    }
+
+    // Sells like sell_robot at the given time and returns the id of the robot sold.
+    // Sales wait while a robot is decommissioned. Two orders may still pick the same
+    // robot at once, the one losing on sold_robot_id tries the next robot.
+    pub async fn sell_robot_at(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+        at: NaiveDateTime,
+    ) -> sqlx::Result<Option<i32>> {
+        let sql = "INSERT INTO sold (robot_id, customer_id, sold_date)
+            SELECT r.id, c.id, $4 FROM robots r, customers c
+            WHERE r.model = $1 AND r.version = $2 AND c.login = $3 AND r.decommissioned_at IS NULL
+            AND NOT EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id)
+            ORDER BY r.created, r.id LIMIT 1
+            RETURNING robot_id";
+
+        let mut attempt = 1;
+        loop {
+            let query = sqlx::query_scalar(sql)
+                .bind(model)
+                .bind(version)
+                .bind(login)
+                .bind(at);
+
+            match insert_returning_id(query, &self.pool).await {
+                Ok(id) => return Ok(id),
+                Err(e) if attempt < SELL_ATTEMPTS && is_unique_violation(&e) => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn add_to_waitlist(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> sqlx::Result<u64> {
+        let sql = "INSERT INTO waitlist (login, model, version, requested) VALUES ($1, $2, $3, $4)";
+
+        sqlx::query(sql)
+            .bind(login)
+            .bind(model)
+            .bind(version)
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+
+    // Marks the customer's open waitlist entries for the model and version as notified,
+    // returns when each of them was requested
+    pub async fn mark_notified(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> sqlx::Result<Vec<NaiveDateTime>> {
+        let sql = "UPDATE waitlist SET notified = $4
+            WHERE login = $1 AND model = $2 AND version = $3 AND notified IS NULL
+            RETURNING requested";
+
+        sqlx::query_scalar(sql)
+            .bind(login)
+            .bind(model)
+            .bind(version)
+            .bind(Utc::now().naive_utc())
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+#[async_trait]
+impl RobotRepository for Database {
+    async fn count_model(&self, model: &str) -> RepositoryResult<i64> {
+        let sql = "SELECT COUNT(*) FROM robots WHERE model = $1";
+
+        Ok(sqlx::query_scalar(sql)
+            .bind(model)
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn insert_robot(
+        &self,
+        serial: &str,
+        model: &str,
+        version: &str,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<()> {
+        let sql = "INSERT INTO robots (serial, model, version, created) VALUES ($1, $2, $3, $4)";
+
+        sqlx::query(sql)
+            .bind(serial)
+            .bind(model)
+            .bind(version)
+            .bind(created)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_robots(
+        &self,
+        rows: Vec<BatchRow>,
+        mode: BatchMode,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<(Vec<Robot>, Vec<RowError>)> {
+        let mut tx = self.pool.begin().await?;
+        // Serial numbers are derived from the current robots, concurrent imports must wait
+        lock_table(&mut tx, "robots").await?;
+
+        let serials: Vec<String> = rows
+            .iter()
+            .filter_map(|(_, row)| row.as_ref().ok().map(|robot| robot.serial.clone()))
+            .collect();
+        let mut existing: Vec<String> = Vec::new();
+        if !serials.is_empty() {
+            let sql = format!(
+                "SELECT DISTINCT serial FROM robots WHERE serial IN ({})",
+                placeholders(1, serials.len())
+            );
+            let mut query = sqlx::query_scalar(&sql);
+            for serial in &serials {
+                query = query.bind(serial);
+            }
+            existing = query.fetch_all(&mut tx).await?;
+        }
+        let models: Vec<String> = rows
+            .iter()
+            .filter_map(|(_, row)| row.as_ref().ok().map(|robot| robot.model.clone()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let mut counts: Vec<(String, i64)> = Vec::new();
+        if !models.is_empty() {
+            let sql = format!(
+                "SELECT model, COUNT(*) FROM robots WHERE model IN ({}) GROUP BY model",
+                placeholders(1, models.len())
+            );
+            let mut query = sqlx::query_as(&sql);
+            for model in &models {
+                query = query.bind(model);
+            }
+            counts = query.fetch_all(&mut tx).await?;
+        }
+
+        let existing: HashSet<String> = existing.into_iter().collect();
+        let mut counts: HashMap<String, i64> = counts.into_iter().collect();
+        let (valid, errors) = prepare_rows(rows, &existing, &mut counts);
+
+        if mode == BatchMode::Atomic && !errors.is_empty() {
+            tx.rollback().await?;
+            return Ok((Vec::new(), errors));
+        }
+
+        for chunk in valid.chunks(BATCH_INSERT_ROWS) {
+            let values: Vec<String> = (0..chunk.len())
+                .map(|i| format!("({})", placeholders(i * 4 + 1, 4)))
+                .collect();
+            let sql = format!(
+                "INSERT INTO robots (serial, model, version, created) VALUES {}",
+                values.join(", ")
+            );
+            let mut query = sqlx::query(&sql);
+            for robot in chunk {
+                query = query
+                    .bind(&robot.serial)
+                    .bind(&robot.model)
+                    .bind(&robot.version)
+                    .bind(created);
+            }
+            query.execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok((valid, errors))
+    }
+
+    async fn list_robots(&self, filter: &RobotFilter) -> RepositoryResult<Vec<RobotRecord>> {
+        let column = filter.sort.column();
+        let (direction, comparison) = match filter.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        let sql = format!(
+            "SELECT r.id, r.serial, r.model, r.version, r.created,
+            EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id) AS sold,
+            r.decommissioned_at, r.decommission_reason
+            FROM robots r
+            WHERE (r.decommissioned_at IS NOT NULL) = $10
+            AND (CAST($1 AS TEXT) IS NULL OR r.model = $1)
+            AND (CAST($2 AS TEXT) IS NULL OR r.version = $2)
+            AND (CAST($3 AS TEXT) IS NULL OR substr(r.serial, 1, length($3)) = $3)
+            AND (CAST($4 AS TIMESTAMP) IS NULL OR r.created >= $4)
+            AND (CAST($5 AS TIMESTAMP) IS NULL OR r.created < $5)
+            AND (CAST($6 AS BOOLEAN) IS NULL OR EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id) = $6)
+            AND (CAST($8 AS INTEGER) IS NULL OR ({column}, r.id) {comparison} ($7, $8))
+            ORDER BY {column} {direction}, r.id {direction} LIMIT $9"
+        );
+
+        let (cursor_key, cursor_id) = filter.cursor.clone().unzip();
+        let query = sqlx::query_as(&sql)
+            .bind(&filter.model)
+            .bind(&filter.version)
+            .bind(&filter.serial_prefix)
+            .bind(filter.start)
+            .bind(filter.end)
+            .bind(filter.sold);
+        // The cursor key is bound with the type of the sort column
+        let query = match filter.sort {
+            RobotSort::Created => {
+                let created = match &cursor_key {
+                    Some(key) => Some(cursor_time(key).ok_or_else(|| {
+                        RepositoryError::Invalid(format!("Invalid cursor time {key}"))
+                    })?),
+                    None => None,
+                };
+                query.bind(created)
+            }
+            RobotSort::Serial | RobotSort::Model => query.bind(cursor_key),
+        };
+
+        Ok(query
+            .bind(cursor_id)
+            .bind(filter.limit)
+            .bind(filter.decommissioned)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn fetch_robot(&self, serial: &str) -> RepositoryResult<Option<RobotDetails>> {
+        type Row = (
+            i32,
+            String,
+            String,
+            String,
+            NaiveDateTime,
+            Option<NaiveDateTime>,
+            Option<String>,
+            Option<NaiveDateTime>,
+            Option<String>,
+            Option<String>,
+        );
+
+        let sql = "SELECT r.id, r.serial, r.model, r.version, r.created,
+            r.decommissioned_at, r.decommission_reason, s.sold_date, c.login, c.name
+            FROM robots r
+            LEFT JOIN sold s ON s.robot_id = r.id
+            LEFT JOIN customers c ON c.id = s.customer_id
+            WHERE r.serial = $1
+            ORDER BY r.decommissioned_at IS NULL DESC, r.created DESC, r.id DESC LIMIT 1";
+        let row: Option<Row> = sqlx::query_as(sql)
+            .bind(serial)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(
+            |(
+                id,
+                serial,
+                model,
+                version,
+                created,
+                decommissioned_at,
+                reason,
+                sold_date,
+                login,
+                name,
+            )| {
+                let sale = sold_date.map(|sold_date| SaleRecord {
+                    sold_date,
+                    customer_login: login.unwrap_or_default(),
+                    customer_name: name.unwrap_or_default(),
+                });
+                RobotDetails {
+                    robot: RobotRecord {
+                        id,
+                        serial,
+                        model,
+                        version,
+                        created,
+                        sold: sale.is_some(),
+                        decommissioned_at,
+                        decommission_reason: reason,
+                    },
+                    sale,
+                }
+            },
+        ))
+    }
+
+    async fn decommission_robot(
+        &self,
+        id: i32,
+        at: NaiveDateTime,
+        reason: &str,
+        force: bool,
+    ) -> RepositoryResult<Decommission> {
+        let mut tx = self.pool.begin().await?;
+        // Sales wait until the robot is decommissioned or kept
+        lock_table(&mut tx, "sold").await?;
+
+        let sql = "SELECT EXISTS (SELECT 1 FROM sold WHERE robot_id = r.id) FROM robots r
+            WHERE r.id = $1 AND r.decommissioned_at IS NULL";
+        let sold: Option<bool> = sqlx::query_scalar(sql)
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?;
+        let Some(sold) = sold else {
+            return Ok(Decommission::NotInService);
+        };
+        if !force {
+            if sold {
+                return Ok(Decommission::Sold);
+            }
+            let reserved: bool = sqlx::query_scalar(IS_RESERVED)
+                .bind(id)
+                .fetch_one(&mut tx)
+                .await?;
+            if reserved {
+                return Ok(Decommission::Reserved);
+            }
+        }
+
+        let sql =
+            "UPDATE robots SET decommissioned_at = $2, decommission_reason = $3 WHERE id = $1";
+        sqlx::query(sql)
+            .bind(id)
+            .bind(at)
+            .bind(reason)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Decommission::Done)
+    }
+
+    async fn restore_robot(&self, id: i32) -> RepositoryResult<bool> {
+        let sql = "UPDATE robots SET decommissioned_at = NULL, decommission_reason = NULL
+            WHERE id = $1 AND decommissioned_at IS NOT NULL";
+
+        let result = sqlx::query(sql).bind(id).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn in_stock(&self, model: &str, version: &str) -> RepositoryResult<i64> {
+        Ok(self.find_robot(model, version).await?)
+    }
+
+    async fn check_stock(&self, model: &str, version: &str) -> RepositoryResult<()> {
+        stock::check_stock(self, &self.mailer, model, version).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CustomerRepository for Database {
+    async fn insert_customer(&self, customer: &Customer) -> RepositoryResult<()> {
+        let sql = "INSERT INTO customers (name, email, login, password) VALUES ($1, $2, $3, $4)";
+
+        sqlx::query(sql)
+            .bind(&customer.name)
+            .bind(&customer.email)
+            .bind(&customer.login)
+            .bind(&customer.password)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn check_credentials(
+        &self,
+        login: &str,
+        password: &str,
+    ) -> RepositoryResult<Option<String>> {
+        Ok(Database::check_credentials(self, login, password).await?)
+    }
+
+    async fn customer_name(&self, login: &str) -> RepositoryResult<Option<String>> {
+        let sql = "SELECT name FROM customers WHERE login = $1";
+
+        Ok(sqlx::query_scalar(sql)
+            .bind(login)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+}
+
+#[async_trait]
+impl OrderRepository for Database {
+    async fn sell_robot(&self, login: &str, model: &str, version: &str) -> RepositoryResult<u64> {
+        Ok(Database::sell_robot(self, login, model, version).await?)
+    }
+
+    async fn add_order(&self, order: &Order) -> RepositoryResult<()> {
+        let sql = "INSERT INTO orders (customer_name, robot_model, order_date) VALUES ($1, $2, $3)";
+
+        sqlx::query(sql)
+            .bind(&order.customer_name)
+            .bind(&order.robot_model)
+            .bind(Utc::now().naive_utc())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_to_waitlist(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<()> {
+        Database::add_to_waitlist(self, login, model, version).await?;
+        Ok(())
+    }
+
+    async fn mark_notified(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Vec<NaiveDateTime>> {
+        Ok(Database::mark_notified(self, login, model, version).await?)
+    }
+
+    async fn waitlist_depth(&self) -> RepositoryResult<Vec<(String, i64)>> {
+        let sql = "SELECT model, COUNT(*) FROM waitlist WHERE notified IS NULL
+            GROUP BY model ORDER BY model";
+
+        Ok(sqlx::query_as(sql).fetch_all(&self.pool).await?)
+    }
+}
+
+#[async_trait]
+impl StockRepository for Database {
+    async fn list_thresholds(&self) -> RepositoryResult<Vec<StockThreshold>> {
+        Ok(stock::list_thresholds(&self.pool).await?)
+    }
+
+    async fn fetch_threshold(
+        &self,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Option<StockThreshold>> {
+        Ok(stock::fetch_threshold(&self.pool, model, version).await?)
+    }
+
+    async fn set_threshold(
+        &self,
+        model: &str,
+        version: &str,
+        minimum: i32,
+        recovery: i32,
+    ) -> RepositoryResult<()> {
+        Ok(stock::set_threshold(&self.pool, model, version, minimum, recovery).await?)
+    }
+
+    async fn delete_threshold(&self, model: &str, version: &str) -> RepositoryResult<bool> {
+        Ok(stock::delete_threshold(&self.pool, model, version).await?)
+    }
+
+    async fn set_alerting(
+        &self,
+        model: &str,
+        version: &str,
+        alerting: bool,
+        at: NaiveDateTime,
+    ) -> RepositoryResult<bool> {
+        Ok(stock::set_alerting(&self.pool, model, version, alerting, at).await?)
+    }
+}
+
+#[async_trait]
+impl PlanRepository for Database {
+    async fn list_plans(&self, tz: Tz, query: &PlanQuery) -> RepositoryResult<Vec<ProductionPlan>> {
+        Ok(plan::list_plans(&self.pool, tz, query).await?)
+    }
+
+    async fn fetch_plan(&self, tz: Tz, id: i32) -> RepositoryResult<Option<ProductionPlan>> {
+        Ok(plan::fetch_plan(&self.pool, tz, id).await?)
+    }
+
+    async fn insert_plan(&self, plan: &PlanInput) -> RepositoryResult<Option<i32>> {
+        Ok(plan::insert_plan(&self.pool, plan).await?)
+    }
+
+    async fn update_plan(&self, id: i32, plan: &PlanInput) -> RepositoryResult<bool> {
+        Ok(plan::update_plan(&self.pool, id, plan).await? > 0)
+    }
+
+    async fn delete_plan(&self, id: i32) -> RepositoryResult<bool> {
+        Ok(plan::delete_plan(&self.pool, id).await? > 0)
+    }
+
+    async fn fetch_targets(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> RepositoryResult<BTreeMap<(String, String), i64>> {
+        Ok(plan::fetch_targets(&self.pool, from, to).await?)
+    }
+}
+
+#[async_trait]
+impl ReportRepository for Database {
+    async fn production(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<Vec<(String, String, NaiveDate, i64)>> {
+        Ok(report::fetch_robots(&self.pool, range).await?)
+    }
+
+    async fn sales(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<BTreeMap<(String, String), SalesFigures>> {
+        Ok(sales::fetch_sales(&self.pool, range).await?)
+    }
+
+    async fn weekly_demand(
+        &self,
+        demand: Demand,
+        tz: Tz,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> RepositoryResult<BTreeMap<(String, String, NaiveDate), i64>> {
+        Ok(forecast::weekly_counts(&self.pool, demand, tz, start, end).await?)
+    }
+}
+
+#[async_trait]
+impl ArchiveRepository for Database {
+    async fn archive_report(&self, entry: &ReportEntry<'_>) -> RepositoryResult<i32> {
+        Ok(archive::insert_report(&self.pool, entry).await?)
+    }
+
+    async fn list_reports(&self, kind: Option<&str>) -> RepositoryResult<Vec<ArchivedReport>> {
+        Ok(archive::list_reports(&self.pool, kind).await?)
+    }
+
+    async fn fetch_report(&self, id: i32) -> RepositoryResult<Option<(String, String, Vec<u8>)>> {
+        Ok(archive::fetch_report(&self.pool, id).await?)
+    }
 }
@@ -1,22 +1,88 @@
-use sqlx::postgres::PgPool;
-use sqlx::Pool;
-use sqlx::Postgres;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use sqlx::pool::PoolOptions;
 use tokio::sync::OnceCell;
 
-use crate::constants::DATABASE_URL;
+#[cfg(not(feature = "sqlite"))]
+use sqlx::postgres::{PgConnectOptions as ConnectOptions, Postgres};
+#[cfg(feature = "sqlite")]
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions as ConnectOptions, SqliteJournalMode, SqliteSynchronous,
+};
+
+use crate::constants::{
+    DATABASE_URL, DB_ACQUIRE_TIMEOUT, DB_IDLE_TIMEOUT, DB_MAX_CONNECTIONS, DB_STATEMENT_CACHE,
+    SQLITE_DATABASE_URL,
+};
+use crate::db::Database;
+
+// The backend is chosen at build time, Postgres unless the `sqlite` feature is enabled.
+// Every query is written so that it runs on both.
+#[cfg(not(feature = "sqlite"))]
+pub type Backend = Postgres;
+#[cfg(feature = "sqlite")]
+pub type Backend = Sqlite;
+
+pub type DbPool = sqlx::Pool<Backend>;
+
+// The URL can be overridden with the DATABASE_URL environment variable
+pub fn database_url() -> String {
+    let default = if cfg!(feature = "sqlite") {
+        SQLITE_DATABASE_URL
+    } else {
+        DATABASE_URL
+    };
+    std::env::var("DATABASE_URL").unwrap_or_else(|_| default.to_string())
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn connect_options(url: &str) -> Result<ConnectOptions, sqlx::Error> {
+    Ok(ConnectOptions::from_str(url)?.statement_cache_capacity(DB_STATEMENT_CACHE))
+}
+
+// Handlers and workers write concurrently, WAL lets readers go on meanwhile
+// and writers wait for each other instead of failing
+#[cfg(feature = "sqlite")]
+pub fn connect_options(url: &str) -> Result<ConnectOptions, sqlx::Error> {
+    Ok(ConnectOptions::from_str(url)?
+        .statement_cache_capacity(DB_STATEMENT_CACHE)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_secs(DB_ACQUIRE_TIMEOUT)))
+}
 
-static POOL: OnceCell<Arc<Pool<Postgres>>> = OnceCell::const_new();
+// Opens the application's only pool, it is shared by every handler and worker.
+// Requests wait at most DB_ACQUIRE_TIMEOUT seconds for a free connection.
+pub async fn connect_pool() -> Result<DbPool, sqlx::Error> {
+    let pool = pool_options()
+        .connect_with(connect_options(&database_url())?)
+        .await?;
 
-pub async fn get_pool() -> Result<Arc<Pool<Postgres>>, sqlx::Error> {
-    let pool = POOL
-        .get_or_init(|| async {
-            let pool = PgPool::connect(DATABASE_URL)
-                .await
-                .expect("Failed to connect to database");
-            Arc::new(pool)
-        })
-        .await;
+    // A new SQLite file starts out empty, it is migrated once per process
+    if cfg!(feature = "sqlite") {
+        static MIGRATED: OnceCell<()> = OnceCell::const_new();
+        MIGRATED
+            .get_or_try_init(|| async { Database::new(pool.clone()).setup_database().await })
+            .await?;
+    }
+    Ok(pool)
+}
+
+// A pool that connects on first use, for running without the database.
+// Queries fail with 503 once DB_ACQUIRE_TIMEOUT has passed.
+pub fn lazy_pool() -> DbPool {
+    let options = ConnectOptions::from_str(&database_url())
+        .unwrap_or_else(|_| ConnectOptions::new())
+        .statement_cache_capacity(DB_STATEMENT_CACHE);
+
+    pool_options().connect_lazy_with(options)
+}
 
-    Ok(pool.clone())
+pub fn pool_options() -> PoolOptions<Backend> {
+    PoolOptions::new()
+        .max_connections(DB_MAX_CONNECTIONS)
+        .connect_timeout(Duration::from_secs(DB_ACQUIRE_TIMEOUT))
+        .idle_timeout(Duration::from_secs(DB_IDLE_TIMEOUT))
 }
@@ -0,0 +1,308 @@
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, FromRequestParts, Path, Query};
+use axum::http::request::Parts;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, Json};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use utoipa::ToSchema;
+use validator::ValidationErrors;
+
+use crate::supervisor::panic_message;
+
+// Stable machine readable error codes, clients match on these rather than on messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ValidationFailed,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    Conflict,
+    Gone,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    Unprocessable,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::ValidationFailed | ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::NOT_ACCEPTABLE => ErrorCode::NotAcceptable,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::GONE => ErrorCode::Gone,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::Unprocessable,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            status if status.is_client_error() => ErrorCode::BadRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+// One failed `validator` rule, `code` is the rule name, e.g. "email" or "length"
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub code: String,
+    pub message: Option<String>,
+    #[schema(value_type = Object)]
+    pub params: BTreeMap<String, serde_json::Value>,
+}
+
+// Body of every error response:
+// {"code": "validation_failed", "message": "...", "fields": {"email": [{"code": "email", ...}]}}
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AppError {
+    pub code: ErrorCode,
+    pub message: String,
+    // Failed rules per field, see FieldError
+    #[schema(value_type = Object)]
+    pub fields: BTreeMap<String, Vec<FieldError>>,
+}
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if self.code == ErrorCode::Internal {
+            eprintln!("Request failed: {}", self.message);
+        }
+        (self.status(), Json(self)).into_response()
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        let fields = errors
+            .field_errors()
+            .into_iter()
+            .map(|(field, errors)| {
+                let errors = errors
+                    .iter()
+                    .map(|error| FieldError {
+                        code: error.code.to_string(),
+                        message: error.message.as_ref().map(Cow::to_string),
+                        params: error
+                            .params
+                            .iter()
+                            // `value` repeats the rejected input, passwords included
+                            .filter(|(name, _)| *name != "value")
+                            .map(|(name, value)| (name.to_string(), value.clone()))
+                            .collect(),
+                    })
+                    .collect();
+                (field.to_string(), errors)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let names: Vec<&str> = fields.keys().map(String::as_str).collect();
+        Self {
+            code: ErrorCode::ValidationFailed,
+            message: format!("Invalid fields: {}", names.join(", ")),
+            fields,
+        }
+    }
+}
+
+// A unique or primary key value is taken. Postgres reports SQLSTATE codes,
+// SQLite its extended result codes.
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => {
+            matches!(
+                e.code().as_deref(),
+                Some("23505") | Some("2067") | Some("1555")
+            )
+        }
+        _ => false,
+    }
+}
+
+// Unique and foreign key violations are conflicts with existing rows,
+// check violations are invalid input
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::RowNotFound => AppError::not_found("Not found"),
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                AppError::new(ErrorCode::Unavailable, "The database is not available")
+            }
+            sqlx::Error::Database(e) if is_unique_violation(&error) => {
+                AppError::conflict(format!("Already exists: {}", e.message()))
+            }
+            sqlx::Error::Database(e) => match e.code().as_deref() {
+                Some("23503") | Some("787") => AppError::conflict(format!(
+                    "Refers to a missing or used record: {}",
+                    e.message()
+                )),
+                Some("23514") | Some("22P02") | Some("22007") | Some("22008") | Some("275") => {
+                    AppError::bad_request(e.message().to_string())
+                }
+                // SQLITE_BUSY and SQLITE_CANTOPEN
+                Some("5") | Some("14") => {
+                    AppError::new(ErrorCode::Unavailable, "The database is not available")
+                }
+                _ => AppError::internal(format!("Database error: {error}")),
+            },
+            _ => AppError::internal(format!("Database error: {error}")),
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<sqlx::Error>() {
+            Ok(error) => error.into(),
+            Err(error) => AppError::internal(error.to_string()),
+        }
+    }
+}
+
+// `Json` whose rejections are AppErrors
+pub struct AppJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for AppJson<T>
+where
+    Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(AppJson(value)),
+            Err(rejection) => Err(AppError::new(
+                ErrorCode::from_status(rejection.status()),
+                rejection.body_text(),
+            )),
+        }
+    }
+}
+
+// `Query` whose rejections are AppErrors
+pub struct AppQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for AppQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(value)) => Ok(AppQuery(value)),
+            Err(rejection) => Err(AppError::new(
+                ErrorCode::from_status(rejection.status()),
+                rejection.body_text(),
+            )),
+        }
+    }
+}
+
+// `Path` whose rejections are AppErrors
+pub struct AppPath<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for AppPath<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Path::<T>::from_request_parts(parts, state).await {
+            Ok(Path(value)) => Ok(AppPath(value)),
+            Err(rejection) => Err(AppError::new(
+                ErrorCode::from_status(rejection.status()),
+                rejection.body_text(),
+            )),
+        }
+    }
+}
+
+// Response of CatchPanicLayer, a handler that panics fails only its own request
+pub fn panic_response(payload: Box<dyn Any + Send + 'static>) -> Response {
+    AppError::internal(format!(
+        "Handler panicked: {}",
+        panic_message(payload.as_ref())
+    ))
+    .into_response()
+}
+
+// Fallback for paths that match no route
+pub async fn not_found_handler() -> AppError {
+    AppError::not_found("No such endpoint")
+}
@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use axum::extract::State;
+use axum::Json;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[cfg(feature = "sqlite")]
+use crate::constants::QUARTER_SECONDS;
+use crate::constants::{
+    FORECAST_ALPHA, FORECAST_BETA, FORECAST_HISTORY_WEEKS, FORECAST_MAX_HISTORY_WEEKS,
+    FORECAST_MAX_WEEKS, FORECAST_WEEKS, FORECAST_WINDOW,
+};
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppQuery};
+use crate::period::{factory_timezone, local_midnight_utc, RangeError};
+#[cfg(feature = "sqlite")]
+use crate::period::{quarter_local_date, week_start};
+use crate::repository::{ReportRepository, Repositories, RepositoryResult};
+
+// Query parameters of /analytics/forecast, every field has a default
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ForecastQuery {
+    // Number of weeks to forecast
+    pub weeks: Option<usize>,
+    // Number of past weeks the forecast is based on
+    pub history: Option<usize>,
+    // Moving average window in weeks
+    pub window: Option<usize>,
+    // Smoothing factors, 0 < alpha, beta <= 1
+    pub alpha: Option<f64>,
+    pub beta: Option<f64>,
+    pub model: Option<String>,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ForecastParams {
+    pub weeks: usize,
+    pub history: usize,
+    pub window: usize,
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ForecastError {
+    Weeks(usize),
+    History(usize),
+    Window { window: usize, history: usize },
+    Smoothing(&'static str, f64),
+}
+
+impl fmt::Display for ForecastError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForecastError::Weeks(weeks) => write!(
+                f,
+                "`weeks` must be between 1 and {FORECAST_MAX_WEEKS}, got {weeks}"
+            ),
+            ForecastError::History(history) => write!(
+                f,
+                "`history` must be between 1 and {FORECAST_MAX_HISTORY_WEEKS}, got {history}"
+            ),
+            ForecastError::Window { window, history } => write!(
+                f,
+                "`window` must be between 1 and `history` ({history}), got {window}"
+            ),
+            ForecastError::Smoothing(name, value) => {
+                write!(f, "`{name}` must be in (0, 1], got {value}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForecastError {}
+
+impl ForecastQuery {
+    pub fn params(&self) -> Result<ForecastParams, ForecastError> {
+        let params = ForecastParams {
+            weeks: self.weeks.unwrap_or(FORECAST_WEEKS),
+            history: self.history.unwrap_or(FORECAST_HISTORY_WEEKS),
+            window: self.window.unwrap_or(FORECAST_WINDOW),
+            alpha: self.alpha.unwrap_or(FORECAST_ALPHA),
+            beta: self.beta.unwrap_or(FORECAST_BETA),
+        };
+
+        if !(1..=FORECAST_MAX_WEEKS).contains(&params.weeks) {
+            return Err(ForecastError::Weeks(params.weeks));
+        }
+        if !(1..=FORECAST_MAX_HISTORY_WEEKS).contains(&params.history) {
+            return Err(ForecastError::History(params.history));
+        }
+        if !(1..=params.history).contains(&params.window) {
+            return Err(ForecastError::Window {
+                window: params.window,
+                history: params.history,
+            });
+        }
+        for (name, value) in [("alpha", params.alpha), ("beta", params.beta)] {
+            if !(value > 0.0 && value <= 1.0) {
+                return Err(ForecastError::Smoothing(name, value));
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+// Weekly demand of one model/version and its projections, one value per forecast week
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ForecastSeries {
+    pub model: String,
+    pub version: String,
+    pub history: Vec<i64>,
+    pub moving_average: Vec<f64>,
+    pub exponential_smoothing: Vec<f64>,
+    pub holt: Vec<f64>,
+}
+
+// Weeks start on Monday in the factory time zone
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Forecast {
+    pub params: ForecastParams,
+    pub history_weeks: Vec<NaiveDate>,
+    pub forecast_weeks: Vec<NaiveDate>,
+    pub series: Vec<ForecastSeries>,
+}
+
+// Mean of the last `window` weeks, each forecast week is fed back into the window
+pub fn moving_average(history: &[i64], window: usize, horizon: usize) -> Vec<f64> {
+    let mut values: Vec<f64> = history.iter().map(|v| *v as f64).collect();
+    let mut forecast = Vec::with_capacity(horizon);
+    for _ in 0..horizon {
+        let tail = &values[values.len().saturating_sub(window)..];
+        let next = if tail.is_empty() {
+            0.0
+        } else {
+            tail.iter().sum::<f64>() / tail.len() as f64
+        };
+        forecast.push(next);
+        values.push(next);
+    }
+    forecast
+}
+
+// Simple exponential smoothing, the projection is flat at the last smoothed level
+pub fn exponential_smoothing(history: &[i64], alpha: f64, horizon: usize) -> Vec<f64> {
+    let mut level = history.first().map_or(0.0, |v| *v as f64);
+    for value in history.iter().skip(1) {
+        level = alpha * *value as f64 + (1.0 - alpha) * level;
+    }
+    vec![level; horizon]
+}
+
+// Holt's linear method: exponential smoothing with a trend, never below zero
+pub fn holt(history: &[i64], alpha: f64, beta: f64, horizon: usize) -> Vec<f64> {
+    let values: Vec<f64> = history.iter().map(|v| *v as f64).collect();
+    let (mut level, mut trend) = match values.as_slice() {
+        [] => (0.0, 0.0),
+        [only] => (*only, 0.0),
+        [first, second, ..] => (*first, second - first),
+    };
+    for value in values.iter().skip(1) {
+        let previous = level;
+        level = alpha * value + (1.0 - alpha) * (level + trend);
+        trend = beta * (level - previous) + (1.0 - beta) * trend;
+    }
+    (1..=horizon)
+        .map(|step| (level + step as f64 * trend).max(0.0))
+        .collect()
+}
+
+// The last `history` full weeks (Monday to Sunday) that ended on or before `as_of`
+pub fn history_weeks(as_of: NaiveDate, history: usize) -> Result<Vec<NaiveDate>, RangeError> {
+    let days_from_monday = as_of.weekday().num_days_from_monday() as i64;
+    let back = if days_from_monday == 6 {
+        Duration::days(6)
+    } else {
+        Duration::days(days_from_monday + 7)
+    };
+    let last_monday = as_of
+        .checked_sub_signed(back)
+        .ok_or(RangeError::OutOfRange)?;
+    (0..history as i64)
+        .rev()
+        .map(|week| {
+            last_monday
+                .checked_sub_signed(Duration::weeks(week))
+                .ok_or(RangeError::OutOfRange)
+        })
+        .collect()
+}
+
+type Key = (String, String);
+
+pub async fn build_forecast(
+    reports: &dyn ReportRepository,
+    tz: Tz,
+    as_of: NaiveDate,
+    params: ForecastParams,
+    query: &ForecastQuery,
+) -> Result<Forecast, AppError> {
+    let out_of_range = |err: RangeError| AppError::bad_request(err.to_string());
+    let history_weeks = history_weeks(as_of, params.history).map_err(out_of_range)?;
+    let (Some(&first_week), Some(&last_week)) = (history_weeks.first(), history_weeks.last())
+    else {
+        return Err(AppError::bad_request(
+            ForecastError::History(params.history).to_string(),
+        ));
+    };
+    let forecast_weeks: Vec<NaiveDate> = (1..=params.weeks as i64)
+        .map(|week| last_week.checked_add_signed(Duration::weeks(week)))
+        .collect::<Option<_>>()
+        .ok_or(RangeError::OutOfRange)
+        .map_err(out_of_range)?;
+
+    // The week after the last one ends the history, the forecast weeks fit so it does too
+    let demand =
+        fetch_weekly_demand(reports, tz, first_week, last_week + Duration::weeks(1)).await?;
+
+    let mut history: BTreeMap<Key, Vec<i64>> = BTreeMap::new();
+    for ((model, version, week), count) in demand {
+        if query.model.as_ref().is_some_and(|m| *m != model)
+            || query.version.as_ref().is_some_and(|v| *v != version)
+        {
+            continue;
+        }
+        let Some(index) = history_weeks.iter().position(|w| *w == week) else {
+            continue;
+        };
+        history
+            .entry((model, version))
+            .or_insert_with(|| vec![0; history_weeks.len()])[index] += count;
+    }
+
+    let series = history
+        .into_iter()
+        .map(|((model, version), history)| ForecastSeries {
+            moving_average: moving_average(&history, params.window, params.weeks),
+            exponential_smoothing: exponential_smoothing(&history, params.alpha, params.weeks),
+            holt: holt(&history, params.alpha, params.beta, params.weeks),
+            model,
+            version,
+            history,
+        })
+        .collect();
+
+    Ok(Forecast {
+        params,
+        history_weeks,
+        forecast_weeks,
+        series,
+    })
+}
+
+// Counts per (model, version, local week) in [$1, $2). `robot_model` is "model-version".
+#[cfg(not(feature = "sqlite"))]
+const ORDERS_BY_WEEK: &str = "SELECT split_part(robot_model, '-', 1) AS model, split_part(robot_model, '-', 2) AS version, date_trunc('week', order_date AT TIME ZONE 'UTC' AT TIME ZONE $3)::date AS week, COUNT(*) FROM orders WHERE order_date >= $1 AND order_date < $2 GROUP BY model, version, week";
+#[cfg(not(feature = "sqlite"))]
+const SOLD_BY_WEEK: &str = "SELECT r.model, r.version, date_trunc('week', s.sold_date AT TIME ZONE 'UTC' AT TIME ZONE $3)::date AS week, COUNT(*) FROM sold s JOIN robots r ON r.id = s.robot_id WHERE s.sold_date >= $1 AND s.sold_date < $2 GROUP BY r.model, r.version, week";
+#[cfg(not(feature = "sqlite"))]
+const WAITLIST_BY_WEEK: &str = "SELECT model, version, date_trunc('week', requested AT TIME ZONE 'UTC' AT TIME ZONE $3)::date AS week, COUNT(*) FROM waitlist WHERE requested >= $1 AND requested < $2 GROUP BY model, version, week";
+
+// Counts per (model, version, UTC quarter hour), folded into local weeks by `weekly_counts`
+#[cfg(feature = "sqlite")]
+const ORDERS_BY_WEEK: &str = "SELECT substr(robot_model, 1, instr(robot_model || '-', '-') - 1) AS model, substr(robot_model, instr(robot_model || '-', '-') + 1) AS version, CAST(strftime('%s', order_date) AS INTEGER) / $3 AS quarter, COUNT(*) FROM orders WHERE order_date >= $1 AND order_date < $2 GROUP BY model, version, quarter";
+#[cfg(feature = "sqlite")]
+const SOLD_BY_WEEK: &str = "SELECT r.model, r.version, CAST(strftime('%s', s.sold_date) AS INTEGER) / $3 AS quarter, COUNT(*) FROM sold s JOIN robots r ON r.id = s.robot_id WHERE s.sold_date >= $1 AND s.sold_date < $2 GROUP BY r.model, r.version, quarter";
+#[cfg(feature = "sqlite")]
+const WAITLIST_BY_WEEK: &str = "SELECT model, version, CAST(strftime('%s', requested) AS INTEGER) / $3 AS quarter, COUNT(*) FROM waitlist WHERE requested >= $1 AND requested < $2 GROUP BY model, version, quarter";
+
+#[cfg(not(feature = "sqlite"))]
+pub async fn weekly_counts(
+    pool: &DbPool,
+    demand: Demand,
+    tz: Tz,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> sqlx::Result<BTreeMap<(String, String, NaiveDate), i64>> {
+    let rows: Vec<(String, String, NaiveDate, i64)> = sqlx::query_as(demand.by_week())
+        .bind(start)
+        .bind(end)
+        .bind(tz.name())
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(model, version, week, count)| ((model, version, week), count))
+        .collect())
+}
+
+#[cfg(feature = "sqlite")]
+pub async fn weekly_counts(
+    pool: &DbPool,
+    demand: Demand,
+    tz: Tz,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> sqlx::Result<BTreeMap<(String, String, NaiveDate), i64>> {
+    let rows: Vec<(String, String, i64, i64)> = sqlx::query_as(demand.by_week())
+        .bind(start)
+        .bind(end)
+        .bind(QUARTER_SECONDS)
+        .fetch_all(pool)
+        .await?;
+
+    let mut counts = BTreeMap::new();
+    for (model, version, quarter, count) in rows {
+        if let Some(day) = quarter_local_date(tz, quarter) {
+            *counts.entry((model, version, week_start(day))).or_default() += count;
+        }
+    }
+    Ok(counts)
+}
+
+// Kinds of demand counted per week
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Demand {
+    // Every order, also those waiting for a robot
+    Orders,
+    // Robots sold from stock
+    Sold,
+    Waitlist,
+}
+
+impl Demand {
+    fn by_week(self) -> &'static str {
+        match self {
+            Demand::Orders => ORDERS_BY_WEEK,
+            Demand::Sold => SOLD_BY_WEEK,
+            Demand::Waitlist => WAITLIST_BY_WEEK,
+        }
+    }
+}
+
+// Demand per (model, version, week): waitlist entries plus orders served from stock.
+// Every order served from stock is stored both in `orders` and `sold`,
+// the larger of the two counts is used so that it is not counted twice.
+async fn fetch_weekly_demand(
+    reports: &dyn ReportRepository,
+    tz: Tz,
+    first_week: NaiveDate,
+    end_week: NaiveDate,
+) -> RepositoryResult<BTreeMap<(String, String, NaiveDate), i64>> {
+    // Weeks around today always fit the calendar, others have no demand
+    let (Some(start), Some(end)) = (
+        local_midnight_utc(tz, first_week),
+        local_midnight_utc(tz, end_week),
+    ) else {
+        return Ok(BTreeMap::new());
+    };
+
+    let orders = reports
+        .weekly_demand(Demand::Orders, tz, start, end)
+        .await?;
+    let sold = reports.weekly_demand(Demand::Sold, tz, start, end).await?;
+    let waitlist = reports
+        .weekly_demand(Demand::Waitlist, tz, start, end)
+        .await?;
+
+    let mut served: BTreeMap<(String, String, NaiveDate), i64> = BTreeMap::new();
+    for (key, count) in orders.into_iter().chain(sold) {
+        let entry = served.entry(key).or_default();
+        *entry = (*entry).max(count);
+    }
+    for (key, count) in waitlist {
+        *served.entry(key).or_default() += count;
+    }
+
+    Ok(served)
+}
+
+#[utoipa::path(
+    get,
+    path = "/analytics/forecast",
+    tag = "analytics",
+    params(ForecastQuery),
+    responses(
+        (status = 200, description = "Weekly demand and its forecast per model and version", body = Forecast),
+        (status = 400, description = "Invalid forecast parameters", body = AppError),
+    )
+)]
+pub async fn forecast_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<ForecastQuery>,
+) -> Result<Json<Forecast>, AppError> {
+    let params = query
+        .params()
+        .map_err(|err| AppError::bad_request(err.to_string()))?;
+
+    let tz = factory_timezone();
+    let today = Utc::now().with_timezone(&tz).date_naive();
+
+    Ok(Json(
+        build_forecast(repos.reports.as_ref(), tz, today, params, &query).await?,
+    ))
+}
@@ -0,0 +1,146 @@
+use std::future::Future;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::constants::{HEALTH_CHECK_TIMEOUT, SCHEMA_VERSION, WORKER_HEARTBEAT_TIMEOUT};
+use crate::db::Database;
+use crate::state::AppState;
+
+// Probes for the orchestrator, outside the versioned API
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/health/live", get(live_handler))
+        .route("/health/ready", get(ready_handler))
+        .route("/version", get(version_handler))
+}
+
+// One dependency of /health/ready, `detail` says what was found or what failed
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    // The schema this build sets up and the one the database was last set up with
+    pub schema_version: i32,
+    pub applied_schema_version: Option<i32>,
+}
+
+// The process answers, nothing else is checked
+pub async fn live_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+// 503 until the database is reachable and set up, the order processor beats
+// and emails can be sent if a mail server is configured. The body lists every
+// check either way.
+pub async fn ready_handler(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let (database, migrations, order_processor, notifier) = tokio::join!(
+        check("database", check_database(&state)),
+        check("migrations", check_migrations(&state)),
+        check("order_processor", check_order_processor(&state)),
+        check("notifier", check_notifier(&state)),
+    );
+    let checks = vec![database, migrations, order_processor, notifier];
+    let ready = checks.iter().all(|check| check.ok);
+
+    for check in checks.iter().filter(|check| !check.ok) {
+        eprintln!("Not ready, {} failed: {}", check.name, check.detail);
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready, checks }))
+}
+
+pub async fn version_handler(State(state): State<AppState>) -> Json<VersionInfo> {
+    let db = Database::new(state.pool.clone());
+    let applied = timeout(
+        Duration::from_secs(HEALTH_CHECK_TIMEOUT),
+        db.schema_version(),
+    )
+    .await;
+
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        schema_version: SCHEMA_VERSION,
+        applied_schema_version: applied.ok().and_then(Result::ok).flatten(),
+    })
+}
+
+// Runs one check, a check that takes longer than HEALTH_CHECK_TIMEOUT fails
+async fn check<F>(name: &'static str, probe: F) -> Check
+where
+    F: Future<Output = Result<String, anyhow::Error>>,
+{
+    let result = match timeout(Duration::from_secs(HEALTH_CHECK_TIMEOUT), probe).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!(
+            "No answer within {HEALTH_CHECK_TIMEOUT} seconds"
+        )),
+    };
+
+    match result {
+        Ok(detail) => Check {
+            name,
+            ok: true,
+            detail,
+        },
+        Err(e) => Check {
+            name,
+            ok: false,
+            detail: e.to_string(),
+        },
+    }
+}
+
+async fn check_database(state: &AppState) -> Result<String, anyhow::Error> {
+    sqlx::query("SELECT 1").execute(&state.pool).await?;
+    Ok(format!(
+        "{} connections open, {} idle",
+        state.pool.size(),
+        state.pool.num_idle()
+    ))
+}
+
+async fn check_migrations(state: &AppState) -> Result<String, anyhow::Error> {
+    match Database::new(state.pool.clone()).schema_version().await? {
+        Some(version) if version >= SCHEMA_VERSION => Ok(format!("Schema version {version}")),
+        Some(version) => anyhow::bail!("Schema version {version}, expected {SCHEMA_VERSION}"),
+        None => anyhow::bail!("The database is not set up"),
+    }
+}
+
+async fn check_order_processor(state: &AppState) -> Result<String, anyhow::Error> {
+    let age = state.heartbeat.age().as_secs();
+    if age > WORKER_HEARTBEAT_TIMEOUT {
+        anyhow::bail!("Last round {age} seconds ago");
+    }
+    Ok(format!("Last round {age} seconds ago"))
+}
+
+// Sending blocks, so does connecting to the mail server
+async fn check_notifier(state: &AppState) -> Result<String, anyhow::Error> {
+    let mailer = state.mailer.clone();
+    tokio::task::spawn_blocking(move || mailer.check()).await?
+}
@@ -0,0 +1,389 @@
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::Json;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::role_from_headers;
+use crate::constants::{
+    DECOMMISSION_REASON, RESTORE_WINDOW_DAYS, ROBOTS_MAX_PAGE_SIZE, ROBOTS_PAGE_SIZE,
+};
+use crate::error::{AppError, AppPath, AppQuery, ErrorCode};
+use crate::metrics;
+use crate::period::{factory_timezone, local_day_end_utc, local_midnight_utc, RangeError};
+use crate::repository::{Decommission, Repositories, RobotFilter, RobotRepository};
+
+// One robot as returned by the query API, times are in UTC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow, ToSchema)]
+pub struct RobotRecord {
+    pub id: i32,
+    pub serial: String,
+    pub model: String,
+    pub version: String,
+    pub created: NaiveDateTime,
+    pub sold: bool,
+    pub decommissioned_at: Option<NaiveDateTime>,
+    pub decommission_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SaleRecord {
+    pub sold_date: NaiveDateTime,
+    pub customer_login: String,
+    pub customer_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RobotDetails {
+    #[serde(flatten)]
+    pub robot: RobotRecord,
+    pub sale: Option<SaleRecord>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RobotSort {
+    #[default]
+    Created,
+    Serial,
+    Model,
+}
+
+impl RobotSort {
+    pub fn column(self) -> &'static str {
+        match self {
+            RobotSort::Created => "r.created",
+            RobotSort::Serial => "r.serial",
+            RobotSort::Model => "r.model",
+        }
+    }
+
+    fn key(self, robot: &RobotRecord) -> String {
+        match self {
+            RobotSort::Created => robot.created.format(CURSOR_TIME_FORMAT).to_string(),
+            RobotSort::Serial => robot.serial.clone(),
+            RobotSort::Model => robot.model.clone(),
+        }
+    }
+}
+
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+// Creation time in the cursor of a listing sorted by `created`
+pub fn cursor_time(key: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(key, CURSOR_TIME_FORMAT).ok()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+// Query parameters of GET /robots, dates are days in the factory time zone and inclusive.
+// Decommissioned robots are only listed with `decommissioned=true`.
+// `cursor` is the `next_cursor` of the previous page and only valid with the same sorting.
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RobotQuery {
+    pub model: Option<String>,
+    pub version: Option<String>,
+    pub serial_prefix: Option<String>,
+    pub created_from: Option<NaiveDate>,
+    pub created_to: Option<NaiveDate>,
+    pub sold: Option<bool>,
+    pub decommissioned: Option<bool>,
+    pub sort: Option<RobotSort>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+// Query parameters of DELETE /robots/{serial}, `model` and `version` are checked when given.
+// `force` removes sold or reserved robots and needs a role allowed to do so.
+#[derive(Debug, Default, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RemovalQuery {
+    pub model: Option<String>,
+    pub version: Option<String>,
+    pub reason: Option<String>,
+    pub force: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RobotPage {
+    pub robots: Vec<RobotRecord>,
+    pub next_cursor: Option<String>,
+}
+
+// Position after the last robot of a page: its sort key and id, hex encoded so that
+// clients treat it as opaque
+pub fn encode_cursor(key: &str, id: i32) -> String {
+    format!("{key}\n{id}")
+        .bytes()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<(String, i32)> {
+    // An odd length leaves a single digit that fails to parse
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let text = String::from_utf8(bytes).ok()?;
+    let (key, id) = text.rsplit_once('\n')?;
+    Some((key.to_string(), id.parse().ok()?))
+}
+
+pub async fn list_robots(
+    robots: &dyn RobotRepository,
+    query: &RobotQuery,
+) -> Result<RobotPage, AppError> {
+    let limit = query.limit.unwrap_or(ROBOTS_PAGE_SIZE);
+    if !(1..=ROBOTS_MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::bad_request(format!(
+            "`limit` must be between 1 and {ROBOTS_MAX_PAGE_SIZE}, got {limit}"
+        )));
+    }
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(
+            decode_cursor(cursor)
+                .ok_or_else(|| AppError::bad_request(format!("Invalid cursor {cursor}")))?,
+        ),
+        None => None,
+    };
+
+    let tz = factory_timezone();
+    let out_of_range = || AppError::bad_request(RangeError::OutOfRange.to_string());
+    let start = match query.created_from {
+        Some(date) => Some(local_midnight_utc(tz, date).ok_or_else(out_of_range)?),
+        None => None,
+    };
+    let end = match query.created_to {
+        Some(date) => Some(local_day_end_utc(tz, date).ok_or_else(out_of_range)?),
+        None => None,
+    };
+    let sort = query.sort.unwrap_or_default();
+    let filter = RobotFilter {
+        model: query.model.clone(),
+        version: query.version.clone(),
+        serial_prefix: query.serial_prefix.clone(),
+        start,
+        end,
+        sold: query.sold,
+        decommissioned: query.decommissioned.unwrap_or(false),
+        sort,
+        order: query.order.unwrap_or_default(),
+        cursor,
+        // One extra row tells whether there is a next page
+        limit: limit + 1,
+    };
+    let mut robots = robots.list_robots(&filter).await?;
+
+    let next_cursor = if robots.len() as i64 > limit {
+        robots.truncate(limit as usize);
+        robots
+            .last()
+            .map(|robot| encode_cursor(&sort.key(robot), robot.id))
+    } else {
+        None
+    };
+
+    Ok(RobotPage {
+        robots,
+        next_cursor,
+    })
+}
+
+fn robot_not_found(serial: &str) -> AppError {
+    AppError::not_found(format!("Robot {serial} not found"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/robots",
+    tag = "robots",
+    params(RobotQuery),
+    responses(
+        (status = 200, description = "One page of robots", body = RobotPage),
+        (status = 400, description = "Invalid filter, limit or cursor", body = AppError),
+    )
+)]
+pub async fn list_robots_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<RobotQuery>,
+) -> Result<Json<RobotPage>, AppError> {
+    list_robots(repos.robots.as_ref(), &query).await.map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/robots/{serial}",
+    tag = "robots",
+    params(("serial" = String, Path, description = "Serial number of the robot")),
+    responses(
+        (status = 200, description = "The robot and its sale", body = RobotDetails),
+        (status = 404, description = "No such robot", body = AppError),
+    )
+)]
+pub async fn robot_details_handler(
+    State(repos): State<Repositories>,
+    AppPath(serial): AppPath<String>,
+) -> Result<Json<RobotDetails>, AppError> {
+    match repos.robots.fetch_robot(&serial).await? {
+        Some(robot) => Ok(Json(robot)),
+        None => Err(robot_not_found(&serial)),
+    }
+}
+
+// Puts a decommissioned robot back in stock, unless it was decommissioned
+// more than RESTORE_WINDOW_DAYS ago
+#[utoipa::path(
+    post,
+    path = "/robots/{serial}/restore",
+    tag = "robots",
+    params(("serial" = String, Path, description = "Serial number of the robot")),
+    responses(
+        (status = 200, description = "The restored robot", body = RobotDetails),
+        (status = 404, description = "No such robot", body = AppError),
+        (status = 409, description = "The robot is not decommissioned", body = AppError),
+        (status = 410, description = "Decommissioned too long ago", body = AppError),
+    )
+)]
+pub async fn restore_robot_handler(
+    State(repos): State<Repositories>,
+    AppPath(serial): AppPath<String>,
+) -> Result<Json<RobotDetails>, AppError> {
+    let robot = match repos.robots.fetch_robot(&serial).await? {
+        Some(details) => details.robot,
+        None => return Err(robot_not_found(&serial)),
+    };
+    let Some(decommissioned_at) = robot.decommissioned_at else {
+        return Err(AppError::conflict(format!(
+            "Robot {serial} is not decommissioned"
+        )));
+    };
+    if decommissioned_at < Utc::now().naive_utc() - Duration::days(RESTORE_WINDOW_DAYS) {
+        return Err(AppError::new(
+            ErrorCode::Gone,
+            format!(
+                "Robot {serial} was decommissioned on {}, more than {RESTORE_WINDOW_DAYS} days ago",
+                decommissioned_at.date()
+            ),
+        ));
+    }
+
+    repos.robots.restore_robot(robot.id).await?;
+    println!("Robot {serial} has been restored");
+    if let Err(e) = repos.robots.check_stock(&robot.model, &robot.version).await {
+        eprintln!("Stock check failed: {e}");
+    }
+
+    match repos.robots.fetch_robot(&serial).await? {
+        Some(details) => Ok(Json(details)),
+        None => Err(robot_not_found(&serial)),
+    }
+}
+
+// Decommissions the robot in service with this serial and returns it.
+// Sold and reserved robots are refused unless `force` is set.
+pub async fn decommission_robot(
+    robots: &dyn RobotRepository,
+    serial: &str,
+    query: &RemovalQuery,
+) -> Result<RobotDetails, AppError> {
+    let mut details = match robots.fetch_robot(serial).await? {
+        Some(details) if details.robot.decommissioned_at.is_none() => details,
+        _ => return Err(robot_not_found(serial)),
+    };
+    let robot = &details.robot;
+    if query
+        .model
+        .as_ref()
+        .is_some_and(|model| *model != robot.model)
+        || query
+            .version
+            .as_ref()
+            .is_some_and(|version| *version != robot.version)
+    {
+        return Err(AppError::conflict(format!(
+            "Robot {serial} is {}-{}, not {}-{}",
+            robot.model,
+            robot.version,
+            query.model.as_deref().unwrap_or(&robot.model),
+            query.version.as_deref().unwrap_or(&robot.version)
+        )));
+    }
+
+    let now = Utc::now().naive_utc();
+    let reason = query.reason.as_deref().unwrap_or(DECOMMISSION_REASON);
+    let force = query.force.unwrap_or(false);
+    match robots
+        .decommission_robot(robot.id, now, reason, force)
+        .await?
+    {
+        Decommission::Done => {}
+        Decommission::NotInService => return Err(robot_not_found(serial)),
+        Decommission::Sold => {
+            return Err(AppError::conflict(format!(
+                "Robot {serial} is sold, removing it needs `force`"
+            )))
+        }
+        Decommission::Reserved => {
+            return Err(AppError::conflict(format!(
+                "Robot {serial} is reserved for a waiting customer, removing it needs `force`"
+            )))
+        }
+    }
+    println!("Robot {serial} has been decommissioned");
+    metrics::robot_removed(&robot.model);
+    if let Err(e) = robots.check_stock(&robot.model, &robot.version).await {
+        eprintln!("Stock check failed: {e}");
+    }
+
+    details.robot.decommissioned_at = Some(now);
+    details.robot.decommission_reason = Some(reason.to_string());
+    Ok(details)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/robots/{serial}",
+    tag = "robots",
+    params(("serial" = String, Path, description = "Serial number of the robot"), RemovalQuery),
+    responses(
+        (status = 200, description = "The decommissioned robot", body = RobotDetails),
+        (status = 401, description = "`force` without a valid token", body = AppError),
+        (status = 403, description = "The role may not force removals", body = AppError),
+        (status = 404, description = "No such robot in service", body = AppError),
+        (status = 409, description = "Sold, reserved or of another model", body = AppError),
+    ),
+    security((), ("bearer" = []))
+)]
+pub async fn remove_robot_handler(
+    State(repos): State<Repositories>,
+    AppPath(serial): AppPath<String>,
+    AppQuery(query): AppQuery<RemovalQuery>,
+    headers: HeaderMap,
+) -> Result<Json<RobotDetails>, AppError> {
+    if query.force.unwrap_or(false) {
+        let role = role_from_headers(&headers)?;
+        if !role.can_force_removal() {
+            return Err(AppError::new(
+                ErrorCode::Forbidden,
+                format!(
+                    "The {} role may not force the removal of robots",
+                    role.as_str()
+                ),
+            ));
+        }
+    }
+
+    decommission_robot(repos.robots.as_ref(), &serial, &query)
+        .await
+        .map(Json)
+}
@@ -1,39 +1,75 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::time::Duration;
 
-use axum::extract::Extension;
-use axum::{
-    routing::{get, post},
-    Json, Router, Server,
-};
+use axum::{middleware, Router, Server};
 use chrono::Local;
-use sqlx::postgres::PgPool;
+use tower_http::catch_panic::CatchPanicLayer;
 
+#[cfg(test)]
+mod test_support;
 #[cfg(test)]
 mod tests;
 
+mod api;
+mod archive;
+mod auth;
+mod batch;
 mod constants;
 mod db;
 mod db_pool;
+mod error;
+mod forecast;
+mod health;
+mod inventory;
+mod memory;
+mod metrics;
+mod notification;
+mod openapi;
 mod order;
+mod period;
+mod plan;
 mod processing;
 mod report;
+mod report_format;
+mod repository;
 mod robot;
+mod sales;
+mod scheduler;
+mod state;
+mod stock;
+mod supervisor;
 mod user;
+mod v2;
 
+use crate::constants::{API_V1, API_V2, WORKER_RESTART_DELAY};
 use crate::db::Database;
-use crate::db_pool::get_pool;
-use processing::order_robot;
-use report::report_handler;
-use robot::Robot;
-use user::create_customer;
+use crate::db_pool::{connect_pool, DbPool};
+use crate::error::{not_found_handler, panic_response};
+use processing::run_order_processor;
+use state::AppState;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    amount_of_robots().await?;
+    let pool = connect_pool().await?;
+    amount_of_robots(&pool).await?;
+    let state = AppState::new(pool);
+
+    let restart_delay = Duration::from_secs(WORKER_RESTART_DELAY);
+    let (repos, mailer) = (state.repos.clone(), state.mailer.clone());
+    tokio::spawn(supervisor::supervise(
+        "report scheduler",
+        restart_delay,
+        move || scheduler::run_report_scheduler(repos.clone(), mailer.clone()),
+    ));
+    let orders = state.orders.clone();
+    let heartbeat = state.heartbeat.clone();
+    tokio::spawn(supervisor::supervise(
+        "order processor",
+        restart_delay,
+        move || run_order_processor(orders.clone(), heartbeat.clone()),
+    ));
 
-    let pool = get_pool().await?;
-    let app = create_router(pool);
+    let app = router_with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
 
@@ -42,40 +78,22 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn create_router(pool: Arc<PgPool>) -> Router<()> {
-    let router: Router = Router::new()
-        .route("/robots/report", get(report_handler))
-        .route(
-            "/robots/create",
-            post(move |Json(robot_data): Json<Robot>| async move {
-                let robot = Robot {
-                    serial: robot_data.serial,
-                    model: robot_data.model,
-                    version: robot_data.version,
-                };
-                robot.create_robot().await
-            }),
-        )
-        .route(
-            "/robots/remove",
-            post(move |Json(robot_data): Json<Robot>| async move {
-                let robot = Robot {
-                    serial: robot_data.serial,
-                    model: robot_data.model,
-                    version: robot_data.version,
-                };
-                robot.remove_robot().await
-            }),
-        )
-        .route("/robots/order", post(order_robot))
-        .route("/user/create", post(create_customer))
-        .layer(Extension(pool));
-
-    router
+pub fn router_with_state(state: AppState) -> Router<()> {
+    Router::new()
+        .nest(API_V1, api::v1())
+        .nest(API_V2, api::v2())
+        .merge(api::legacy())
+        .merge(health::routes())
+        .merge(metrics::routes())
+        .merge(openapi::docs())
+        .fallback(not_found_handler)
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .with_state(state)
 }
 
-async fn amount_of_robots() -> anyhow::Result<()> {
-    let db = Database::new().await.unwrap();
+async fn amount_of_robots(pool: &DbPool) -> anyhow::Result<()> {
+    let db = Database::new(pool.clone());
 
     match db.setup_database().await {
         Ok(_) => println!("Database setup completed successfully"),
@@ -84,7 +102,7 @@ async fn amount_of_robots() -> anyhow::Result<()> {
 
     let now = Local::now();
     let date = now.format("%Y-%m-%d %H:%M:%S").to_string();
-    match db.get_robots_by_date(&date).await {
+    match db.get_robots_by_date(now.naive_utc()).await {
         Ok(count) => println!("Total amount of robots on {date} is {count}"),
         Err(e) => println!("Error: {}", e),
     }
@@ -0,0 +1,836 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use axum::async_trait;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::archive::{ArchivedReport, ReportEntry};
+use crate::batch::{prepare_rows, BatchMode, BatchRow, RowError};
+use crate::forecast::Demand;
+use crate::inventory::{cursor_time, RobotDetails, RobotRecord, RobotSort, SaleRecord, SortOrder};
+use crate::notification::Mailer;
+use crate::order::Order;
+use crate::period::{local_date, local_day_end_utc, local_midnight_utc, week_start, ReportRange};
+use crate::plan::{prorated_target, PlanInput, PlanQuery, ProductionPlan};
+use crate::repository::{
+    ArchiveRepository, CustomerRepository, Decommission, OrderRepository, PlanRepository,
+    ReportRepository, RepositoryError, RepositoryResult, RobotFilter, RobotRepository,
+    StockRepository,
+};
+use crate::robot::Robot;
+use crate::sales::SalesFigures;
+use crate::stock::{self, StockThreshold};
+use crate::user::Customer;
+
+struct Sale {
+    robot_id: i32,
+    login: String,
+    sold_date: NaiveDateTime,
+}
+
+struct WaitlistEntry {
+    login: String,
+    model: String,
+    version: String,
+    requested: NaiveDateTime,
+    notified: Option<NaiveDateTime>,
+}
+
+struct Threshold {
+    model: String,
+    version: String,
+    minimum: i32,
+    recovery: i32,
+    alerting: bool,
+    alerted_at: Option<NaiveDateTime>,
+}
+
+struct StoredReport {
+    report: ArchivedReport,
+    content_type: String,
+    content: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Store {
+    robots: Vec<RobotRecord>,
+    sales: Vec<Sale>,
+    customers: Vec<Customer>,
+    // (customer name, "model-version", order date)
+    orders: Vec<(String, String, NaiveDateTime)>,
+    waitlist: Vec<WaitlistEntry>,
+    thresholds: Vec<Threshold>,
+    plans: Vec<ProductionPlan>,
+    // Plans can be deleted, their ids are not reused
+    last_plan_id: i32,
+    reports: Vec<StoredReport>,
+}
+
+impl Store {
+    fn push_robot(&mut self, serial: &str, model: &str, version: &str, created: NaiveDateTime) {
+        let id = self.robots.len() as i32 + 1;
+        self.robots.push(RobotRecord {
+            id,
+            serial: serial.to_string(),
+            model: model.to_string(),
+            version: version.to_string(),
+            created,
+            sold: false,
+            decommissioned_at: None,
+            decommission_reason: None,
+        });
+    }
+
+    // The robot a sale was made of
+    fn sold_robot(&self, sale: &Sale) -> Option<&RobotRecord> {
+        self.robots.iter().find(|r| r.id == sale.robot_id)
+    }
+
+    fn in_stock(&self, model: &str, version: &str) -> Vec<&RobotRecord> {
+        let mut robots: Vec<&RobotRecord> = self
+            .robots
+            .iter()
+            .filter(|r| r.model == model && r.version == version)
+            .filter(|r| !r.sold && r.decommissioned_at.is_none())
+            .collect();
+        robots.sort_by_key(|r| (r.created, r.id));
+        robots
+    }
+
+    fn is_reserved(&self, robot: &RobotRecord) -> bool {
+        let waiting = self
+            .waitlist
+            .iter()
+            .filter(|w| w.model == robot.model && w.version == robot.version)
+            .filter(|w| w.notified.is_none())
+            .count();
+        self.in_stock(&robot.model, &robot.version)
+            .iter()
+            .take(waiting)
+            .any(|r| r.id == robot.id)
+    }
+}
+
+// Keeps everything in process memory, for tests and demos without a database.
+// Low stock alerts are sent with `mailer`.
+pub struct MemoryDatabase {
+    store: Mutex<Store>,
+    mailer: Arc<dyn Mailer>,
+}
+
+impl MemoryDatabase {
+    pub fn with_mailer(mailer: Arc<dyn Mailer>) -> Self {
+        Self {
+            store: Mutex::default(),
+            mailer,
+        }
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        // The store stays consistent even if a holder panicked, every change is one push or field update
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Orders two robots by the sort column, then by id
+fn compare(sort: RobotSort, a: &RobotRecord, b: &RobotRecord) -> Ordering {
+    let by_column = match sort {
+        RobotSort::Created => a.created.cmp(&b.created),
+        RobotSort::Serial => a.serial.cmp(&b.serial),
+        RobotSort::Model => a.model.cmp(&b.model),
+    };
+    by_column.then(a.id.cmp(&b.id))
+}
+
+// Compares a robot with the cursor of the previous page
+fn compare_cursor(
+    sort: RobotSort,
+    robot: &RobotRecord,
+    (key, id): &(String, i32),
+) -> RepositoryResult<Ordering> {
+    let by_column = match sort {
+        RobotSort::Created => {
+            let created = cursor_time(key)
+                .ok_or_else(|| RepositoryError::Invalid(format!("Invalid cursor time {key}")))?;
+            robot.created.cmp(&created)
+        }
+        RobotSort::Serial => robot.serial.as_str().cmp(key),
+        RobotSort::Model => robot.model.as_str().cmp(key),
+    };
+    Ok(by_column.then(robot.id.cmp(id)))
+}
+
+#[async_trait]
+impl RobotRepository for MemoryDatabase {
+    async fn count_model(&self, model: &str) -> RepositoryResult<i64> {
+        let store = self.store();
+        Ok(store.robots.iter().filter(|r| r.model == model).count() as i64)
+    }
+
+    async fn insert_robot(
+        &self,
+        serial: &str,
+        model: &str,
+        version: &str,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<()> {
+        self.store().push_robot(serial, model, version, created);
+        Ok(())
+    }
+
+    async fn insert_robots(
+        &self,
+        rows: Vec<BatchRow>,
+        mode: BatchMode,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<(Vec<Robot>, Vec<RowError>)> {
+        let mut store = self.store();
+        let existing: HashSet<String> = store.robots.iter().map(|r| r.serial.clone()).collect();
+        let mut counts: HashMap<String, i64> = HashMap::new();
+        for robot in &store.robots {
+            *counts.entry(robot.model.clone()).or_default() += 1;
+        }
+
+        let (valid, errors) = prepare_rows(rows, &existing, &mut counts);
+        if mode == BatchMode::Atomic && !errors.is_empty() {
+            return Ok((Vec::new(), errors));
+        }
+        for robot in &valid {
+            store.push_robot(&robot.serial, &robot.model, &robot.version, created);
+        }
+        Ok((valid, errors))
+    }
+
+    async fn list_robots(&self, filter: &RobotFilter) -> RepositoryResult<Vec<RobotRecord>> {
+        let store = self.store();
+        let mut robots = Vec::new();
+
+        for robot in &store.robots {
+            let matches = robot.decommissioned_at.is_some() == filter.decommissioned
+                && filter.model.as_ref().is_none_or(|m| *m == robot.model)
+                && filter.version.as_ref().is_none_or(|v| *v == robot.version)
+                && filter
+                    .serial_prefix
+                    .as_ref()
+                    .is_none_or(|p| robot.serial.starts_with(p.as_str()))
+                && filter.start.is_none_or(|start| robot.created >= start)
+                && filter.end.is_none_or(|end| robot.created < end)
+                && filter.sold.is_none_or(|sold| robot.sold == sold);
+            if !matches {
+                continue;
+            }
+            if let Some(cursor) = &filter.cursor {
+                let wanted = match filter.order {
+                    SortOrder::Asc => Ordering::Greater,
+                    SortOrder::Desc => Ordering::Less,
+                };
+                if compare_cursor(filter.sort, robot, cursor)? != wanted {
+                    continue;
+                }
+            }
+            robots.push(robot.clone());
+        }
+
+        robots.sort_by(|a, b| match filter.order {
+            SortOrder::Asc => compare(filter.sort, a, b),
+            SortOrder::Desc => compare(filter.sort, b, a),
+        });
+        robots.truncate(filter.limit.max(0) as usize);
+        Ok(robots)
+    }
+
+    async fn fetch_robot(&self, serial: &str) -> RepositoryResult<Option<RobotDetails>> {
+        let store = self.store();
+        let Some(robot) = store
+            .robots
+            .iter()
+            .filter(|r| r.serial == serial)
+            .max_by_key(|r| (r.decommissioned_at.is_none(), r.created, r.id))
+        else {
+            return Ok(None);
+        };
+
+        let sale = store
+            .sales
+            .iter()
+            .find(|s| s.robot_id == robot.id)
+            .map(|sale| SaleRecord {
+                sold_date: sale.sold_date,
+                customer_login: sale.login.clone(),
+                customer_name: store
+                    .customers
+                    .iter()
+                    .find(|c| c.login == sale.login)
+                    .map(|c| c.name.clone())
+                    .unwrap_or_default(),
+            });
+        Ok(Some(RobotDetails {
+            robot: robot.clone(),
+            sale,
+        }))
+    }
+
+    async fn decommission_robot(
+        &self,
+        id: i32,
+        at: NaiveDateTime,
+        reason: &str,
+        force: bool,
+    ) -> RepositoryResult<Decommission> {
+        let mut store = self.store();
+        let Some(robot) = store
+            .robots
+            .iter()
+            .find(|r| r.id == id && r.decommissioned_at.is_none())
+        else {
+            return Ok(Decommission::NotInService);
+        };
+        if !force {
+            if robot.sold {
+                return Ok(Decommission::Sold);
+            }
+            if store.is_reserved(robot) {
+                return Ok(Decommission::Reserved);
+            }
+        }
+
+        if let Some(robot) = store.robots.iter_mut().find(|r| r.id == id) {
+            robot.decommissioned_at = Some(at);
+            robot.decommission_reason = Some(reason.to_string());
+        }
+        Ok(Decommission::Done)
+    }
+
+    async fn restore_robot(&self, id: i32) -> RepositoryResult<bool> {
+        let mut store = self.store();
+        match store
+            .robots
+            .iter_mut()
+            .find(|r| r.id == id && r.decommissioned_at.is_some())
+        {
+            Some(robot) => {
+                robot.decommissioned_at = None;
+                robot.decommission_reason = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn in_stock(&self, model: &str, version: &str) -> RepositoryResult<i64> {
+        Ok(self.store().in_stock(model, version).len() as i64)
+    }
+
+    async fn check_stock(&self, model: &str, version: &str) -> RepositoryResult<()> {
+        stock::check_stock(self, &self.mailer, model, version).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CustomerRepository for MemoryDatabase {
+    async fn insert_customer(&self, customer: &Customer) -> RepositoryResult<()> {
+        let mut store = self.store();
+        if store
+            .customers
+            .iter()
+            .any(|c| c.email == customer.email || c.login == customer.login)
+        {
+            return Err(RepositoryError::Conflict(format!(
+                "Already exists: customer {} or {}",
+                customer.login, customer.email
+            )));
+        }
+        store.customers.push(customer.clone());
+        Ok(())
+    }
+
+    async fn check_credentials(
+        &self,
+        login: &str,
+        password: &str,
+    ) -> RepositoryResult<Option<String>> {
+        let store = self.store();
+        Ok(store
+            .customers
+            .iter()
+            .find(|c| c.login == login && c.password == password)
+            .map(|c| c.email.clone()))
+    }
+
+    async fn customer_name(&self, login: &str) -> RepositoryResult<Option<String>> {
+        let store = self.store();
+        Ok(store
+            .customers
+            .iter()
+            .find(|c| c.login == login)
+            .map(|c| c.name.clone()))
+    }
+}
+
+#[async_trait]
+impl OrderRepository for MemoryDatabase {
+    async fn sell_robot(&self, login: &str, model: &str, version: &str) -> RepositoryResult<u64> {
+        let mut store = self.store();
+        if !store.customers.iter().any(|c| c.login == login) {
+            return Ok(0);
+        }
+        let Some(id) = store.in_stock(model, version).first().map(|r| r.id) else {
+            return Ok(0);
+        };
+
+        if let Some(robot) = store.robots.iter_mut().find(|r| r.id == id) {
+            robot.sold = true;
+        }
+        store.sales.push(Sale {
+            robot_id: id,
+            login: login.to_string(),
+            sold_date: Utc::now().naive_utc(),
+        });
+        Ok(1)
+    }
+
+    async fn add_order(&self, order: &Order) -> RepositoryResult<()> {
+        self.store().orders.push((
+            order.customer_name.clone(),
+            order.robot_model.clone(),
+            Utc::now().naive_utc(),
+        ));
+        Ok(())
+    }
+
+    async fn add_to_waitlist(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<()> {
+        self.store().waitlist.push(WaitlistEntry {
+            login: login.to_string(),
+            model: model.to_string(),
+            version: version.to_string(),
+            requested: Utc::now().naive_utc(),
+            notified: None,
+        });
+        Ok(())
+    }
+
+    async fn mark_notified(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Vec<NaiveDateTime>> {
+        let now = Utc::now().naive_utc();
+        let mut requested = Vec::new();
+        for entry in self.store().waitlist.iter_mut() {
+            if entry.login == login
+                && entry.model == model
+                && entry.version == version
+                && entry.notified.is_none()
+            {
+                entry.notified = Some(now);
+                requested.push(entry.requested);
+            }
+        }
+        Ok(requested)
+    }
+
+    async fn waitlist_depth(&self) -> RepositoryResult<Vec<(String, i64)>> {
+        let mut depth = BTreeMap::new();
+        for entry in self.store().waitlist.iter() {
+            if entry.notified.is_none() {
+                *depth.entry(entry.model.clone()).or_insert(0) += 1;
+            }
+        }
+        Ok(depth.into_iter().collect())
+    }
+}
+
+fn threshold_view(store: &Store, threshold: &Threshold) -> StockThreshold {
+    StockThreshold {
+        model: threshold.model.clone(),
+        version: threshold.version.clone(),
+        minimum: threshold.minimum,
+        recovery: threshold.recovery,
+        alerting: threshold.alerting,
+        alerted_at: threshold.alerted_at,
+        stock: store.in_stock(&threshold.model, &threshold.version).len() as i64,
+    }
+}
+
+#[async_trait]
+impl StockRepository for MemoryDatabase {
+    async fn list_thresholds(&self) -> RepositoryResult<Vec<StockThreshold>> {
+        let store = self.store();
+        let mut thresholds: Vec<StockThreshold> = store
+            .thresholds
+            .iter()
+            .map(|t| threshold_view(&store, t))
+            .collect();
+        thresholds.sort_by(|a, b| (&a.model, &a.version).cmp(&(&b.model, &b.version)));
+        Ok(thresholds)
+    }
+
+    async fn fetch_threshold(
+        &self,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Option<StockThreshold>> {
+        let store = self.store();
+        Ok(store
+            .thresholds
+            .iter()
+            .find(|t| t.model == model && t.version == version)
+            .map(|t| threshold_view(&store, t)))
+    }
+
+    async fn set_threshold(
+        &self,
+        model: &str,
+        version: &str,
+        minimum: i32,
+        recovery: i32,
+    ) -> RepositoryResult<()> {
+        let mut store = self.store();
+        match store
+            .thresholds
+            .iter_mut()
+            .find(|t| t.model == model && t.version == version)
+        {
+            Some(threshold) => {
+                threshold.minimum = minimum;
+                threshold.recovery = recovery;
+            }
+            None => store.thresholds.push(Threshold {
+                model: model.to_string(),
+                version: version.to_string(),
+                minimum,
+                recovery,
+                alerting: false,
+                alerted_at: None,
+            }),
+        }
+        Ok(())
+    }
+
+    async fn delete_threshold(&self, model: &str, version: &str) -> RepositoryResult<bool> {
+        let mut store = self.store();
+        let before = store.thresholds.len();
+        store
+            .thresholds
+            .retain(|t| t.model != model || t.version != version);
+        Ok(store.thresholds.len() < before)
+    }
+
+    async fn set_alerting(
+        &self,
+        model: &str,
+        version: &str,
+        alerting: bool,
+        at: NaiveDateTime,
+    ) -> RepositoryResult<bool> {
+        let mut store = self.store();
+        let Some(threshold) = store
+            .thresholds
+            .iter_mut()
+            .find(|t| t.model == model && t.version == version && t.alerting != alerting)
+        else {
+            return Ok(false);
+        };
+        threshold.alerting = alerting;
+        if alerting {
+            threshold.alerted_at = Some(at);
+        }
+        Ok(true)
+    }
+}
+
+// Robots made during the plan period, whose days start at midnight in `tz`
+fn plan_actual(store: &Store, tz: Tz, plan: &ProductionPlan) -> i64 {
+    let (Some(start), Some(end)) = (
+        local_midnight_utc(tz, plan.period_start),
+        local_day_end_utc(tz, plan.period_end),
+    ) else {
+        return 0;
+    };
+    store
+        .robots
+        .iter()
+        .filter(|r| r.model == plan.model && r.version == plan.version)
+        .filter(|r| r.created >= start && r.created < end)
+        .count() as i64
+}
+
+// Whether another plan of the model/version overlaps the period
+fn overlaps(store: &Store, id: Option<i32>, plan: &PlanInput) -> bool {
+    store.plans.iter().any(|p| {
+        Some(p.id) != id
+            && p.model == plan.model
+            && p.version == plan.version
+            && p.period_start <= plan.period_end
+            && p.period_end >= plan.period_start
+    })
+}
+
+#[async_trait]
+impl PlanRepository for MemoryDatabase {
+    async fn list_plans(&self, tz: Tz, query: &PlanQuery) -> RepositoryResult<Vec<ProductionPlan>> {
+        let store = self.store();
+        let mut plans: Vec<ProductionPlan> = store
+            .plans
+            .iter()
+            .filter(|p| query.model.as_ref().is_none_or(|m| *m == p.model))
+            .filter(|p| query.version.as_ref().is_none_or(|v| *v == p.version))
+            .filter(|p| query.from.is_none_or(|from| p.period_end >= from))
+            .filter(|p| query.to.is_none_or(|to| p.period_start <= to))
+            .map(|p| ProductionPlan {
+                actual: plan_actual(&store, tz, p),
+                ..p.clone()
+            })
+            .collect();
+        plans.sort_by(|a, b| {
+            (a.period_start, &a.model, &a.version).cmp(&(b.period_start, &b.model, &b.version))
+        });
+        Ok(plans)
+    }
+
+    async fn fetch_plan(&self, tz: Tz, id: i32) -> RepositoryResult<Option<ProductionPlan>> {
+        let store = self.store();
+        Ok(store
+            .plans
+            .iter()
+            .find(|p| p.id == id)
+            .map(|p| ProductionPlan {
+                actual: plan_actual(&store, tz, p),
+                ..p.clone()
+            }))
+    }
+
+    async fn insert_plan(&self, plan: &PlanInput) -> RepositoryResult<Option<i32>> {
+        let mut store = self.store();
+        if overlaps(&store, None, plan) {
+            return Ok(None);
+        }
+        store.last_plan_id += 1;
+        let id = store.last_plan_id;
+        store.plans.push(ProductionPlan {
+            id,
+            model: plan.model.clone(),
+            version: plan.version.clone(),
+            period_start: plan.period_start,
+            period_end: plan.period_end,
+            target: plan.target,
+            actual: 0,
+        });
+        Ok(Some(id))
+    }
+
+    async fn update_plan(&self, id: i32, plan: &PlanInput) -> RepositoryResult<bool> {
+        let mut store = self.store();
+        if overlaps(&store, Some(id), plan) {
+            return Ok(false);
+        }
+        let Some(stored) = store.plans.iter_mut().find(|p| p.id == id) else {
+            return Ok(false);
+        };
+        stored.model = plan.model.clone();
+        stored.version = plan.version.clone();
+        stored.period_start = plan.period_start;
+        stored.period_end = plan.period_end;
+        stored.target = plan.target;
+        Ok(true)
+    }
+
+    async fn delete_plan(&self, id: i32) -> RepositoryResult<bool> {
+        let mut store = self.store();
+        let before = store.plans.len();
+        store.plans.retain(|p| p.id != id);
+        Ok(store.plans.len() < before)
+    }
+
+    async fn fetch_targets(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> RepositoryResult<BTreeMap<(String, String), i64>> {
+        let mut targets = BTreeMap::new();
+        for plan in self.store().plans.iter() {
+            if plan.period_start <= to && plan.period_end >= from {
+                *targets
+                    .entry((plan.model.clone(), plan.version.clone()))
+                    .or_default() += prorated_target(
+                    plan.target as i64,
+                    plan.period_start,
+                    plan.period_end,
+                    from,
+                    to,
+                );
+            }
+        }
+        Ok(targets)
+    }
+}
+
+#[async_trait]
+impl ReportRepository for MemoryDatabase {
+    async fn production(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<Vec<(String, String, NaiveDate, i64)>> {
+        let mut counts: BTreeMap<(String, String, NaiveDate), i64> = BTreeMap::new();
+        for robot in self.store().robots.iter() {
+            if robot.created >= range.start && robot.created < range.end {
+                let day = local_date(range.tz, robot.created);
+                *counts
+                    .entry((robot.model.clone(), robot.version.clone(), day))
+                    .or_default() += 1;
+            }
+        }
+        Ok(counts
+            .into_iter()
+            .map(|((model, version, day), count)| (model, version, day, count))
+            .collect())
+    }
+
+    async fn sales(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<BTreeMap<(String, String), SalesFigures>> {
+        let store = self.store();
+        let mut figures: BTreeMap<(String, String), SalesFigures> = BTreeMap::new();
+        let in_range = |time: NaiveDateTime| time >= range.start && time < range.end;
+
+        for sale in store.sales.iter().filter(|s| in_range(s.sold_date)) {
+            let Some(robot) = store.sold_robot(sale) else {
+                continue;
+            };
+            let entry = figures
+                .entry((robot.model.clone(), robot.version.clone()))
+                .or_default();
+            entry.sold += 1;
+            entry.requests += 1;
+            entry.fulfilled += 1;
+        }
+
+        for wait in &store.waitlist {
+            let key = (wait.model.clone(), wait.version.clone());
+            if in_range(wait.requested) {
+                let entry = figures.entry(key.clone()).or_default();
+                entry.requests += 1;
+                if wait.notified.is_some_and(|notified| notified < range.end) {
+                    entry.fulfilled += 1;
+                }
+            }
+            if wait.requested < range.end && wait.notified.is_none_or(|n| n >= range.end) {
+                figures.entry(key.clone()).or_default().waiting += 1;
+            }
+            if let Some(notified) = wait.notified.filter(|n| in_range(*n)) {
+                let entry = figures.entry(key).or_default();
+                entry.notified += 1;
+                entry.wait_hours +=
+                    (notified - wait.requested).num_milliseconds() as f64 / 3_600_000.0;
+            }
+        }
+
+        Ok(figures)
+    }
+
+    async fn weekly_demand(
+        &self,
+        demand: Demand,
+        tz: Tz,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> RepositoryResult<BTreeMap<(String, String, NaiveDate), i64>> {
+        let store = self.store();
+        let times: Vec<(String, String, NaiveDateTime)> = match demand {
+            Demand::Orders => store
+                .orders
+                .iter()
+                .map(|(_, robot_model, date)| {
+                    let mut parts = robot_model.split('-');
+                    let model = parts.next().unwrap_or_default().to_string();
+                    let version = parts.next().unwrap_or_default().to_string();
+                    (model, version, *date)
+                })
+                .collect(),
+            Demand::Sold => store
+                .sales
+                .iter()
+                .filter_map(|sale| {
+                    let robot = store.sold_robot(sale)?;
+                    Some((robot.model.clone(), robot.version.clone(), sale.sold_date))
+                })
+                .collect(),
+            Demand::Waitlist => store
+                .waitlist
+                .iter()
+                .map(|w| (w.model.clone(), w.version.clone(), w.requested))
+                .collect(),
+        };
+
+        let mut counts = BTreeMap::new();
+        for (model, version, time) in times {
+            if time >= start && time < end {
+                let week = week_start(local_date(tz, time));
+                *counts.entry((model, version, week)).or_default() += 1;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+#[async_trait]
+impl ArchiveRepository for MemoryDatabase {
+    async fn archive_report(&self, entry: &ReportEntry<'_>) -> RepositoryResult<i32> {
+        let mut store = self.store();
+        let id = store.reports.len() as i32 + 1;
+        store.reports.push(StoredReport {
+            report: ArchivedReport {
+                id,
+                kind: entry.kind.to_string(),
+                format: entry.format.extension().to_string(),
+                period_from: entry.period_from,
+                period_to: entry.period_to,
+                timezone: entry.timezone.to_string(),
+                generated_at: entry.generated_at,
+                source: entry.source.as_str().to_string(),
+                filename: entry.filename.clone(),
+                size: entry.content.len() as i32,
+            },
+            content_type: entry.format.content_type().to_string(),
+            content: entry.content.to_vec(),
+        });
+        Ok(id)
+    }
+
+    async fn list_reports(&self, kind: Option<&str>) -> RepositoryResult<Vec<ArchivedReport>> {
+        let mut reports: Vec<ArchivedReport> = self
+            .store()
+            .reports
+            .iter()
+            .filter(|r| kind.is_none_or(|kind| r.report.kind == kind))
+            .map(|r| r.report.clone())
+            .collect();
+        reports.sort_by_key(|r| std::cmp::Reverse((r.generated_at, r.id)));
+        Ok(reports)
+    }
+
+    async fn fetch_report(&self, id: i32) -> RepositoryResult<Option<(String, String, Vec<u8>)>> {
+        Ok(self
+            .store()
+            .reports
+            .iter()
+            .find(|r| r.report.id == id)
+            .map(|r| {
+                (
+                    r.report.filename.clone(),
+                    r.content_type.clone(),
+                    r.content.clone(),
+                )
+            }))
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use chrono::NaiveDateTime;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+
+use crate::constants::{DB_MAX_CONNECTIONS, FULFILMENT_BUCKETS, UNMATCHED_ROUTE};
+use crate::error::AppError;
+use crate::state::AppState;
+
+// Registered once per process in the default registry, served at /metrics.
+// Counters only ever grow, Prometheus computes the rates.
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by method, route and status",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time to answer HTTP requests by method and route",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref ROBOTS_CREATED: IntCounterVec = register_int_counter_vec!(
+        "robots_created_total",
+        "Robots created by model",
+        &["model"]
+    )
+    .unwrap();
+    static ref ROBOTS_REMOVED: IntCounterVec = register_int_counter_vec!(
+        "robots_removed_total",
+        "Robots decommissioned by model",
+        &["model"]
+    )
+    .unwrap();
+    static ref WAITLIST_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "waitlist_depth",
+        "Customers waiting for a robot by model",
+        &["model"]
+    )
+    .unwrap();
+    static ref ORDER_FULFILMENT: Histogram = register_histogram!(
+        "order_fulfilment_seconds",
+        "Time from an order going on the waitlist to the customer being notified",
+        FULFILMENT_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref EMAILS: IntCounterVec = register_int_counter_vec!(
+        "emails_total",
+        "Emails by kind (order, alert, report) and outcome (sent, failed)",
+        &["kind", "outcome"]
+    )
+    .unwrap();
+    static ref DB_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "db_pool_connections",
+        "Open connections of the database pool by state (idle, in_use)",
+        &["state"]
+    )
+    .unwrap();
+    static ref DB_MAX: IntGauge = register_int_gauge!(
+        "db_pool_max_connections",
+        "Connections the database pool may open"
+    )
+    .unwrap();
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+// Counts every request under the route it matched, so that paths with ids
+// do not create a series each. Unknown paths share UNMATCHED_ROUTE.
+pub async fn track_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, MatchedPath::as_str)
+        .to_string();
+    let method = request.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+pub fn robot_created(model: &str) {
+    ROBOTS_CREATED.with_label_values(&[model]).inc();
+}
+
+pub fn robot_removed(model: &str) {
+    ROBOTS_REMOVED.with_label_values(&[model]).inc();
+}
+
+pub fn order_fulfilled(requested: NaiveDateTime, notified: NaiveDateTime) {
+    let waited = (notified - requested).num_milliseconds() as f64 / 1000.0;
+    ORDER_FULFILMENT.observe(waited.max(0.0));
+}
+
+pub fn email_sent(kind: &str, sent: bool) {
+    let outcome = if sent { "sent" } else { "failed" };
+    EMAILS.with_label_values(&[kind, outcome]).inc();
+}
+
+// The waitlist and the pool are read at scrape time, a waitlist that cannot be
+// read keeps its last values
+pub async fn metrics_handler(State(state): State<AppState>) -> Result<Response, AppError> {
+    match state.repos.orders.waitlist_depth().await {
+        Ok(depth) => {
+            WAITLIST_DEPTH.reset();
+            for (model, waiting) in depth {
+                WAITLIST_DEPTH.with_label_values(&[&model]).set(waiting);
+            }
+        }
+        Err(e) => eprintln!("Failed to read the waitlist depth: {e}"),
+    }
+
+    let open = state.pool.size() as i64;
+    let idle = state.pool.num_idle() as i64;
+    DB_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    DB_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set((open - idle).max(0));
+    DB_MAX.set(DB_MAX_CONNECTIONS.into());
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut body)
+        .map_err(|e| AppError::internal(e.to_string()))?;
+    Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response())
+}
@@ -0,0 +1,183 @@
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MessageBuilder, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::constants::{SMTP_SENDER, SMTP_SERVER};
+use crate::metrics;
+
+// File attached to an email
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmailAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+// Mail server from the SMTP_SERVER environment variable, None if it is not set
+fn configured_server() -> Option<String> {
+    std::env::var("SMTP_SERVER")
+        .ok()
+        .filter(|server| !server.trim().is_empty())
+}
+
+fn mailer() -> Result<SmtpTransport, lettre::transport::smtp::Error> {
+    let server = configured_server().unwrap_or_else(|| SMTP_SERVER.to_string());
+    Ok(SmtpTransport::relay(&server)?
+        .credentials(Credentials::new("user".to_string(), "password".to_string()))
+        .build())
+}
+
+// One email, sent to every recipient
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub attachment: Option<EmailAttachment>,
+}
+
+impl Email {
+    pub fn new(to: Vec<String>, subject: &str, body: &str) -> Self {
+        Self {
+            to,
+            subject: subject.to_string(),
+            body: body.to_string(),
+            attachment: None,
+        }
+    }
+
+    pub fn with_attachment(mut self, attachment: EmailAttachment) -> Self {
+        self.attachment = Some(attachment);
+        self
+    }
+}
+
+// Sends the emails of the app: order notifications, stock alerts and scheduled reports.
+// Sending blocks, async callers use spawn_blocking.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error>;
+
+    // Whether emails can be sent at all, for /health/ready. Returns what was checked.
+    fn check(&self) -> Result<String, anyhow::Error>;
+}
+
+// Sends `email` on a blocking thread and waits for it, the outcome is counted
+// under `kind` in the metrics
+pub async fn send_email(
+    mailer: Arc<dyn Mailer>,
+    kind: &str,
+    email: Email,
+) -> Result<(), anyhow::Error> {
+    let sent = tokio::task::spawn_blocking(move || mailer.send(&email))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|sent| sent);
+    metrics::email_sent(kind, sent.is_ok());
+    sent
+}
+
+pub struct SmtpMailer;
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error> {
+        let builder = Message::builder()
+            .from(SMTP_SENDER.parse()?)
+            .subject(&email.subject);
+        let builder = to_recipients(builder, &email.to)?;
+
+        let message = match &email.attachment {
+            None => builder.body(email.body.clone())?,
+            Some(attachment) => {
+                let content_type = ContentType::parse(&attachment.content_type)?;
+                builder.multipart(
+                    MultiPart::mixed()
+                        .singlepart(SinglePart::plain(email.body.clone()))
+                        .singlepart(
+                            Attachment::new(attachment.filename.clone())
+                                .body(attachment.content.clone(), content_type),
+                        ),
+                )?
+            }
+        };
+
+        mailer()?.send(&message)?;
+        Ok(())
+    }
+
+    // Only a server set with SMTP_SERVER is checked, the default one is a placeholder
+    fn check(&self) -> Result<String, anyhow::Error> {
+        let Some(server) = configured_server() else {
+            return Ok("Skipped, SMTP_SERVER is not set".to_string());
+        };
+        if !mailer()?.test_connection()? {
+            anyhow::bail!("SMTP server {server} did not answer");
+        }
+        Ok(format!("SMTP server {server} reachable"))
+    }
+}
+
+// Keeps the emails instead of sending them, for tests and demos without a mail server
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    // Only read by tests, demos see the emails in the log
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error> {
+        println!("Email to {}: {}", email.to.join(", "), email.subject);
+        self.sent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(email.clone());
+        Ok(())
+    }
+
+    fn check(&self) -> Result<String, anyhow::Error> {
+        Ok("Emails are kept in memory".to_string())
+    }
+}
+
+// Comma separated list of addresses from the environment variable, `default` if it is not set
+pub fn recipients_from_env(var: &str, default: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|recipient| !recipient.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Recipients that fail to parse are skipped
+fn to_recipients(
+    mut builder: MessageBuilder,
+    recipients: &[String],
+) -> Result<MessageBuilder, anyhow::Error> {
+    let mut valid = 0;
+    for recipient in recipients {
+        match recipient.parse::<Mailbox>() {
+            Ok(mailbox) => {
+                builder = builder.to(mailbox);
+                valid += 1;
+            }
+            Err(e) => eprintln!("Skipping invalid recipient {recipient}: {e}"),
+        }
+    }
+    if valid == 0 {
+        anyhow::bail!("No valid recipients");
+    }
+    Ok(builder)
+}
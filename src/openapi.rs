@@ -0,0 +1,144 @@
+use axum::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{self, Server};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::constants::{API_V1, API_V2, DOCS_PATH, OPENAPI_V1_PATH, OPENAPI_V2_PATH};
+use crate::{archive, batch, error, forecast, inventory, period, plan, processing};
+use crate::{report, report_format, robot, sales, stock, user, v2};
+
+// OpenAPI 3 document of every route of v1 in api.rs, in the same order.
+// Paths are relative to the version prefix, which is the document's server.
+// Schemas come from the request and response types themselves.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Robot factory",
+        description = "Production, inventory, orders and reports of the robot factory"
+    ),
+    paths(
+        inventory::list_robots_handler,
+        report::report_handler,
+        archive::list_reports_handler,
+        sales::sales_report_handler,
+        archive::download_report_handler,
+        robot::remove_robot_request_handler,
+        batch::batch_handler,
+        processing::order_robot,
+        inventory::robot_details_handler,
+        inventory::remove_robot_handler,
+        inventory::restore_robot_handler,
+        forecast::forecast_handler,
+        plan::list_plans_handler,
+        plan::create_plan_handler,
+        plan::get_plan_handler,
+        plan::update_plan_handler,
+        plan::delete_plan_handler,
+        stock::list_thresholds_handler,
+        stock::set_threshold_handler,
+        stock::delete_threshold_handler,
+        user::create_customer,
+        robot::create_robot_handler,
+    ),
+    components(schemas(
+        robot::Robot,
+        robot::RemovalRequest,
+        user::Customer,
+        processing::CurrentOrder,
+        inventory::RobotRecord,
+        inventory::SaleRecord,
+        inventory::RobotDetails,
+        inventory::RobotPage,
+        inventory::RobotSort,
+        inventory::SortOrder,
+        batch::BatchMode,
+        batch::BatchSummary,
+        batch::RowError,
+        period::Period,
+        period::ReportRange,
+        report_format::ReportFormat,
+        report::ProductionReport,
+        report::ModelSummary,
+        report::VersionSummary,
+        sales::SalesReport,
+        sales::SalesFigures,
+        sales::ModelSales,
+        sales::VersionSales,
+        forecast::Forecast,
+        forecast::ForecastParams,
+        forecast::ForecastSeries,
+        archive::ArchivedReport,
+        plan::PlanInput,
+        plan::ProductionPlan,
+        plan::PlanProgress,
+        stock::StockThreshold,
+        stock::ThresholdInput,
+        error::AppError,
+        error::ErrorCode,
+        error::FieldError,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "robots", description = "Robots in production and in stock"),
+        (name = "orders", description = "Orders and the waitlist"),
+        (name = "customers", description = "Customer accounts"),
+        (name = "reports", description = "Production and sales reports and their archive"),
+        (name = "analytics", description = "Demand forecast"),
+        (name = "plans", description = "Production plans"),
+        (name = "stock", description = "Low stock thresholds"),
+    )
+)]
+pub struct ApiDoc;
+
+// Operations of v2 that differ from v1, the rest of its document is taken from v1
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Robot factory",
+        description = "Production, inventory, orders and reports of the robot factory"
+    ),
+    paths(v2::create_robot_handler),
+    components(schemas(v2::CreatedRobot))
+)]
+pub struct ApiDocV2;
+
+// Staff tokens from API_TOKENS, sent as `Authorization: Bearer <token>`
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+pub fn v1() -> openapi::OpenApi {
+    let mut doc = ApiDoc::openapi();
+    doc.servers = Some(vec![Server::new(API_V1)]);
+    doc
+}
+
+// `merge` keeps the v2 operations where both versions document a path
+pub fn v2() -> openapi::OpenApi {
+    let mut doc = ApiDocV2::openapi();
+    doc.merge(ApiDoc::openapi());
+    doc.servers = Some(vec![Server::new(API_V2)]);
+    doc
+}
+
+// The v1 document for clients that still read OPENAPI_PATH
+pub async fn v1_handler() -> Json<openapi::OpenApi> {
+    Json(v1())
+}
+
+// Serves the document of each version and Swagger UI for them at DOCS_PATH
+pub fn docs() -> SwaggerUi {
+    SwaggerUi::new(DOCS_PATH)
+        .url(OPENAPI_V1_PATH, v1())
+        .url(OPENAPI_V2_PATH, v2())
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize, Serializer};
+use utoipa::{IntoParams, ToSchema};
+
+#[cfg(feature = "sqlite")]
+use crate::constants::QUARTER_SECONDS;
+use crate::constants::{FACTORY_TIMEZONE, MAX_REPORT_DAYS};
+
+// The factory time zone can be overridden with the FACTORY_TIMEZONE environment variable
+pub fn factory_timezone() -> Tz {
+    let name = std::env::var("FACTORY_TIMEZONE").unwrap_or_else(|_| FACTORY_TIMEZONE.to_string());
+
+    match Tz::from_str(&name) {
+        Ok(tz) => tz,
+        Err(e) => {
+            eprintln!("Unknown factory time zone {name}: {e}, falling back to {FACTORY_TIMEZONE}");
+            Tz::from_str(FACTORY_TIMEZONE).unwrap()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Quarter,
+}
+
+impl Period {
+    // First day of a period that ends on `to` (inclusive), None past the calendar's limits
+    fn start_for(self, to: NaiveDate) -> Option<NaiveDate> {
+        let next = to.succ_opt()?;
+        match self {
+            Period::Day => Some(to),
+            Period::Week => next.checked_sub_signed(Duration::days(7)),
+            Period::Month => next.checked_sub_months(Months::new(1)),
+            Period::Quarter => next.checked_sub_months(Months::new(3)),
+        }
+    }
+
+    // Last day (inclusive) of a period that starts on `from`, None past the calendar's limits.
+    // Months end the day before the same day of the month after, or on the last day of
+    // that month if it is shorter, e.g. 2023-01-31 to 2023-02-28.
+    fn end_for(self, from: NaiveDate) -> Option<NaiveDate> {
+        let months = match self {
+            Period::Day => return from.succ_opt().map(|_| from),
+            Period::Week => return from.checked_add_signed(Duration::days(7))?.pred_opt(),
+            Period::Month => Months::new(1),
+            Period::Quarter => Months::new(3),
+        };
+        let next = from.checked_add_months(months)?;
+        if next.day() == from.day() {
+            next.pred_opt()
+        } else {
+            Some(next)
+        }
+    }
+}
+
+// Query parameters accepted by the report endpoints, e.g.
+// /robots/report?period=month or /robots/report?from=2023-10-01&to=2023-10-15
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub period: Option<Period>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    PeriodWithExplicitRange,
+    Inverted { from: NaiveDate, to: NaiveDate },
+    TooLong { days: i64 },
+    // The range or its UTC interval does not fit the calendar, e.g. from=+262143-12-31
+    OutOfRange,
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeError::PeriodWithExplicitRange => {
+                write!(f, "`period` cannot be combined with both `from` and `to`")
+            }
+            RangeError::Inverted { from, to } => {
+                write!(f, "`from` ({from}) must not be later than `to` ({to})")
+            }
+            RangeError::TooLong { days } => write!(
+                f,
+                "Requested range covers {days} days, the maximum is {MAX_REPORT_DAYS}"
+            ),
+            RangeError::OutOfRange => write!(f, "Requested range is outside the supported dates"),
+        }
+    }
+}
+
+impl std::error::Error for RangeError {}
+
+// Inclusive range of factory-local calendar days together with the matching
+// half-open [start, end) interval in UTC, which is how `robots.created` is stored
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ReportRange {
+    #[serde(rename = "timezone", serialize_with = "serialize_tz")]
+    #[schema(value_type = String, example = "Europe/Moscow")]
+    pub tz: Tz,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    #[serde(skip)]
+    pub start: NaiveDateTime,
+    #[serde(skip)]
+    pub end: NaiveDateTime,
+}
+
+fn serialize_tz<S: Serializer>(tz: &Tz, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(tz.name())
+}
+
+impl ReportQuery {
+    // Resolves the query against the current date in the factory time zone
+    pub fn resolve_now(&self) -> Result<ReportRange, RangeError> {
+        let tz = factory_timezone();
+        self.resolve(tz, Utc::now().with_timezone(&tz).date_naive())
+    }
+
+    pub fn resolve(&self, tz: Tz, today: NaiveDate) -> Result<ReportRange, RangeError> {
+        let period = self.period.unwrap_or(Period::Week);
+
+        let (from, to) = match (self.from, self.to) {
+            (Some(_), Some(_)) if self.period.is_some() => {
+                return Err(RangeError::PeriodWithExplicitRange)
+            }
+            (Some(from), Some(to)) => (from, to),
+            (Some(from), None) => (from, period.end_for(from).ok_or(RangeError::OutOfRange)?),
+            (None, to) => {
+                let to = to.unwrap_or(today);
+                (period.start_for(to).ok_or(RangeError::OutOfRange)?, to)
+            }
+        };
+
+        if from > to {
+            return Err(RangeError::Inverted { from, to });
+        }
+
+        let days = (to - from).num_days() + 1;
+        if days > MAX_REPORT_DAYS {
+            return Err(RangeError::TooLong { days });
+        }
+
+        let start = local_midnight_utc(tz, from).ok_or(RangeError::OutOfRange)?;
+        let end = local_day_end_utc(tz, to).ok_or(RangeError::OutOfRange)?;
+        Ok(ReportRange {
+            tz,
+            from,
+            to,
+            start,
+            end,
+        })
+    }
+}
+
+// Start of the local day in UTC, None if it does not fit the calendar.
+// Where a DST transition skips midnight the day starts when the gap ends,
+// the first minute that exists on the clock. A skipped day starts the next day.
+pub fn local_midnight_utc(tz: Tz, date: NaiveDate) -> Option<NaiveDateTime> {
+    let midnight = date.and_hms_opt(0, 0, 0)?;
+
+    (0..=2 * 24 * 60).find_map(|minute| {
+        let local = midnight.checked_add_signed(Duration::minutes(minute))?;
+        // Offsets are below a day, the conversion cannot overflow if a day either side fits
+        local.checked_sub_signed(Duration::days(1))?;
+        local.checked_add_signed(Duration::days(1))?;
+        tz.from_local_datetime(&local)
+            .earliest()
+            .map(|local| local.naive_utc())
+    })
+}
+
+// End of the local day in UTC, i.e. the start of the next one
+pub fn local_day_end_utc(tz: Tz, date: NaiveDate) -> Option<NaiveDateTime> {
+    local_midnight_utc(tz, date.succ_opt()?)
+}
+
+// Day in the time zone of a UTC time
+pub fn local_date(tz: Tz, utc: NaiveDateTime) -> NaiveDate {
+    Utc.from_utc_datetime(&utc).with_timezone(&tz).date_naive()
+}
+
+// SQLite cannot convert time zones, so its queries count per UTC quarter hour,
+// `CAST(strftime('%s', time) AS INTEGER) / QUARTER_SECONDS`, and the quarters are
+// assigned to local days here. Every UTC offset is whole quarter hours since 1972.
+#[cfg(feature = "sqlite")]
+pub fn quarter_local_date(tz: Tz, quarter: i64) -> Option<NaiveDate> {
+    let utc = NaiveDateTime::from_timestamp_opt(quarter.checked_mul(QUARTER_SECONDS)?, 0)?;
+    Some(local_date(tz, utc))
+}
+
+// Monday of the week of a day
+pub fn week_start(day: NaiveDate) -> NaiveDate {
+    day - Duration::days(day.weekday().num_days_from_monday() as i64)
+}
@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+use validator_derive::Validate;
+
+use crate::constants::{MAX_REPORT_DAYS, PLANS_PER_QUERY, PLAN_THRESHOLD};
+use crate::db::{insert_returning_id, lock_table, placeholders, validate_model_version};
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppJson, AppPath, AppQuery};
+use crate::period::{factory_timezone, local_day_end_utc, local_midnight_utc, RangeError};
+use crate::repository::{PlanRepository, Repositories};
+
+// Production quota of one model/version, the period includes both days
+#[derive(Debug, Clone, Deserialize, Serialize, Validate, ToSchema)]
+pub struct PlanInput {
+    #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "M1")]
+    pub model: String,
+    #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "V1")]
+    pub version: String,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    #[validate(range(min = 0))]
+    #[schema(minimum = 0)]
+    pub target: i32,
+}
+
+impl PlanInput {
+    fn check(&self) -> Result<(), AppError> {
+        self.validate()?;
+        let days = (self.period_end - self.period_start).num_days() + 1;
+        if days < 1 {
+            return Err(AppError::bad_request(format!(
+                "`period_end` ({}) is before `period_start` ({})",
+                self.period_end, self.period_start
+            )));
+        }
+        if days > MAX_REPORT_DAYS {
+            return Err(AppError::bad_request(format!(
+                "A plan can cover at most {MAX_REPORT_DAYS} days, got {days}"
+            )));
+        }
+        // Actuals are counted between these instants
+        let tz = factory_timezone();
+        if local_midnight_utc(tz, self.period_start).is_none()
+            || local_day_end_utc(tz, self.period_end).is_none()
+        {
+            return Err(AppError::bad_request(RangeError::OutOfRange.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// `actual` is the number of robots created during the plan period in the factory time zone
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct ProductionPlan {
+    pub id: i32,
+    pub model: String,
+    pub version: String,
+    pub period_start: NaiveDate,
+    pub period_end: NaiveDate,
+    pub target: i32,
+    #[sqlx(default)]
+    pub actual: i64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PlanProgress {
+    #[serde(flatten)]
+    pub plan: ProductionPlan,
+    pub attainment: Option<f64>,
+    pub below_target: bool,
+}
+
+impl From<ProductionPlan> for PlanProgress {
+    fn from(plan: ProductionPlan) -> Self {
+        let attainment = attainment(plan.actual, plan.target as i64);
+        Self {
+            plan,
+            attainment,
+            below_target: is_below_target(attainment),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PlanQuery {
+    pub model: Option<String>,
+    pub version: Option<String>,
+    // Only plans overlapping [from, to]
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+// Share of the target that was produced, there is nothing to attain with a zero target
+pub fn attainment(actual: i64, target: i64) -> Option<f64> {
+    (target > 0).then(|| actual as f64 / target as f64)
+}
+
+pub fn is_below_target(attainment: Option<f64>) -> bool {
+    attainment.is_some_and(|a| a < PLAN_THRESHOLD)
+}
+
+// Part of the target that falls into [from, to], assuming an even pace over the plan period
+pub fn prorated_target(
+    target: i64,
+    period_start: NaiveDate,
+    period_end: NaiveDate,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> i64 {
+    let plan_days = (period_end - period_start).num_days() + 1;
+    let overlap = (period_end.min(to) - period_start.max(from)).num_days() + 1;
+    if plan_days <= 0 || overlap <= 0 {
+        return 0;
+    }
+    (target as f64 * overlap as f64 / plan_days as f64).round() as i64
+}
+
+// Targets of every model/version with a plan overlapping [from, to], prorated to the range
+pub async fn fetch_targets(
+    pool: &DbPool,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> sqlx::Result<BTreeMap<(String, String), i64>> {
+    let plans: Vec<(String, String, NaiveDate, NaiveDate, i32)> = sqlx::query_as(
+        "SELECT model, version, period_start, period_end, target FROM production_plans
+        WHERE period_start <= $2 AND period_end >= $1",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let mut targets = BTreeMap::new();
+    for (model, version, start, end, target) in plans {
+        *targets.entry((model, version)).or_default() +=
+            prorated_target(target as i64, start, end, from, to);
+    }
+    Ok(targets)
+}
+
+const PLAN_COLUMNS: &str = "p.id, p.model, p.version, p.period_start, p.period_end, p.target";
+
+// Counts the robots made during each plan period, whose days start at midnight in `tz`.
+// The bounds are computed here, SQLite cannot convert time zones, and SQL counts per plan.
+async fn count_actuals(pool: &DbPool, tz: Tz, plans: &mut [ProductionPlan]) -> sqlx::Result<()> {
+    // Plans are checked to fit the calendar when they are written
+    let bounds: Vec<(i32, NaiveDateTime, NaiveDateTime)> = plans
+        .iter()
+        .filter_map(|plan| {
+            let start = local_midnight_utc(tz, plan.period_start)?;
+            let end = local_day_end_utc(tz, plan.period_end)?;
+            Some((plan.id, start, end))
+        })
+        .collect();
+
+    let mut actual: HashMap<i32, i64> = HashMap::new();
+    for chunk in bounds.chunks(PLANS_PER_QUERY) {
+        let values: Vec<String> = (0..chunk.len())
+            .map(|i| format!("({})", placeholders(i * 3 + 1, 3)))
+            .collect();
+        let sql = format!(
+            "WITH bounds (plan_id, period_from, period_to) AS (VALUES {})
+            SELECT b.plan_id, COUNT(r.id) FROM bounds b
+            JOIN production_plans p ON p.id = b.plan_id
+            LEFT JOIN robots r ON r.model = p.model AND r.version = p.version
+            AND r.created >= b.period_from AND r.created < b.period_to
+            GROUP BY b.plan_id",
+            values.join(", ")
+        );
+        let mut query = sqlx::query_as(&sql);
+        for (id, start, end) in chunk {
+            query = query.bind(id).bind(start).bind(end);
+        }
+        let counts: Vec<(i32, i64)> = query.fetch_all(pool).await?;
+        actual.extend(counts);
+    }
+
+    for plan in plans {
+        plan.actual = actual.get(&plan.id).copied().unwrap_or_default();
+    }
+    Ok(())
+}
+
+pub async fn list_plans(
+    pool: &DbPool,
+    tz: Tz,
+    query: &PlanQuery,
+) -> sqlx::Result<Vec<ProductionPlan>> {
+    let sql = format!(
+        "SELECT {PLAN_COLUMNS} FROM production_plans p
+        WHERE (CAST($1 AS TEXT) IS NULL OR p.model = $1)
+        AND (CAST($2 AS TEXT) IS NULL OR p.version = $2)
+        AND (CAST($3 AS DATE) IS NULL OR p.period_end >= $3)
+        AND (CAST($4 AS DATE) IS NULL OR p.period_start <= $4)
+        ORDER BY p.period_start, p.model, p.version"
+    );
+
+    let mut plans: Vec<ProductionPlan> = sqlx::query_as(&sql)
+        .bind(&query.model)
+        .bind(&query.version)
+        .bind(query.from)
+        .bind(query.to)
+        .fetch_all(pool)
+        .await?;
+    count_actuals(pool, tz, &mut plans).await?;
+    Ok(plans)
+}
+
+pub async fn fetch_plan(pool: &DbPool, tz: Tz, id: i32) -> sqlx::Result<Option<ProductionPlan>> {
+    let sql = format!("SELECT {PLAN_COLUMNS} FROM production_plans p WHERE p.id = $1");
+
+    let mut plan: Option<ProductionPlan> =
+        sqlx::query_as(&sql).bind(id).fetch_optional(pool).await?;
+    if let Some(plan) = &mut plan {
+        count_actuals(pool, tz, std::slice::from_mut(plan)).await?;
+    }
+    Ok(plan)
+}
+
+// Plans of the same model/version must not overlap, returns None if the period is taken.
+// Writes wait for each other, so two of them cannot both find the period free.
+pub async fn insert_plan(pool: &DbPool, plan: &PlanInput) -> sqlx::Result<Option<i32>> {
+    let sql = "INSERT INTO production_plans (model, version, period_start, period_end, target)
+        SELECT $1, $2, $3, $4, $5 WHERE NOT EXISTS (
+            SELECT 1 FROM production_plans WHERE model = $1 AND version = $2
+            AND period_start <= $4 AND period_end >= $3)
+        RETURNING id";
+
+    let mut tx = pool.begin().await?;
+    lock_table(&mut tx, "production_plans").await?;
+    let query = sqlx::query_scalar(sql)
+        .bind(&plan.model)
+        .bind(&plan.version)
+        .bind(plan.period_start)
+        .bind(plan.period_end)
+        .bind(plan.target);
+    let id = insert_returning_id(query, &mut tx).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+// Returns the number of updated plans, 0 if the plan does not exist or the period is taken
+pub async fn update_plan(pool: &DbPool, id: i32, plan: &PlanInput) -> sqlx::Result<u64> {
+    let sql = "UPDATE production_plans
+        SET model = $2, version = $3, period_start = $4, period_end = $5, target = $6
+        WHERE id = $1 AND NOT EXISTS (
+            SELECT 1 FROM production_plans WHERE id <> $1 AND model = $2 AND version = $3
+            AND period_start <= $5 AND period_end >= $4)";
+
+    let mut tx = pool.begin().await?;
+    lock_table(&mut tx, "production_plans").await?;
+    let updated = sqlx::query(sql)
+        .bind(id)
+        .bind(&plan.model)
+        .bind(&plan.version)
+        .bind(plan.period_start)
+        .bind(plan.period_end)
+        .bind(plan.target)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    Ok(updated)
+}
+
+pub async fn delete_plan(pool: &DbPool, id: i32) -> sqlx::Result<u64> {
+    sqlx::query("DELETE FROM production_plans WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+fn overlap_error(plan: &PlanInput) -> AppError {
+    AppError::conflict(format!(
+        "{}-{} already has a plan overlapping {} - {}",
+        plan.model, plan.version, plan.period_start, plan.period_end
+    ))
+}
+
+fn plan_not_found(id: i32) -> AppError {
+    AppError::not_found(format!("Plan {id} not found"))
+}
+
+async fn plan_progress(
+    plans: &dyn PlanRepository,
+    id: i32,
+) -> Result<Json<PlanProgress>, AppError> {
+    match plans.fetch_plan(factory_timezone(), id).await? {
+        Some(plan) => Ok(Json(plan.into())),
+        None => Err(plan_not_found(id)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/plans",
+    tag = "plans",
+    params(PlanQuery),
+    responses((status = 200, description = "Plans with their progress", body = [PlanProgress]))
+)]
+pub async fn list_plans_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<PlanQuery>,
+) -> Result<Json<Vec<PlanProgress>>, AppError> {
+    let plans = repos.plans.list_plans(factory_timezone(), &query).await?;
+    Ok(Json(plans.into_iter().map(PlanProgress::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/plans/{id}",
+    tag = "plans",
+    params(("id" = i32, Path, description = "Id of the plan")),
+    responses(
+        (status = 200, description = "The plan and its progress", body = PlanProgress),
+        (status = 404, description = "No such plan", body = AppError),
+    )
+)]
+pub async fn get_plan_handler(
+    State(repos): State<Repositories>,
+    AppPath(id): AppPath<i32>,
+) -> Result<Json<PlanProgress>, AppError> {
+    plan_progress(repos.plans.as_ref(), id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/plans",
+    tag = "plans",
+    request_body = PlanInput,
+    responses(
+        (status = 201, description = "The new plan and its progress", body = PlanProgress),
+        (status = 400, description = "Invalid plan", body = AppError),
+        (status = 409, description = "Overlaps another plan of the version", body = AppError),
+    )
+)]
+pub async fn create_plan_handler(
+    State(repos): State<Repositories>,
+    AppJson(plan): AppJson<PlanInput>,
+) -> Result<(StatusCode, Json<PlanProgress>), AppError> {
+    plan.check()?;
+
+    match repos.plans.insert_plan(&plan).await? {
+        Some(id) => {
+            println!("Production plan {id} has been added");
+            let progress = plan_progress(repos.plans.as_ref(), id).await?;
+            Ok((StatusCode::CREATED, progress))
+        }
+        None => Err(overlap_error(&plan)),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/plans/{id}",
+    tag = "plans",
+    params(("id" = i32, Path, description = "Id of the plan")),
+    request_body = PlanInput,
+    responses(
+        (status = 200, description = "The updated plan and its progress", body = PlanProgress),
+        (status = 400, description = "Invalid plan", body = AppError),
+        (status = 404, description = "No such plan", body = AppError),
+        (status = 409, description = "Overlaps another plan of the version", body = AppError),
+    )
+)]
+pub async fn update_plan_handler(
+    State(repos): State<Repositories>,
+    AppPath(id): AppPath<i32>,
+    AppJson(plan): AppJson<PlanInput>,
+) -> Result<Json<PlanProgress>, AppError> {
+    plan.check()?;
+
+    if repos.plans.update_plan(id, &plan).await? {
+        return plan_progress(repos.plans.as_ref(), id).await;
+    }
+    match repos.plans.fetch_plan(factory_timezone(), id).await? {
+        Some(_) => Err(overlap_error(&plan)),
+        None => Err(plan_not_found(id)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/plans/{id}",
+    tag = "plans",
+    params(("id" = i32, Path, description = "Id of the plan")),
+    responses(
+        (status = 204, description = "Plan removed"),
+        (status = 404, description = "No such plan", body = AppError),
+    )
+)]
+pub async fn delete_plan_handler(
+    State(repos): State<Repositories>,
+    AppPath(id): AppPath<i32>,
+) -> Result<StatusCode, AppError> {
+    if !repos.plans.delete_plan(id).await? {
+        return Err(plan_not_found(id));
+    }
+    println!("Production plan {id} has been removed");
+    Ok(StatusCode::NO_CONTENT)
+}
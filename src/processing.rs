@@ -2,192 +2,258 @@ use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::Duration;
 
-use axum::extract::Json;
-use lettre::transport::smtp::response::Response;
-use lettre::{Message, SmtpTransport, Transport};
+use axum::extract::State;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgPool;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
+use utoipa::ToSchema;
 use validator::Validate;
 use validator_derive::Validate;
 
-use crate::constants::{CHECK_INTERVAL, DATABASE_URL, SMTP_SENDER, SMTP_SERVER};
-use crate::db::{validate_model_version, Database};
+use crate::constants::{CHECK_INTERVAL, ORDER_AVAILABLE_SUBJECT};
+use crate::db::validate_model_version;
+use crate::error::{AppError, AppJson, ErrorCode};
+use crate::metrics;
+use crate::notification::{send_email, Email, Mailer};
 use crate::order::Order;
+use crate::repository::{Repositories, RepositoryResult};
+use crate::state::AppState;
+use crate::supervisor::Heartbeat;
 
-#[derive(Debug, Deserialize, Serialize, Validate)]
+#[derive(Debug, Deserialize, Serialize, Validate, ToSchema)]
 pub struct CurrentOrder {
     pub login: String,
+    #[schema(format = Password)]
     pub password: String,
     // Check that the model and version match the template [A-Za-z][0-9]
     #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "M1")]
     pub model: String,
     #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "V1")]
     pub version: String,
 }
 
+// Orders waiting for a robot. The lock is held only to add or take orders,
+// never while the database or the mail server is working.
 pub struct OrderQueue {
-    pub orders: std::collections::VecDeque<CurrentOrder>,
-    pool: Arc<Mutex<PgPool>>,
+    pub orders: Mutex<VecDeque<CurrentOrder>>,
+    repos: Repositories,
+    mailer: Arc<dyn Mailer>,
 }
 
 impl OrderQueue {
-    pub async fn new() -> Self {
-        let pool = match PgPool::connect(DATABASE_URL).await {
-            Ok(pool) => pool,
-            Err(err) => panic!("Failed to open database connection: {}", err),
-        };
-        // Wrap the connection in Arc and Mutex for secure access from different threads
-        let pool = Arc::new(Mutex::new(pool));
-
-        let orders = VecDeque::new();
+    pub fn new(repos: Repositories, mailer: Arc<dyn Mailer>) -> Self {
+        let orders = Mutex::new(VecDeque::new());
 
-        Self { orders, pool }
+        Self {
+            orders,
+            repos,
+            mailer,
+        }
     }
 
-    // Method for adding an order to the queue
-    pub async fn enqueue(&mut self, order_current: CurrentOrder) {
+    // Sells a robot in stock, or puts the order on the waitlist if there is none.
+    // Returns whether the robot was sold.
+    pub async fn enqueue(&self, order_current: CurrentOrder) -> RepositoryResult<bool> {
         println!("Enqueue: {:?}", order_current);
 
-        let db = Database::new().await.unwrap();
-        let pool = self.pool.lock().await;
-        let order = Order {
-            customer_name: order_current.login.clone(),
-            robot_model: order_current.model.clone(),
+        let repos = self.repos.clone();
+        let in_stock = repos
+            .robots
+            .in_stock(&order_current.model, &order_current.version)
+            .await?;
+        let sold = if in_stock > 0 {
+            repos
+                .orders
+                .sell_robot(
+                    &order_current.login,
+                    &order_current.model,
+                    &order_current.version,
+                )
+                .await?
+        } else {
+            0
         };
 
-        let result = Database::find_robot(&db, &order_current.model, &order_current.version).await;
-        // Check that the result is not empty
-        match result {
-            Ok(0) => {
-                println!("Product is out of stock");
+        if sold == 0 {
+            println!("Product is out of stock");
 
-                self.orders.push_back(order_current);
+            if let Err(e) = repos
+                .orders
+                .add_to_waitlist(
+                    &order_current.login,
+                    &order_current.model,
+                    &order_current.version,
+                )
+                .await
+            {
+                eprintln!("Failed to save the order to the waitlist: {e}");
             }
-            Ok(_) => {
-                println!("Product is in stock");
-                // Save completed order to "orders" table
-                let customer_name: String =
-                    sqlx::query_scalar("SELECT name FROM customers WHERE login = $1")
-                        .bind(&order_current.login)
-                        .fetch_one(&*pool)
-                        .await
-                        .unwrap();
-                let robot_model = format!("{}-{}", &order_current.model, &order_current.version);
-                order.add_order(customer_name, robot_model).await.unwrap();
-            }
-            Err(_) => {
-                println!("product is out of stock");
+            self.orders.lock().await.push_back(order_current);
+            return Ok(false);
+        }
 
-                self.orders.push_back(order_current);
-            }
+        println!("Product is in stock");
+        if let Err(e) = repos
+            .robots
+            .check_stock(&order_current.model, &order_current.version)
+            .await
+        {
+            eprintln!("Stock check failed: {e}");
         }
+        // Save completed order to "orders" table
+        let customer_name = repos.customers.customer_name(&order_current.login).await?;
+        let order = Order {
+            customer_name: customer_name.unwrap_or(order_current.login),
+            robot_model: format!("{}-{}", &order_current.model, &order_current.version),
+        };
+        if let Err(e) = repos.orders.add_order(&order).await {
+            eprintln!("Failed to save the order: {e}");
+        }
+
+        Ok(true)
     }
 
-    // Method for queue processing
-    pub async fn process(&mut self) {
-        loop {
-            let pool = self.pool.lock().await;
-            let db = Database::new().await.unwrap();
+    // Notifies the customers whose robots are back in stock. Orders that fail
+    // are kept for the next pass, orders of unknown customers are dropped.
+    pub async fn process(&self) {
+        let repos = self.repos.clone();
+        // Orders placed while this pass runs wait for the next one
+        let orders = std::mem::take(&mut *self.orders.lock().await);
 
-            // Vector for storing uncompleted orders
-            let mut pending_orders = VecDeque::new();
+        // Vector for storing uncompleted orders
+        let mut pending_orders = VecDeque::new();
+
+        for order in orders {
+            let email_addr = match repos
+                .customers
+                .check_credentials(&order.login, &order.password)
+                .await
+            {
+                Ok(Some(email_addr)) => email_addr,
+                Ok(None) => {
+                    eprintln!(
+                        "Dropping the order of {} for {}-{}, unknown customer",
+                        order.login, order.model, order.version
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Failed to look up customer {}: {e}", order.login);
+                    pending_orders.push_back(order);
+                    continue;
+                }
+            };
+            let customer_name = match repos.customers.customer_name(&order.login).await {
+                Ok(Some(name)) => name,
+                Ok(None) => order.login.clone(),
+                Err(e) => {
+                    eprintln!("Failed to look up customer {}: {e}", order.login);
+                    pending_orders.push_back(order);
+                    continue;
+                }
+            };
 
-            // Iterate over the order vector using the drain method, which moves elements from the vector
-            for _ in 0..self.orders.len() {
-                let order = self.orders.pop_front().unwrap();
+            match repos.robots.in_stock(&order.model, &order.version).await {
+                Ok(0) | Err(_) => {
+                    pending_orders.push_back(order);
+                }
+                Ok(_) => {
+                    println!("Hello {customer_name} product is available");
 
-                let customer_name: String =
-                    sqlx::query_scalar("SELECT name FROM customers WHERE login = $1")
-                        .bind(&order.login)
-                        .fetch_one(&*pool)
-                        .await
-                        .unwrap();
+                    let message = format!(
+                        "Добрый день, {}!\n\
+                        Недавно вы интересовались нашим роботом модели {}, версии {}.\n\
+                        Этот робот теперь в наличии. Если вам подходит этот вариант - пожалуйста, свяжитесь с нами",
+                        customer_name, &order.model, &order.version
+                    );
 
-                let result = Database::find_robot(&db, &order.model, &order.version).await;
-                match result {
-                    Ok(0) => {
+                    let email =
+                        Email::new(vec![email_addr.clone()], ORDER_AVAILABLE_SUBJECT, &message);
+                    if let Err(e) = send_email(self.mailer.clone(), "order", email).await {
+                        eprintln!("Failed to notify {email_addr}: {e}");
                         pending_orders.push_back(order);
+                        continue;
                     }
-                    Ok(_) => {
-                        println!("Hello {customer_name} product is available");
-                        let db = Database::new().await.unwrap();
-
-                        let message = format!(
-                            "Добрый день, {}!\n\
-                            Недавно вы интересовались нашим роботом модели {}, версии {}.\n\
-                            Этот робот теперь в наличии. Если вам подходит этот вариант - пожалуйста, свяжитесь с нами",
-                            customer_name, &order.model, &order.version
-                        );
-
-                        // This part of the code always returns an error
-                        let (login, password) = (order.login, order.password);
-                        let email_addr = db
-                            .check_credentials(&login, &password)
-                            .await
-                            .unwrap()
-                            .unwrap();
-                        send_email(&email_addr, &message).expect("Failed to send email");
-
-                        println!("{}", message);
-                    }
-                    Err(_) => {
-                        pending_orders.push_back(order);
+                    match repos
+                        .orders
+                        .mark_notified(&order.login, &order.model, &order.version)
+                        .await
+                    {
+                        Ok(requested) => {
+                            let notified = Utc::now().naive_utc();
+                            for requested in requested {
+                                metrics::order_fulfilled(requested, notified);
+                            }
+                        }
+                        Err(e) => eprintln!("Failed to update the waitlist: {e}"),
                     }
+
+                    println!("{}", message);
                 }
             }
-            // Replace the vector of orders with the vector of uncompleted orders
-            self.orders = pending_orders;
-            // Release access to the database connection
-            drop(pool);
-
-            sleep(Duration::from_secs(CHECK_INTERVAL)).await;
         }
+        // Uncompleted orders go back ahead of the ones placed meanwhile
+        let mut orders = self.orders.lock().await;
+        pending_orders.append(&mut orders);
+        *orders = pending_orders;
     }
 }
 
-pub async fn order_robot(
-    Json(order): Json<CurrentOrder>,
-) -> Result<axum::http::StatusCode, axum::http::StatusCode> {
-    if order.validate().is_err() {
-        return Err(axum::http::StatusCode::BAD_REQUEST);
+// Checks the waiting orders every CHECK_INTERVAL seconds, runs under the supervisor.
+// Beats after every round, /health/ready reports a processor that stopped.
+pub async fn run_order_processor(orders: Arc<OrderQueue>, heartbeat: Heartbeat) {
+    loop {
+        sleep(Duration::from_secs(CHECK_INTERVAL)).await;
+        orders.process().await;
+        heartbeat.beat();
     }
+}
 
-    let queue = Arc::new(Mutex::new(OrderQueue::new().await));
-    // Get a lock on the queue and add the order
-    queue.lock().await.enqueue(order).await;
-    // Запускаем задачу для обработки очереди
-    let queue_clone = Arc::clone(&queue);
+// Sells a robot in stock, otherwise the customer is emailed once one is made
+#[utoipa::path(
+    post,
+    path = "/robots/order",
+    tag = "orders",
+    request_body = CurrentOrder,
+    responses(
+        (status = 200, description = "Robot sold"),
+        (status = 400, description = "Invalid model or version", body = AppError),
+        (status = 401, description = "Invalid login or password", body = AppError),
+        (status = 404, description = "Out of stock, the order is on the waitlist", body = AppError),
+    )
+)]
+pub async fn order_robot(
+    State(state): State<AppState>,
+    AppJson(order): AppJson<CurrentOrder>,
+) -> Result<axum::http::StatusCode, AppError> {
+    order.validate()?;
+    let (model, version) = (order.model.clone(), order.version.clone());
 
-    // Get a lock on the queue and call the process method
-    queue_clone.lock().await.process().await;
+    if state
+        .repos
+        .customers
+        .check_credentials(&order.login, &order.password)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::new(
+            ErrorCode::Unauthorized,
+            "Invalid login or password",
+        ));
+    }
 
-    // Checking to see if there are any orders in the queue
-    if queue.lock().await.orders.is_empty() {
-        // If queue is empty, return status code 200 (OK)
+    // Sells or queues the order, the order processor picks up the rest
+    let sold = state.orders.enqueue(order).await?;
+
+    if sold {
         Ok(axum::http::StatusCode::OK)
     } else {
-        // If there are orders in the queue, return status code 404 (Not Found)
-        Err(axum::http::StatusCode::NOT_FOUND)
+        // If the order is in the queue, return status code 404 (Not Found)
+        Err(AppError::not_found(format!(
+            "No {model}-{version} in stock, the order has been put on the waitlist"
+        )))
     }
 }
-
-fn send_email(to: &str, body: &str) -> Result<Response, lettre::transport::smtp::Error> {
-    let email = Message::builder()
-        .from(SMTP_SENDER.parse().unwrap())
-        .to(to.parse().unwrap())
-        .subject("Your order is available")
-        .body(body.to_string())
-        .unwrap();
-
-    let mailer = SmtpTransport::relay(SMTP_SERVER)
-        .unwrap()
-        .credentials(lettre::transport::smtp::authentication::Credentials::new(
-            "user".to_string(),
-            "password".to_string(),
-        ))
-        .build();
-
-    mailer.send(&email)
-}
@@ -1,108 +1,518 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, HashSet};
 
-use axum::body::StreamBody;
-use axum::http::{self, HeaderMap, HeaderValue};
-use axum::{http::StatusCode, response::IntoResponse};
-use http::header::CONTENT_TYPE;
-use rust_xlsxwriter::{Workbook, Worksheet, XlsxError};
-use tokio::fs::File;
-use tokio_util::io::ReaderStream;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use chrono::NaiveDate;
+use rust_xlsxwriter::{Chart, ChartType, Format, FormatBorder, Workbook, Worksheet, XlsxError};
+use serde::Serialize;
+use utoipa::ToSchema;
 
-use crate::constants::{PATH_TO_XLSX, SHEET_HEADERS};
-use crate::db_pool::get_pool;
+use crate::archive::archived_response;
+#[cfg(feature = "sqlite")]
+use crate::constants::QUARTER_SECONDS;
+use crate::constants::{
+    ALERT_COLOR, BELOW_TARGET_LABEL, HEADER_COLOR, ON_TARGET_LABEL, PLAN_HEADERS, RECORD_HEADERS,
+    SHEET_HEADERS, SUMMARY_HEADERS, SUMMARY_SHEET, TOTAL_LABEL,
+};
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppQuery};
+#[cfg(feature = "sqlite")]
+use crate::period::quarter_local_date;
+use crate::period::{ReportQuery, ReportRange};
+use crate::plan::{attainment, is_below_target};
+use crate::report_format::{FormatQuery, Report, ReportFormat, Table};
+use crate::repository::{Repositories, RepositoryResult};
 
-async fn create_xlsx() -> std::result::Result<(), anyhow::Error> {
-    if fs::metadata(PATH_TO_XLSX).is_ok() {
-        fs::remove_file(PATH_TO_XLSX)?;
+// Robots produced by one version of a model, `daily` has one entry per day of the range.
+// `target` is set when a production plan overlaps the range.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct VersionSummary {
+    pub version: String,
+    pub daily: Vec<i64>,
+    pub total: i64,
+    pub target: Option<i64>,
+    pub attainment: Option<f64>,
+    pub below_target: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ModelSummary {
+    pub model: String,
+    pub total: i64,
+    pub target: Option<i64>,
+    pub attainment: Option<f64>,
+    pub below_target: bool,
+    pub versions: Vec<VersionSummary>,
+}
+
+impl ModelSummary {
+    pub fn daily_totals(&self, days: usize) -> Vec<i64> {
+        (0..days)
+            .map(|day| self.versions.iter().map(|v| v.daily[day]).sum())
+            .collect()
     }
+}
 
-    let pool = get_pool().await.unwrap();
-    let robots = fetch_robots(&pool).await?;
+// Aggregated production data shared by every report format.
+// Models and versions are sorted by name.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ProductionReport {
+    pub range: ReportRange,
+    pub days: Vec<NaiveDate>,
+    pub total: i64,
+    pub models: Vec<ModelSummary>,
+}
 
-    let groups = robots
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, (model, version, count)| {
-            acc.entry(model.chars().next().unwrap())
-                .or_insert_with(Vec::new)
-                .push((model, version, count));
-            acc
-        });
-    create_excel_file(groups).unwrap();
+impl ProductionReport {
+    // Rows are (model, version, factory-local day, quantity), targets are per (model, version)
+    // for the whole range. Planned versions without any production are included with zeros.
+    pub fn from_rows(
+        range: ReportRange,
+        rows: Vec<(String, String, NaiveDate, i64)>,
+        mut targets: BTreeMap<(String, String), i64>,
+    ) -> Self {
+        let days: Vec<NaiveDate> = range
+            .from
+            .iter_days()
+            .take_while(|d| *d <= range.to)
+            .collect();
 
-    Ok(())
+        let mut groups: BTreeMap<String, BTreeMap<String, Vec<i64>>> = BTreeMap::new();
+        for (model, version, day, count) in rows {
+            let index = (day - range.from).num_days();
+            if index < 0 || index >= days.len() as i64 {
+                continue;
+            }
+            groups
+                .entry(model)
+                .or_default()
+                .entry(version)
+                .or_insert_with(|| vec![0; days.len()])[index as usize] += count;
+        }
+        for (model, version) in targets.keys() {
+            groups
+                .entry(model.clone())
+                .or_default()
+                .entry(version.clone())
+                .or_insert_with(|| vec![0; days.len()]);
+        }
+
+        let models: Vec<ModelSummary> = groups
+            .into_iter()
+            .map(|(model, versions)| {
+                let versions: Vec<VersionSummary> = versions
+                    .into_iter()
+                    .map(|(version, daily)| {
+                        let total = daily.iter().sum();
+                        let target = targets.remove(&(model.clone(), version.clone()));
+                        let attainment = target.and_then(|t| attainment(total, t));
+                        VersionSummary {
+                            version,
+                            total,
+                            target,
+                            attainment,
+                            below_target: is_below_target(attainment),
+                            daily,
+                        }
+                    })
+                    .collect();
+                let total = versions.iter().map(|v| v.total).sum();
+                let target = versions
+                    .iter()
+                    .filter_map(|v| v.target)
+                    .reduce(|a, b| a + b);
+                let attainment = target.and_then(|t| attainment(total, t));
+                ModelSummary {
+                    model,
+                    total,
+                    target,
+                    attainment,
+                    below_target: is_below_target(attainment),
+                    versions,
+                }
+            })
+            .collect();
+
+        Self {
+            range,
+            days,
+            total: models.iter().map(|m| m.total).sum(),
+            models,
+        }
+    }
 }
 
-async fn fetch_robots(pool: &sqlx::PgPool) -> sqlx::Result<Vec<(String, String, i64)>> {
-    let robots: Vec<(String, String, i64)> = sqlx::query_as(
-        "SELECT model, version, COUNT(*) as count FROM robots
-        WHERE created >= current_date - interval '7 day' GROUP BY model, version",
+impl Report for ProductionReport {
+    fn kind(&self) -> &'static str {
+        "production"
+    }
+
+    fn range(&self) -> &ReportRange {
+        &self.range
+    }
+
+    fn title(&self) -> String {
+        format!(
+            "Robot production {} - {} ({})",
+            self.range.from,
+            self.range.to,
+            self.range.tz.name()
+        )
+    }
+
+    fn file_stem(&self) -> String {
+        format!("robots_report_{}_{}", self.range.from, self.range.to)
+    }
+
+    // One row per version and day
+    fn records(&self) -> Table {
+        let mut rows = Vec::new();
+        for model in &self.models {
+            for version in &model.versions {
+                for (day, count) in self.days.iter().zip(&version.daily) {
+                    rows.push(vec![
+                        model.model.clone(),
+                        version.version.clone(),
+                        day.to_string(),
+                        count.to_string(),
+                    ]);
+                }
+            }
+        }
+
+        Table {
+            title: self.title(),
+            headers: RECORD_HEADERS.iter().map(|h| h.to_string()).collect(),
+            rows,
+        }
+    }
+
+    // Daily columns do not fit on paper, the PDF only has the per-version totals
+    fn tables(&self) -> Vec<Table> {
+        let mut summary = Table {
+            title: SUMMARY_SHEET.to_string(),
+            headers: SUMMARY_HEADERS.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        };
+        for model in &self.models {
+            summary.rows.push(vec![
+                model.model.clone(),
+                model.versions.len().to_string(),
+                model.total.to_string(),
+            ]);
+        }
+        let versions: usize = self.models.iter().map(|m| m.versions.len()).sum();
+        summary.rows.push(vec![
+            TOTAL_LABEL.to_string(),
+            versions.to_string(),
+            self.total.to_string(),
+        ]);
+
+        let mut tables = vec![summary];
+        for model in &self.models {
+            let mut rows: Vec<Vec<String>> = model
+                .versions
+                .iter()
+                .map(|v| {
+                    let mut row = vec![model.model.clone(), v.version.clone(), v.total.to_string()];
+                    row.extend(plan_cells(v.target, v.attainment));
+                    row
+                })
+                .collect();
+            let mut total = vec![
+                TOTAL_LABEL.to_string(),
+                String::new(),
+                model.total.to_string(),
+            ];
+            total.extend(plan_cells(model.target, model.attainment));
+            rows.push(total);
+            tables.push(Table {
+                title: model.model.clone(),
+                headers: SHEET_HEADERS
+                    .iter()
+                    .chain(&PLAN_HEADERS)
+                    .map(|h| h.to_string())
+                    .collect(),
+                rows,
+            });
+        }
+
+        tables
+    }
+
+    fn workbook(&self) -> Result<Vec<u8>, XlsxError> {
+        create_excel_file(self)
+    }
+}
+
+// Target, attainment and status columns of a version or model, empty without a plan
+fn plan_cells(target: Option<i64>, attainment: Option<f64>) -> Vec<String> {
+    let Some(target) = target else {
+        return vec![String::new(); PLAN_HEADERS.len()];
+    };
+    vec![
+        target.to_string(),
+        attainment.map_or(String::new(), |a| format!("{:.0}%", a * 100.0)),
+        status_label(attainment).to_string(),
+    ]
+}
+
+fn status_label(attainment: Option<f64>) -> &'static str {
+    if is_below_target(attainment) {
+        BELOW_TARGET_LABEL
+    } else {
+        ON_TARGET_LABEL
+    }
+}
+
+pub async fn build_report(
+    repos: &Repositories,
+    range: ReportRange,
+) -> RepositoryResult<ProductionReport> {
+    let rows = repos.reports.production(&range).await?;
+    let targets = repos.plans.fetch_targets(range.from, range.to).await?;
+
+    Ok(ProductionReport::from_rows(range, rows, targets))
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub async fn fetch_robots(
+    pool: &DbPool,
+    range: &ReportRange,
+) -> sqlx::Result<Vec<(String, String, NaiveDate, i64)>> {
+    // `created` holds UTC time, days are counted in the factory time zone
+    sqlx::query_as(
+        "SELECT model, version, (created AT TIME ZONE 'UTC' AT TIME ZONE $3)::date AS day, COUNT(*) as count FROM robots WHERE created >= $1 AND created < $2 GROUP BY model, version, day",
+    )
+    .bind(range.start)
+    .bind(range.end)
+    .bind(range.tz.name())
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(feature = "sqlite")]
+pub async fn fetch_robots(
+    pool: &DbPool,
+    range: &ReportRange,
+) -> sqlx::Result<Vec<(String, String, NaiveDate, i64)>> {
+    let quarters: Vec<(String, String, i64, i64)> = sqlx::query_as(
+        "SELECT model, version, CAST(strftime('%s', created) AS INTEGER) / $3 AS quarter, COUNT(*) as count FROM robots WHERE created >= $1 AND created < $2 GROUP BY model, version, quarter",
     )
+    .bind(range.start)
+    .bind(range.end)
+    .bind(QUARTER_SECONDS)
     .fetch_all(pool)
     .await?;
 
-    Ok(robots)
+    let mut counts: BTreeMap<(String, String, NaiveDate), i64> = BTreeMap::new();
+    for (model, version, quarter, count) in quarters {
+        if let Some(day) = quarter_local_date(range.tz, quarter) {
+            *counts.entry((model, version, day)).or_default() += count;
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .map(|((model, version, day), count)| (model, version, day, count))
+        .collect())
 }
 
-fn create_excel_file(
-    groups: HashMap<char, Vec<(String, String, i64)>>,
-) -> std::result::Result<(), Box<dyn std::error::Error>> {
+// Every request builds its own workbook in memory, so concurrent downloads never share a file
+fn create_excel_file(report: &ProductionReport) -> Result<Vec<u8>, XlsxError> {
     let mut workbook = Workbook::new();
+    let header_format = header_format();
+    let total_format = total_format();
 
-    for (key, value) in &groups {
-        let sheet_name = format!("{}", key);
-        let sheet = workbook.add_worksheet().set_name(sheet_name)?;
-        write_headers(sheet)?;
-        write_data(sheet, value)?;
+    let summary = workbook.add_worksheet().set_name(SUMMARY_SHEET)?;
+    write_headers(summary, &SUMMARY_HEADERS, &header_format)?;
+    write_summary(summary, report, &total_format)?;
+    summary.set_freeze_panes(1, 0)?;
+    summary.autofit();
+
+    // Excel compares sheet names case-insensitively, but models "r2" and "R2" are different
+    let mut used_names = HashSet::from([SUMMARY_SHEET.to_lowercase()]);
+    for model in &report.models {
+        let mut sheet_name = model.model.clone();
+        let mut suffix = 2;
+        while !used_names.insert(sheet_name.to_lowercase()) {
+            sheet_name = format!("{} ({suffix})", model.model);
+            suffix += 1;
+        }
+
+        let sheet = workbook.add_worksheet().set_name(&sheet_name)?;
+        let mut headers: Vec<String> = SHEET_HEADERS
+            .iter()
+            .chain(&PLAN_HEADERS)
+            .map(|h| h.to_string())
+            .collect();
+        headers.extend(report.days.iter().map(|day| day.to_string()));
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+
+        write_headers(sheet, &headers, &header_format)?;
+        write_data(sheet, model, report.days.len(), &total_format)?;
+        // Keep the header row and the model/version columns visible while scrolling
+        sheet.set_freeze_panes(1, 2)?;
+        sheet.autofit();
+
+        let chart = model_chart(&sheet_name, model);
+        sheet.insert_chart(model.versions.len() as u32 + 3, 0, &chart)?;
     }
 
-    workbook.save(PATH_TO_XLSX)?;
+    workbook.save_to_buffer()
+}
+
+pub fn header_format() -> Format {
+    Format::new()
+        .set_bold()
+        .set_background_color(HEADER_COLOR)
+        .set_border(FormatBorder::Thin)
+}
+
+pub fn total_format() -> Format {
+    Format::new().set_bold().set_border_top(FormatBorder::Thin)
+}
+
+pub fn write_headers(
+    sheet: &mut Worksheet,
+    headers: &[&str],
+    format: &Format,
+) -> Result<(), XlsxError> {
+    for (i, header) in headers.iter().enumerate() {
+        sheet.write_string_with_format(0, i as u16, header.to_string(), format)?;
+    }
     Ok(())
 }
 
-fn write_headers(sheet: &mut Worksheet) -> Result<(), XlsxError> {
-    for (i, header) in SHEET_HEADERS.iter().enumerate() {
-        sheet.write_string(0, i as u16, header.to_string())?;
+fn write_summary(
+    sheet: &mut Worksheet,
+    report: &ProductionReport,
+    total_format: &Format,
+) -> Result<(), XlsxError> {
+    for (i, model) in report.models.iter().enumerate() {
+        sheet.write_string((i + 1) as u32, 0, &model.model)?;
+        sheet.write_number((i + 1) as u32, 1, model.versions.len() as f64)?;
+        sheet.write_number((i + 1) as u32, 2, model.total as f64)?;
     }
+
+    let row = (report.models.len() + 1) as u32;
+    let versions: usize = report.models.iter().map(|m| m.versions.len()).sum();
+    sheet.write_string_with_format(row, 0, TOTAL_LABEL, total_format)?;
+    sheet.write_number_with_format(row, 1, versions as f64, total_format)?;
+    sheet.write_number_with_format(row, 2, report.total as f64, total_format)?;
     Ok(())
 }
 
 fn write_data(
     sheet: &mut Worksheet,
-    data: &[(String, String, i64)],
-) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    for (i, (model, version, count)) in data.iter().enumerate() {
-        sheet.write_string((i + 1) as u32, 0, model)?;
-        sheet.write_string((i + 1) as u32, 1, version)?;
-        sheet.write_number((i + 1) as u32, 2, *count as f64)?;
+    model: &ModelSummary,
+    days: usize,
+    total_format: &Format,
+) -> Result<(), XlsxError> {
+    let first_day = (SHEET_HEADERS.len() + PLAN_HEADERS.len()) as u16;
+    let plain = Format::new();
+
+    for (i, version) in model.versions.iter().enumerate() {
+        let row = (i + 1) as u32;
+        sheet.write_string(row, 0, &model.model)?;
+        sheet.write_string(row, 1, &version.version)?;
+        sheet.write_number(row, 2, version.total as f64)?;
+        write_plan(sheet, row, version.target, version.attainment, &plain)?;
+        for (day, count) in version.daily.iter().enumerate() {
+            sheet.write_number(row, first_day + day as u16, *count as f64)?;
+        }
+    }
+
+    let row = (model.versions.len() + 1) as u32;
+    sheet.write_string_with_format(row, 0, TOTAL_LABEL, total_format)?;
+    sheet.write_string_with_format(row, 1, "", total_format)?;
+    sheet.write_number_with_format(row, 2, model.total as f64, total_format)?;
+    write_plan(sheet, row, model.target, model.attainment, total_format)?;
+    for (day, count) in model.daily_totals(days).iter().enumerate() {
+        sheet.write_number_with_format(row, first_day + day as u16, *count as f64, total_format)?;
     }
     Ok(())
 }
 
-pub async fn report_handler() -> std::result::Result<impl IntoResponse, (StatusCode, String)> {
-    match create_xlsx().await {
-        Ok(()) => {
-            let file = File::open(PATH_TO_XLSX).await.map_err(|err| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("File not found: {}", err),
-                )
-            })?;
-            let stream = ReaderStream::new(file);
-            let body = StreamBody::new(stream);
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                CONTENT_TYPE,
-                HeaderValue::from_static(
-                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
-                ),
-            );
-
-            Ok((headers, body))
+// Target, attainment and status in the PLAN_HEADERS columns after SHEET_HEADERS,
+// highlighted when below the threshold
+fn write_plan(
+    sheet: &mut Worksheet,
+    row: u32,
+    target: Option<i64>,
+    attainment: Option<f64>,
+    format: &Format,
+) -> Result<(), XlsxError> {
+    let first = SHEET_HEADERS.len() as u16;
+    let Some(target) = target else {
+        for col in first..first + PLAN_HEADERS.len() as u16 {
+            sheet.write_string_with_format(row, col, "", format)?;
         }
-        Err(err) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to create Excel file: {}", err),
-        )),
+        return Ok(());
+    };
+
+    let mut flag = format.clone();
+    if is_below_target(attainment) {
+        flag = flag.set_background_color(ALERT_COLOR);
     }
+    sheet.write_number_with_format(row, first, target as f64, format)?;
+    match attainment {
+        Some(a) => {
+            sheet.write_number_with_format(row, first + 1, a, &flag.clone().set_num_format("0%"))?
+        }
+        None => sheet.write_string_with_format(row, first + 1, "", &flag)?,
+    };
+    sheet.write_string_with_format(row, first + 2, status_label(attainment), &flag)?;
+    Ok(())
+}
+
+// Bar chart with the quantity of each version of the model
+fn model_chart(sheet_name: &str, model: &ModelSummary) -> Chart {
+    let last_row = model.versions.len() as u32;
+    let mut chart = Chart::new(ChartType::Bar);
+    chart
+        .add_series()
+        .set_name(model.model.as_str())
+        .set_categories((sheet_name, 1, 1, last_row, 1))
+        .set_values((sheet_name, 1, 2, last_row, 2));
+    chart
+        .title()
+        .set_name(&format!("{} production by version", model.model));
+    chart.legend().set_hidden();
+
+    chart
+}
+
+#[utoipa::path(
+    get,
+    path = "/robots/report",
+    tag = "reports",
+    params(ReportQuery, FormatQuery),
+    responses(
+        (status = 200, description = "The report, in the format of `format` or the Accept header", content(
+            ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" = [u8]),
+            ("text/csv" = String),
+            ("application/json" = ProductionReport),
+            ("application/pdf" = [u8]),
+        )),
+        (status = 400, description = "Invalid period or date range", body = AppError),
+        (status = 406, description = "None of the accepted formats is available", body = AppError),
+    )
+)]
+pub async fn report_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<ReportQuery>,
+    AppQuery(format): AppQuery<FormatQuery>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let format = ReportFormat::negotiate(&format, &headers)?;
+    let range = query
+        .resolve_now()
+        .map_err(|err| AppError::bad_request(err.to_string()))?;
+
+    // Database errors keep their status, e.g. 503 while the database is unreachable
+    let report = build_report(&repos, range).await?;
+
+    archived_response(repos.archive.as_ref(), &report, format).await
 }
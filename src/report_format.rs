@@ -0,0 +1,227 @@
+use axum::http::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue};
+use printpdf::{BuiltinFont, Mm, PdfDocument};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::constants::{PDF_FONT_SIZE, PDF_LINES_PER_PAGE, PDF_LINE_HEIGHT, PDF_MARGIN};
+use crate::error::{AppError, ErrorCode};
+use crate::period::ReportRange;
+
+const XLSX_MIME: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+// Plain table used by the CSV and PDF renderers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub title: String,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+// Anything that can be rendered in every ReportFormat.
+// The data is aggregated once and each format only decides how to lay it out.
+pub trait Report: Serialize {
+    // Short name stored in the report archive, e.g. "production"
+    fn kind(&self) -> &'static str;
+    fn range(&self) -> &ReportRange;
+    // Document title printed at the top of the PDF
+    fn title(&self) -> String;
+    // File name without extension, e.g. robots_report_2023-10-11_2023-10-17
+    fn file_stem(&self) -> String;
+    // One flat table with a row per record, suitable for BI tools
+    fn records(&self) -> Table;
+    // Printable tables, one after another
+    fn tables(&self) -> Vec<Table>;
+    fn workbook(&self) -> Result<Vec<u8>, rust_xlsxwriter::XlsxError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Xlsx,
+    Csv,
+    Json,
+    Pdf,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FormatQuery {
+    pub format: Option<ReportFormat>,
+}
+
+impl ReportFormat {
+    const ALL: [ReportFormat; 4] = [
+        ReportFormat::Xlsx,
+        ReportFormat::Csv,
+        ReportFormat::Json,
+        ReportFormat::Pdf,
+    ];
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ReportFormat::Xlsx => XLSX_MIME,
+            ReportFormat::Csv => "text/csv; charset=utf-8",
+            ReportFormat::Json => "application/json",
+            ReportFormat::Pdf => "application/pdf",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Xlsx => "xlsx",
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+            ReportFormat::Pdf => "pdf",
+        }
+    }
+
+    fn media_type(self) -> &'static str {
+        self.content_type().split(';').next().unwrap()
+    }
+
+    // `?format=` wins over the Accept header, XLSX is the default
+    pub fn negotiate(query: &FormatQuery, headers: &HeaderMap) -> Result<Self, AppError> {
+        if let Some(format) = query.format {
+            return Ok(format);
+        }
+
+        let Some(accept) = headers.get(ACCEPT).and_then(|value| value.to_str().ok()) else {
+            return Ok(ReportFormat::Xlsx);
+        };
+
+        // Media ranges ordered by their quality value, ranges with q=0 are refused
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (media, _) in ranges {
+            if media == "*/*" || media == "application/*" {
+                return Ok(ReportFormat::Xlsx);
+            }
+            if media == "text/*" {
+                return Ok(ReportFormat::Csv);
+            }
+            if let Some(format) = Self::ALL.into_iter().find(|f| f.media_type() == media) {
+                return Ok(format);
+            }
+        }
+
+        Err(AppError::new(
+            ErrorCode::NotAcceptable,
+            format!(
+                "Supported report formats are: {}",
+                Self::ALL.map(|f| f.media_type()).join(", ")
+            ),
+        ))
+    }
+
+    pub fn render<R: Report>(self, report: &R) -> anyhow::Result<Vec<u8>> {
+        match self {
+            ReportFormat::Xlsx => Ok(report.workbook()?),
+            ReportFormat::Csv => render_csv(&report.records()),
+            ReportFormat::Json => Ok(serde_json::to_vec_pretty(report)?),
+            ReportFormat::Pdf => render_pdf(&report.title(), &report.tables()),
+        }
+    }
+
+    // Content-Type and Content-Disposition for a rendered report
+    pub fn headers<R: Report>(self, report: &R) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(self.content_type()));
+
+        let disposition = format!(
+            "attachment; filename=\"{}.{}\"",
+            report.file_stem(),
+            self.extension()
+        );
+        // File stems only contain ASCII letters, digits, dashes and underscores
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_str(&disposition).unwrap(),
+        );
+
+        headers
+    }
+}
+
+fn render_csv(table: &Table) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&table.headers)?;
+    for row in &table.rows {
+        writer.write_record(row)?;
+    }
+
+    Ok(writer.into_inner()?)
+}
+
+// A4 portrait pages with monospaced text, so the columns line up without measuring glyphs
+fn render_pdf(title: &str, tables: &[Table]) -> anyhow::Result<Vec<u8>> {
+    let mut lines = vec![title.to_string(), String::new()];
+    for table in tables {
+        lines.extend(text_table(table));
+        lines.push(String::new());
+    }
+
+    let (doc, page, layer) = PdfDocument::new(title, Mm(210.0), Mm(297.0), "Report");
+    let font = doc.add_builtin_font(BuiltinFont::Courier)?;
+    let bold = doc.add_builtin_font(BuiltinFont::CourierBold)?;
+
+    let mut layer = doc.get_page(page).get_layer(layer);
+    for (i, chunk) in lines.chunks(PDF_LINES_PER_PAGE).enumerate() {
+        if i > 0 {
+            let (page, new_layer) = doc.add_page(Mm(210.0), Mm(297.0), "Report");
+            layer = doc.get_page(page).get_layer(new_layer);
+        }
+        for (row, line) in chunk.iter().enumerate() {
+            let y = 297.0 - PDF_MARGIN - row as f64 * PDF_LINE_HEIGHT;
+            let font = if i == 0 && row == 0 { &bold } else { &font };
+            layer.use_text(line.as_str(), PDF_FONT_SIZE, Mm(PDF_MARGIN), Mm(y), font);
+        }
+    }
+
+    Ok(doc.save_to_bytes()?)
+}
+
+fn text_table(table: &Table) -> Vec<String> {
+    let mut widths: Vec<usize> = table.headers.iter().map(String::len).collect();
+    for row in &table.rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+
+    let format_row = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut lines = vec![table.title.clone(), format_row(&table.headers)];
+    lines.push(
+        widths
+            .iter()
+            .map(|width| "-".repeat(*width))
+            .collect::<Vec<_>>()
+            .join("  "),
+    );
+    lines.extend(table.rows.iter().map(|row| format_row(row)));
+
+    lines
+}
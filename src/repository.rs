@@ -0,0 +1,316 @@
+use std::fmt;
+use std::sync::Arc;
+
+use std::collections::BTreeMap;
+
+use axum::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+
+use crate::archive::{ArchivedReport, ReportEntry};
+use crate::batch::{BatchMode, BatchRow, RowError};
+use crate::db::Database;
+use crate::db_pool::DbPool;
+use crate::error::AppError;
+use crate::forecast::Demand;
+use crate::inventory::{RobotDetails, RobotRecord, RobotSort, SortOrder};
+use crate::memory::MemoryDatabase;
+use crate::notification::Mailer;
+use crate::order::Order;
+use crate::period::ReportRange;
+use crate::plan::{PlanInput, PlanQuery, ProductionPlan};
+use crate::robot::Robot;
+use crate::sales::SalesFigures;
+use crate::stock::StockThreshold;
+use crate::user::Customer;
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    // A unique value such as a login is already taken
+    Conflict(String),
+    // The request cannot be answered, e.g. a cursor that does not match the sorting
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+pub type RepositoryResult<T> = Result<T, RepositoryError>;
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Conflict(message) | RepositoryError::Invalid(message) => {
+                write!(f, "{message}")
+            }
+            RepositoryError::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(error: sqlx::Error) -> Self {
+        RepositoryError::Database(error)
+    }
+}
+
+impl From<RepositoryError> for AppError {
+    fn from(error: RepositoryError) -> Self {
+        match error {
+            RepositoryError::Conflict(message) => AppError::conflict(message),
+            RepositoryError::Invalid(message) => AppError::bad_request(message),
+            RepositoryError::Database(e) => e.into(),
+        }
+    }
+}
+
+// Outcome of RobotRepository::decommission_robot. A robot in stock is reserved
+// while customers wait for its model and version: the oldest robots are kept
+// for them, one per open waitlist entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decommission {
+    Done,
+    NotInService,
+    Sold,
+    Reserved,
+}
+
+// Filters of a robot listing, resolved from RobotQuery. `start` and `end` are UTC,
+// `cursor` is the sort key and id of the last robot of the previous page.
+#[derive(Debug, Clone, Default)]
+pub struct RobotFilter {
+    pub model: Option<String>,
+    pub version: Option<String>,
+    pub serial_prefix: Option<String>,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+    pub sold: Option<bool>,
+    pub decommissioned: bool,
+    pub sort: RobotSort,
+    pub order: SortOrder,
+    pub cursor: Option<(String, i32)>,
+    pub limit: i64,
+}
+
+#[async_trait]
+pub trait RobotRepository: Send + Sync {
+    // Robots ever made of the model, generated serials continue from it
+    async fn count_model(&self, model: &str) -> RepositoryResult<i64>;
+
+    async fn insert_robot(
+        &self,
+        serial: &str,
+        model: &str,
+        version: &str,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<()>;
+
+    // Checks the rows with batch::prepare_rows against the robots stored and inserts the
+    // valid ones in one step, none of them in atomic mode if a row is invalid.
+    // Returns the robots inserted and the rows rejected.
+    async fn insert_robots(
+        &self,
+        rows: Vec<BatchRow>,
+        mode: BatchMode,
+        created: NaiveDateTime,
+    ) -> RepositoryResult<(Vec<Robot>, Vec<RowError>)>;
+
+    // At most `filter.limit` robots, sorted by `filter.sort` and then id
+    async fn list_robots(&self, filter: &RobotFilter) -> RepositoryResult<Vec<RobotRecord>>;
+
+    // Serial numbers entered by hand are not guaranteed to be unique,
+    // a robot in service wins over decommissioned ones, then the newest one
+    async fn fetch_robot(&self, serial: &str) -> RepositoryResult<Option<RobotDetails>>;
+
+    // Sold and reserved robots are only decommissioned with `force`. The checks and
+    // the update are one step, a robot sold meanwhile is not removed.
+    async fn decommission_robot(
+        &self,
+        id: i32,
+        at: NaiveDateTime,
+        reason: &str,
+        force: bool,
+    ) -> RepositoryResult<Decommission>;
+
+    async fn restore_robot(&self, id: i32) -> RepositoryResult<bool>;
+
+    // Robots of the model and version that are in stock, i.e. neither sold nor decommissioned
+    async fn in_stock(&self, model: &str, version: &str) -> RepositoryResult<i64>;
+
+    // Compares the stock with its low stock threshold, whenever robots are created,
+    // removed or sold. Backends without thresholds do nothing.
+    async fn check_stock(&self, model: &str, version: &str) -> RepositoryResult<()>;
+}
+
+#[async_trait]
+pub trait CustomerRepository: Send + Sync {
+    // Conflict if the email or login is taken
+    async fn insert_customer(&self, customer: &Customer) -> RepositoryResult<()>;
+
+    // Email of the customer if the login and password match
+    async fn check_credentials(
+        &self,
+        login: &str,
+        password: &str,
+    ) -> RepositoryResult<Option<String>>;
+
+    async fn customer_name(&self, login: &str) -> RepositoryResult<Option<String>>;
+}
+
+#[async_trait]
+pub trait OrderRepository: Send + Sync {
+    // Sells the oldest robot in stock to the customer, returns the number of robots sold
+    async fn sell_robot(&self, login: &str, model: &str, version: &str) -> RepositoryResult<u64>;
+
+    async fn add_order(&self, order: &Order) -> RepositoryResult<()>;
+
+    async fn add_to_waitlist(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<()>;
+
+    // Marks the customer's open waitlist entries for the model and version as notified,
+    // returns when each of them was requested
+    async fn mark_notified(
+        &self,
+        login: &str,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Vec<NaiveDateTime>>;
+
+    // Open waitlist entries per model
+    async fn waitlist_depth(&self) -> RepositoryResult<Vec<(String, i64)>>;
+}
+
+#[async_trait]
+pub trait StockRepository: Send + Sync {
+    async fn list_thresholds(&self) -> RepositoryResult<Vec<StockThreshold>>;
+
+    async fn fetch_threshold(
+        &self,
+        model: &str,
+        version: &str,
+    ) -> RepositoryResult<Option<StockThreshold>>;
+
+    // Creates or replaces the threshold, an alert already sent stays in force
+    async fn set_threshold(
+        &self,
+        model: &str,
+        version: &str,
+        minimum: i32,
+        recovery: i32,
+    ) -> RepositoryResult<()>;
+
+    async fn delete_threshold(&self, model: &str, version: &str) -> RepositoryResult<bool>;
+
+    // Switches the alert on or off, false if it already was, so one drop sends one alert
+    async fn set_alerting(
+        &self,
+        model: &str,
+        version: &str,
+        alerting: bool,
+        at: NaiveDateTime,
+    ) -> RepositoryResult<bool>;
+}
+
+#[async_trait]
+pub trait PlanRepository: Send + Sync {
+    // Plans with the robots made during their period, whose days start at midnight in `tz`
+    async fn list_plans(&self, tz: Tz, query: &PlanQuery) -> RepositoryResult<Vec<ProductionPlan>>;
+
+    async fn fetch_plan(&self, tz: Tz, id: i32) -> RepositoryResult<Option<ProductionPlan>>;
+
+    // Plans of the same model/version must not overlap, None if the period is taken
+    async fn insert_plan(&self, plan: &PlanInput) -> RepositoryResult<Option<i32>>;
+
+    // False if the plan does not exist or the period is taken
+    async fn update_plan(&self, id: i32, plan: &PlanInput) -> RepositoryResult<bool>;
+
+    async fn delete_plan(&self, id: i32) -> RepositoryResult<bool>;
+
+    // Targets of every model/version with a plan overlapping [from, to], prorated to the range
+    async fn fetch_targets(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> RepositoryResult<BTreeMap<(String, String), i64>>;
+}
+
+#[async_trait]
+pub trait ReportRepository: Send + Sync {
+    // Robots created per model, version and day of the range in its time zone
+    async fn production(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<Vec<(String, String, NaiveDate, i64)>>;
+
+    // Sales and waitlist figures per model and version, the rates are left to the caller
+    async fn sales(
+        &self,
+        range: &ReportRange,
+    ) -> RepositoryResult<BTreeMap<(String, String), SalesFigures>>;
+
+    // Demand of one kind per model, version and week (its Monday in `tz`) in [start, end)
+    async fn weekly_demand(
+        &self,
+        demand: Demand,
+        tz: Tz,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> RepositoryResult<BTreeMap<(String, String, NaiveDate), i64>>;
+}
+
+#[async_trait]
+pub trait ArchiveRepository: Send + Sync {
+    // Stores a rendered report and returns its id
+    async fn archive_report(&self, entry: &ReportEntry<'_>) -> RepositoryResult<i32>;
+
+    // Newest first, only reports of `kind` if given
+    async fn list_reports(&self, kind: Option<&str>) -> RepositoryResult<Vec<ArchivedReport>>;
+
+    // Returns (filename, content type, content)
+    async fn fetch_report(&self, id: i32) -> RepositoryResult<Option<(String, String, Vec<u8>)>>;
+}
+
+// The storage used by the handlers, every repository of one backend shares its data
+#[derive(Clone)]
+pub struct Repositories {
+    pub robots: Arc<dyn RobotRepository>,
+    pub customers: Arc<dyn CustomerRepository>,
+    pub orders: Arc<dyn OrderRepository>,
+    pub stock: Arc<dyn StockRepository>,
+    pub plans: Arc<dyn PlanRepository>,
+    pub reports: Arc<dyn ReportRepository>,
+    pub archive: Arc<dyn ArchiveRepository>,
+}
+
+impl Repositories {
+    // Low stock alerts are sent with `mailer`
+    pub fn database(pool: DbPool, mailer: Arc<dyn Mailer>) -> Self {
+        let db = Arc::new(Database::with_mailer(pool, mailer));
+        Self {
+            robots: db.clone(),
+            customers: db.clone(),
+            orders: db.clone(),
+            stock: db.clone(),
+            plans: db.clone(),
+            reports: db.clone(),
+            archive: db,
+        }
+    }
+
+    pub fn in_memory(mailer: Arc<dyn Mailer>) -> Self {
+        let db = Arc::new(MemoryDatabase::with_mailer(mailer));
+        Self {
+            robots: db.clone(),
+            customers: db.clone(),
+            orders: db.clone(),
+            stock: db.clone(),
+            plans: db.clone(),
+            reports: db.clone(),
+            archive: db,
+        }
+    }
+}
@@ -1,109 +1,150 @@
+use axum::extract::State;
 use axum::http::StatusCode;
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use validator::Validate;
 use validator_derive::Validate;
 
-use crate::db::{validate_model_version, Database};
-use crate::db_pool::get_pool;
+use crate::db::validate_model_version;
+use crate::error::{AppError, AppJson};
+use crate::inventory::{decommission_robot, RemovalQuery};
+use crate::metrics;
+use crate::repository::{Repositories, RepositoryResult, RobotRepository};
 
-#[derive(Debug, Deserialize, Serialize, Validate)]
+// Generated serial numbers are the model followed by the robot's number within the model
+pub fn format_serial(model: &str, number: i64) -> String {
+    format!("{}{:03}", model, number)
+}
+
+// A serial of "0" is replaced by a generated one
+#[derive(Debug, Deserialize, Serialize, Validate, ToSchema)]
 pub struct Robot {
     #[validate(length(min = 1, max = 5))]
+    #[schema(min_length = 1, max_length = 5, example = "0")]
     pub serial: String,
     #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "M1")]
     pub model: String,
     #[validate(custom = "validate_model_version")]
+    #[schema(pattern = "^[A-Za-z][0-9]$", example = "V1")]
     pub version: String,
 }
 
-impl Robot {
-    pub async fn generate_serial_number(model: &str) -> Result<String, sqlx::Error> {
-        let pool = get_pool().await?;
+// Body of /robots/remove, the reason is stored with the decommissioned robot
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RemovalRequest {
+    #[serde(flatten)]
+    pub robot: Robot,
+    pub reason: Option<String>,
+}
 
+impl Robot {
+    pub async fn generate_serial_number(
+        robots: &dyn RobotRepository,
+        model: &str,
+    ) -> RepositoryResult<String> {
         println!("generate serial {model:?}");
-        let sql = "SELECT COUNT(*) as count FROM robots WHERE model = $1";
-        let max_serial: Option<i64> = sqlx::query_scalar(sql)
-            .bind(model)
-            .fetch_one(&*pool)
-            .await?;
-        let new_serial = format!("{}{:03}", model, max_serial.unwrap_or(0) + 1);
+        let count = robots.count_model(model).await?;
+        let new_serial = format_serial(model, count + 1);
 
         Ok(new_serial)
     }
 
-    pub fn validate_robot(&self) -> Result<(), StatusCode> {
-        if self.validate().is_err() {
-            Err(StatusCode::BAD_REQUEST)
-        } else {
-            Ok(())
-        }
+    pub fn validate_robot(&self) -> Result<(), AppError> {
+        Ok(self.validate()?)
     }
 
-    pub async fn create_robot(&self) -> Result<StatusCode, StatusCode> {
+    pub async fn create_robot(&self, robots: &dyn RobotRepository) -> Result<StatusCode, AppError> {
+        self.store_robot(robots).await?;
+        Ok(StatusCode::CREATED)
+    }
+
+    // Returns the serial number the robot was stored with and its creation time
+    pub async fn store_robot(
+        &self,
+        robots: &dyn RobotRepository,
+    ) -> Result<(String, NaiveDateTime), AppError> {
         self.validate_robot()?;
         println!("create_robot");
-        let db = Database::new().await?;
-        let pool = db.pool;
 
         let serial_number = if self.serial == "0" {
-            Self::generate_serial_number(&self.model).await.unwrap()
+            Self::generate_serial_number(robots, &self.model).await?
         } else {
             self.serial.clone()
         };
         println!("Serial number: {serial_number}");
 
-        let current_date = Utc::now().to_rfc3339();
-        let statement = format!(
-            r#"INSERT INTO robots (serial, model, version, created) VALUES ($1, $2, $3, '{}')"#,
-            current_date
-        );
-        // Execute the request and return the status
-        match sqlx::query(&statement)
-            .bind(&serial_number)
-            .bind(&self.model)
-            .bind(&self.version)
-            .execute(&pool)
+        let created = Utc::now().naive_utc();
+        if let Err(e) = robots
+            .insert_robot(&serial_number, &self.model, &self.version, created)
             .await
         {
-            Ok(_) => {
-                pool.close().await;
-                Ok(StatusCode::CREATED)
-            }
-            Err(e) => {
-                eprintln!("An error occurred while inserting data into the database: {e}");
-                panic!("Database error: {e}");
-            }
+            eprintln!("An error occurred while inserting data into the database: {e}");
+            return Err(e.into());
+        }
+        metrics::robot_created(&self.model);
+        if let Err(e) = robots.check_stock(&self.model, &self.version).await {
+            eprintln!("Stock check failed: {e}");
         }
+        Ok((serial_number, created))
     }
 
-    pub async fn remove_robot(&self) -> Result<StatusCode, StatusCode> {
+    // Marks the robot as decommissioned, it leaves the stock but its history is kept.
+    // Sold and reserved robots can only be removed with DELETE /robots/{serial}.
+    pub async fn remove_robot(
+        &self,
+        robots: &dyn RobotRepository,
+        reason: Option<&str>,
+    ) -> Result<StatusCode, AppError> {
         self.validate_robot()?;
 
-        let pool = get_pool().await.unwrap();
-
-        let statement = ("DELETE FROM robots WHERE serial = $1").to_string();
+        let query = RemovalQuery {
+            model: Some(self.model.clone()),
+            version: Some(self.version.clone()),
+            reason: reason.map(str::to_string),
+            force: None,
+        };
 
-        match sqlx::query(&statement)
-            .bind(&self.serial)
-            .bind(&self.model)
-            .bind(&self.version)
-            .execute(&*pool)
-            .await
-        {
-            Ok(result) => {
-                if result.rows_affected() > 0 {
-                    println!("Robot has been removed");
-                    Ok(StatusCode::OK)
-                } else {
-                    println!("Robot not found");
-                    Err(StatusCode::NOT_FOUND)
-                }
-            }
-            Err(_) => {
-                println!("An error occurred while attempting to remove the robot");
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-        }
+        decommission_robot(robots, &self.serial, &query).await?;
+        Ok(StatusCode::OK)
     }
 }
+
+#[utoipa::path(
+    post,
+    path = "/robots/create",
+    tag = "robots",
+    request_body = Robot,
+    responses(
+        (status = 201, description = "Robot created"),
+        (status = 400, description = "Invalid serial, model or version", body = AppError),
+    )
+)]
+pub async fn create_robot_handler(
+    State(repos): State<Repositories>,
+    AppJson(robot): AppJson<Robot>,
+) -> Result<StatusCode, AppError> {
+    robot.create_robot(repos.robots.as_ref()).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/robots/remove",
+    tag = "robots",
+    request_body = RemovalRequest,
+    responses(
+        (status = 200, description = "Robot decommissioned"),
+        (status = 404, description = "No such robot in service", body = AppError),
+        (status = 409, description = "The robot is sold or reserved", body = AppError),
+    )
+)]
+pub async fn remove_robot_request_handler(
+    State(repos): State<Repositories>,
+    AppJson(request): AppJson<RemovalRequest>,
+) -> Result<StatusCode, AppError> {
+    request
+        .robot
+        .remove_robot(repos.robots.as_ref(), request.reason.as_deref())
+        .await
+}
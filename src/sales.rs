@@ -0,0 +1,478 @@
+use std::collections::BTreeMap;
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use chrono::NaiveDateTime;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::archive::archived_response;
+use crate::constants::{FORECAST_HEADERS, SALES_HEADERS, SALES_RECORD_HEADERS, TOTAL_LABEL};
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppQuery};
+use crate::forecast::{build_forecast, Forecast, ForecastQuery};
+use crate::period::{ReportQuery, ReportRange};
+use crate::report::{header_format, total_format, write_headers};
+use crate::report_format::{FormatQuery, Report, ReportFormat, Table};
+use crate::repository::Repositories;
+
+// Sales and order fulfilment figures for one model, version or the whole factory.
+// A request is either a sale from stock or a waitlist entry created in the period,
+// it counts as fulfilled once the robot is sold or the waiting customer is notified.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct SalesFigures {
+    pub sold: i64,
+    // Waitlist length at the end of the period
+    pub waiting: i64,
+    pub requests: i64,
+    pub fulfilled: i64,
+    pub fulfilment_rate: Option<f64>,
+    // Average time between joining the waitlist and being notified, for notifications in the period
+    pub average_wait_hours: Option<f64>,
+    #[serde(skip)]
+    pub notified: i64,
+    #[serde(skip)]
+    pub wait_hours: f64,
+}
+
+impl SalesFigures {
+    fn add(&mut self, other: &SalesFigures) {
+        self.sold += other.sold;
+        self.waiting += other.waiting;
+        self.requests += other.requests;
+        self.fulfilled += other.fulfilled;
+        self.notified += other.notified;
+        self.wait_hours += other.wait_hours;
+        self.update_rates();
+    }
+
+    fn update_rates(&mut self) {
+        self.fulfilment_rate =
+            (self.requests > 0).then(|| self.fulfilled as f64 / self.requests as f64);
+        self.average_wait_hours =
+            (self.notified > 0).then(|| self.wait_hours / self.notified as f64);
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.sold.to_string(),
+            self.waiting.to_string(),
+            self.requests.to_string(),
+            self.fulfilled.to_string(),
+            self.fulfilment_rate
+                .map_or("-".to_string(), |rate| format!("{:.1}%", rate * 100.0)),
+            self.average_wait_hours
+                .map_or("-".to_string(), |hours| format!("{hours:.1}")),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct VersionSales {
+    pub model: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub figures: SalesFigures,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct ModelSales {
+    pub model: String,
+    #[serde(flatten)]
+    pub figures: SalesFigures,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct SalesReport {
+    pub range: ReportRange,
+    pub total: SalesFigures,
+    pub models: Vec<ModelSales>,
+    pub versions: Vec<VersionSales>,
+    // Demand forecast for the weeks after the period
+    pub forecast: Forecast,
+}
+
+type Key = (String, String);
+
+impl SalesReport {
+    pub fn from_figures(
+        range: ReportRange,
+        figures: BTreeMap<Key, SalesFigures>,
+        forecast: Forecast,
+    ) -> Self {
+        let mut total = SalesFigures::default();
+        let mut models: BTreeMap<String, SalesFigures> = BTreeMap::new();
+        let mut versions = Vec::new();
+
+        for ((model, version), mut figures) in figures {
+            figures.update_rates();
+            total.add(&figures);
+            models.entry(model.clone()).or_default().add(&figures);
+            versions.push(VersionSales {
+                model,
+                version,
+                figures,
+            });
+        }
+
+        Self {
+            range,
+            total,
+            models: models
+                .into_iter()
+                .map(|(model, figures)| ModelSales { model, figures })
+                .collect(),
+            versions,
+            forecast,
+        }
+    }
+}
+
+pub async fn build_sales_report(
+    repos: &Repositories,
+    range: ReportRange,
+) -> Result<SalesReport, AppError> {
+    let figures = repos.reports.sales(&range).await?;
+
+    let query = ForecastQuery::default();
+    let params = query
+        .params()
+        .map_err(|err| AppError::internal(format!("Invalid default forecast: {err}")))?;
+    let forecast =
+        build_forecast(repos.reports.as_ref(), range.tz, range.to, params, &query).await?;
+
+    Ok(SalesReport::from_figures(range, figures, forecast))
+}
+
+pub async fn fetch_sales(
+    pool: &DbPool,
+    range: &ReportRange,
+) -> sqlx::Result<BTreeMap<Key, SalesFigures>> {
+    let mut figures: BTreeMap<Key, SalesFigures> = BTreeMap::new();
+
+    let sold: Vec<(String, String, i64)> = sqlx::query_as(
+        "SELECT r.model, r.version, COUNT(*) FROM sold s JOIN robots r ON r.id = s.robot_id
+        WHERE s.sold_date >= $1 AND s.sold_date < $2 GROUP BY r.model, r.version",
+    )
+    .bind(range.start)
+    .bind(range.end)
+    .fetch_all(pool)
+    .await?;
+    for (model, version, count) in sold {
+        let entry = figures.entry((model, version)).or_default();
+        entry.sold = count;
+        entry.requests += count;
+        entry.fulfilled += count;
+    }
+
+    let requested: Vec<(String, String, i64, i64)> = sqlx::query_as(
+        "SELECT model, version, COUNT(*), COUNT(CASE WHEN notified < $2 THEN 1 END) FROM waitlist
+        WHERE requested >= $1 AND requested < $2 GROUP BY model, version",
+    )
+    .bind(range.start)
+    .bind(range.end)
+    .fetch_all(pool)
+    .await?;
+    for (model, version, requests, fulfilled) in requested {
+        let entry = figures.entry((model, version)).or_default();
+        entry.requests += requests;
+        entry.fulfilled += fulfilled;
+    }
+
+    let waiting: Vec<(String, String, i64)> = sqlx::query_as(
+        "SELECT model, version, COUNT(*) FROM waitlist
+        WHERE requested < $1 AND (notified IS NULL OR notified >= $1) GROUP BY model, version",
+    )
+    .bind(range.end)
+    .fetch_all(pool)
+    .await?;
+    for (model, version, count) in waiting {
+        figures.entry((model, version)).or_default().waiting = count;
+    }
+
+    // Waiting times are summed up here, SQLite cannot subtract timestamps
+    let waits: Vec<(String, String, NaiveDateTime, NaiveDateTime)> = sqlx::query_as(
+        "SELECT model, version, requested, notified FROM waitlist
+        WHERE notified >= $1 AND notified < $2",
+    )
+    .bind(range.start)
+    .bind(range.end)
+    .fetch_all(pool)
+    .await?;
+    for (model, version, requested, notified) in waits {
+        let entry = figures.entry((model, version)).or_default();
+        entry.notified += 1;
+        entry.wait_hours += (notified - requested).num_milliseconds() as f64 / 3_600_000.0;
+    }
+
+    Ok(figures)
+}
+
+impl Report for SalesReport {
+    fn kind(&self) -> &'static str {
+        "sales"
+    }
+
+    fn range(&self) -> &ReportRange {
+        &self.range
+    }
+
+    fn title(&self) -> String {
+        format!(
+            "Robot sales {} - {} ({})",
+            self.range.from,
+            self.range.to,
+            self.range.tz.name()
+        )
+    }
+
+    fn file_stem(&self) -> String {
+        format!("sales_report_{}_{}", self.range.from, self.range.to)
+    }
+
+    fn records(&self) -> Table {
+        let rows = self
+            .versions
+            .iter()
+            .map(|row| {
+                let figures = &row.figures;
+                vec![
+                    row.model.clone(),
+                    row.version.clone(),
+                    figures.sold.to_string(),
+                    figures.waiting.to_string(),
+                    figures.requests.to_string(),
+                    figures.fulfilled.to_string(),
+                    figures
+                        .fulfilment_rate
+                        .map_or(String::new(), |r| r.to_string()),
+                    figures
+                        .average_wait_hours
+                        .map_or(String::new(), |h| h.to_string()),
+                ]
+            })
+            .collect();
+
+        Table {
+            title: self.title(),
+            headers: SALES_RECORD_HEADERS.iter().map(|h| h.to_string()).collect(),
+            rows,
+        }
+    }
+
+    fn tables(&self) -> Vec<Table> {
+        let mut by_model: Vec<Vec<String>> = self
+            .models
+            .iter()
+            .map(|row| [vec![row.model.clone()], row.figures.cells()].concat())
+            .collect();
+        by_model.push([vec![TOTAL_LABEL.to_string()], self.total.cells()].concat());
+
+        let by_version = self
+            .versions
+            .iter()
+            .map(|row| {
+                [
+                    vec![row.model.clone(), row.version.clone()],
+                    row.figures.cells(),
+                ]
+                .concat()
+            })
+            .collect();
+
+        vec![
+            Table {
+                title: MODEL_SHEET.to_string(),
+                headers: model_headers(),
+                rows: by_model,
+            },
+            Table {
+                title: VERSION_SHEET.to_string(),
+                headers: SALES_HEADERS.iter().map(|h| h.to_string()).collect(),
+                rows: by_version,
+            },
+            Table {
+                title: FORECAST_SHEET.to_string(),
+                headers: forecast_headers(&self.forecast),
+                rows: forecast_rows(&self.forecast)
+                    .into_iter()
+                    .map(|(model, version, method, values)| {
+                        let values = values.iter().map(|v| format!("{v:.1}"));
+                        [vec![model, version, method.to_string()], values.collect()].concat()
+                    })
+                    .collect(),
+            },
+        ]
+    }
+
+    fn workbook(&self) -> Result<Vec<u8>, XlsxError> {
+        let mut workbook = Workbook::new();
+        let header_format = header_format();
+        let total_format = total_format();
+        let headers = model_headers();
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+
+        let sheet = workbook.add_worksheet().set_name(MODEL_SHEET)?;
+        write_headers(sheet, &headers, &header_format)?;
+        for (i, row) in self.models.iter().enumerate() {
+            let row_num = (i + 1) as u32;
+            sheet.write_string(row_num, 0, &row.model)?;
+            write_figures(sheet, row_num, 1, &row.figures, &Format::new())?;
+        }
+        let row_num = (self.models.len() + 1) as u32;
+        sheet.write_string_with_format(row_num, 0, TOTAL_LABEL, &total_format)?;
+        write_figures(sheet, row_num, 1, &self.total, &total_format)?;
+        sheet.set_freeze_panes(1, 1)?;
+        sheet.autofit();
+
+        let sheet = workbook.add_worksheet().set_name(VERSION_SHEET)?;
+        write_headers(sheet, &SALES_HEADERS, &header_format)?;
+        for (i, row) in self.versions.iter().enumerate() {
+            let row_num = (i + 1) as u32;
+            sheet.write_string(row_num, 0, &row.model)?;
+            sheet.write_string(row_num, 1, &row.version)?;
+            write_figures(sheet, row_num, 2, &row.figures, &Format::new())?;
+        }
+        sheet.set_freeze_panes(1, 2)?;
+        sheet.autofit();
+
+        let headers = forecast_headers(&self.forecast);
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        let sheet = workbook.add_worksheet().set_name(FORECAST_SHEET)?;
+        let number_format = Format::new().set_num_format("0.0");
+        write_headers(sheet, &headers, &header_format)?;
+        for (i, (model, version, method, values)) in
+            forecast_rows(&self.forecast).into_iter().enumerate()
+        {
+            let row_num = (i + 1) as u32;
+            sheet.write_string(row_num, 0, model)?;
+            sheet.write_string(row_num, 1, version)?;
+            sheet.write_string(row_num, 2, method)?;
+            for (week, value) in values.iter().enumerate() {
+                sheet.write_number_with_format(
+                    row_num,
+                    (week + 3) as u16,
+                    *value,
+                    &number_format,
+                )?;
+            }
+        }
+        sheet.set_freeze_panes(1, 3)?;
+        sheet.autofit();
+
+        workbook.save_to_buffer()
+    }
+}
+
+const MODEL_SHEET: &str = "By model";
+const VERSION_SHEET: &str = "By version";
+const FORECAST_SHEET: &str = "Forecast";
+
+fn forecast_headers(forecast: &Forecast) -> Vec<String> {
+    let mut headers: Vec<String> = FORECAST_HEADERS.iter().map(|h| h.to_string()).collect();
+    headers.extend(forecast.forecast_weeks.iter().map(|week| week.to_string()));
+    headers
+}
+
+// (model, version, method, weekly values), one row per method
+fn forecast_rows(forecast: &Forecast) -> Vec<(String, String, &'static str, Vec<f64>)> {
+    let mut rows = Vec::new();
+    for series in &forecast.series {
+        for (method, values) in [
+            ("Moving average", &series.moving_average),
+            ("Exponential smoothing", &series.exponential_smoothing),
+            ("Holt", &series.holt),
+        ] {
+            rows.push((
+                series.model.clone(),
+                series.version.clone(),
+                method,
+                values.clone(),
+            ));
+        }
+    }
+    rows
+}
+
+// The per-model sheet has every column except "Version"
+fn model_headers() -> Vec<String> {
+    SALES_HEADERS
+        .iter()
+        .filter(|h| **h != "Version")
+        .map(|h| h.to_string())
+        .collect()
+}
+
+fn write_figures(
+    sheet: &mut Worksheet,
+    row: u32,
+    first_col: u16,
+    figures: &SalesFigures,
+    format: &Format,
+) -> Result<(), XlsxError> {
+    let counts = [
+        figures.sold,
+        figures.waiting,
+        figures.requests,
+        figures.fulfilled,
+    ];
+    for (i, count) in counts.iter().enumerate() {
+        sheet.write_number_with_format(row, first_col + i as u16, *count as f64, format)?;
+    }
+
+    let rate_col = first_col + counts.len() as u16;
+    match figures.fulfilment_rate {
+        Some(rate) => sheet.write_number_with_format(
+            row,
+            rate_col,
+            rate,
+            &format.clone().set_num_format("0.0%"),
+        )?,
+        None => sheet.write_string_with_format(row, rate_col, "-", format)?,
+    };
+    match figures.average_wait_hours {
+        Some(hours) => sheet.write_number_with_format(
+            row,
+            rate_col + 1,
+            hours,
+            &format.clone().set_num_format("0.0"),
+        )?,
+        None => sheet.write_string_with_format(row, rate_col + 1, "-", format)?,
+    };
+
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/reports/sales",
+    tag = "reports",
+    params(ReportQuery, FormatQuery),
+    responses(
+        (status = 200, description = "The report, in the format of `format` or the Accept header", content(
+            ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" = [u8]),
+            ("text/csv" = String),
+            ("application/json" = SalesReport),
+            ("application/pdf" = [u8]),
+        )),
+        (status = 400, description = "Invalid period or date range", body = AppError),
+        (status = 406, description = "None of the accepted formats is available", body = AppError),
+    )
+)]
+pub async fn sales_report_handler(
+    State(repos): State<Repositories>,
+    AppQuery(query): AppQuery<ReportQuery>,
+    AppQuery(format): AppQuery<FormatQuery>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let format = ReportFormat::negotiate(&format, &headers)?;
+    let range = query
+        .resolve_now()
+        .map_err(|err| AppError::bad_request(err.to_string()))?;
+
+    let report = build_sales_report(&repos, range).await?;
+
+    archived_response(repos.archive.as_ref(), &report, format).await
+}
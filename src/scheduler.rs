@@ -0,0 +1,111 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+
+use crate::archive::{archive_report, ReportSource};
+use crate::constants::{REPORT_RECIPIENTS, REPORT_SCHEDULE};
+use crate::notification::{recipients_from_env, send_email, Email, EmailAttachment, Mailer};
+use crate::period::{factory_timezone, Period, ReportQuery};
+use crate::report::build_report;
+use crate::report_format::{Report, ReportFormat};
+use crate::repository::Repositories;
+
+// The schedule can be overridden with the REPORT_SCHEDULE environment variable
+pub fn report_schedule() -> Schedule {
+    let expression =
+        std::env::var("REPORT_SCHEDULE").unwrap_or_else(|_| REPORT_SCHEDULE.to_string());
+
+    match Schedule::from_str(&expression) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            eprintln!(
+                "Invalid report schedule {expression}: {e}, falling back to {REPORT_SCHEDULE}"
+            );
+            Schedule::from_str(REPORT_SCHEDULE).unwrap()
+        }
+    }
+}
+
+// Comma separated list of managers, overridden with the REPORT_RECIPIENTS environment variable
+pub fn report_recipients() -> Vec<String> {
+    recipients_from_env("REPORT_RECIPIENTS", REPORT_RECIPIENTS)
+}
+
+pub fn next_run(schedule: &Schedule, tz: Tz, after: DateTime<Utc>) -> Option<DateTime<Tz>> {
+    schedule.after(&after.with_timezone(&tz)).next()
+}
+
+// Runs forever, generating, archiving and emailing the production report on every tick
+pub async fn run_report_scheduler(repos: Repositories, mailer: Arc<dyn Mailer>) {
+    let schedule = report_schedule();
+    let tz = factory_timezone();
+
+    loop {
+        let Some(next) = next_run(&schedule, tz, Utc::now()) else {
+            println!("Report schedule has no upcoming runs, scheduler stopped");
+            return;
+        };
+        println!("Next scheduled report at {next}");
+
+        let wait = (next.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        if let Err(e) = generate_scheduled_report(&repos, &mailer, tz, next.date_naive()).await {
+            eprintln!("Scheduled report failed: {e}");
+        }
+    }
+}
+
+// Reports on the week before `run_date`, stores the result in the archive and emails it.
+// Returns the archive id.
+pub async fn generate_scheduled_report(
+    repos: &Repositories,
+    mailer: &Arc<dyn Mailer>,
+    tz: Tz,
+    run_date: NaiveDate,
+) -> anyhow::Result<i32> {
+    let query = ReportQuery {
+        to: Some(run_date - Duration::days(1)),
+        period: Some(Period::Week),
+        ..Default::default()
+    };
+    let range = query.resolve(tz, run_date)?;
+
+    let format = ReportFormat::Xlsx;
+    let report = build_report(repos, range).await?;
+    let content = format.render(&report)?;
+
+    let id = archive_report(
+        repos.archive.as_ref(),
+        &report,
+        format,
+        ReportSource::Scheduled,
+        &content,
+    )
+    .await?;
+    println!("Scheduled report {id} archived");
+
+    let recipients = report_recipients();
+    let subject = report.title();
+    let body = format!(
+        "Hello!\nThe production report for {} - {} is attached.",
+        report.range().from,
+        report.range().to
+    );
+    let attachment = EmailAttachment {
+        filename: format!("{}.{}", report.file_stem(), format.extension()),
+        content_type: format.content_type().to_string(),
+        content,
+    };
+
+    let email = Email::new(recipients, &subject, &body).with_attachment(attachment);
+    send_email(mailer.clone(), "report", email).await?;
+    println!("Scheduled report {id} sent");
+
+    Ok(id)
+}
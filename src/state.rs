@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+
+use crate::db_pool::{lazy_pool, DbPool};
+use crate::notification::{Mailer, MemoryMailer, SmtpMailer};
+use crate::processing::OrderQueue;
+use crate::repository::Repositories;
+use crate::supervisor::Heartbeat;
+
+// Shared by every handler through axum's `State`
+#[derive(Clone)]
+pub struct AppState {
+    // Checked by /health/ready, the handlers go through `repos`
+    pub pool: DbPool,
+    // Storage of every handler
+    pub repos: Repositories,
+    // Orders waiting for a robot, also worked on by the order processor
+    pub orders: Arc<OrderQueue>,
+    // Beaten by the order processor
+    pub heartbeat: Heartbeat,
+    // Sends the emails of the order processor, checked by /health/ready
+    pub mailer: Arc<dyn Mailer>,
+}
+
+impl AppState {
+    pub fn new(pool: DbPool) -> Self {
+        let mailer: Arc<dyn Mailer> = Arc::new(SmtpMailer);
+        let repos = Repositories::database(pool.clone(), mailer.clone());
+        Self::with_parts(pool, repos, mailer)
+    }
+
+    // Keeps everything, also sent emails, in memory. The pool never connects,
+    // so only /health/ready reports the database as unavailable.
+    pub fn in_memory() -> Self {
+        let mailer: Arc<dyn Mailer> = Arc::new(MemoryMailer::default());
+        Self::with_parts(lazy_pool(), Repositories::in_memory(mailer.clone()), mailer)
+    }
+
+    pub fn with_parts(pool: DbPool, repos: Repositories, mailer: Arc<dyn Mailer>) -> Self {
+        let orders = Arc::new(OrderQueue::new(repos.clone(), mailer.clone()));
+        Self {
+            pool,
+            repos,
+            orders,
+            heartbeat: Heartbeat::new(),
+            mailer,
+        }
+    }
+}
+
+impl FromRef<AppState> for DbPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Repositories {
+    fn from_ref(state: &AppState) -> Self {
+        state.repos.clone()
+    }
+}
@@ -0,0 +1,298 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::Validate;
+use validator_derive::Validate;
+
+use crate::constants::{STOCK_ALERT_RECIPIENTS, STOCK_HYSTERESIS};
+use crate::db::validate_model_version;
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppJson, AppPath};
+use crate::notification::{recipients_from_env, send_email, Email, Mailer};
+use crate::repository::{Repositories, RepositoryResult, StockRepository};
+
+// Minimum stock of a model/version. An alert is sent once the stock drops below `minimum`,
+// the next one only after the stock has been back to `recovery` in between.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct StockThreshold {
+    pub model: String,
+    pub version: String,
+    pub minimum: i32,
+    pub recovery: i32,
+    pub alerting: bool,
+    pub alerted_at: Option<NaiveDateTime>,
+    // Robots in stock, i.e. neither sold nor decommissioned
+    pub stock: i64,
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct ThresholdInput {
+    #[validate(range(min = 0))]
+    #[schema(minimum = 0)]
+    pub minimum: i32,
+    // Defaults to `minimum` + STOCK_HYSTERESIS
+    pub recovery: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StockEvent {
+    Low,
+    Recovered,
+}
+
+pub fn evaluate(stock: i64, minimum: i32, recovery: i32, alerting: bool) -> Option<StockEvent> {
+    if !alerting && stock < minimum as i64 {
+        Some(StockEvent::Low)
+    } else if alerting && stock >= recovery as i64 {
+        Some(StockEvent::Recovered)
+    } else {
+        None
+    }
+}
+
+// Comma separated list of technicians and managers, overridden with STOCK_ALERT_RECIPIENTS
+pub fn alert_recipients() -> Vec<String> {
+    recipients_from_env("STOCK_ALERT_RECIPIENTS", STOCK_ALERT_RECIPIENTS)
+}
+
+const THRESHOLD_COLUMNS: &str =
+    "t.model, t.version, t.minimum, t.recovery, t.alerting, t.alerted_at,
+    (SELECT COUNT(*) FROM robots r WHERE r.model = t.model AND r.version = t.version
+    AND r.decommissioned_at IS NULL AND NOT EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id)) AS stock";
+
+pub async fn list_thresholds(pool: &DbPool) -> sqlx::Result<Vec<StockThreshold>> {
+    let sql =
+        format!("SELECT {THRESHOLD_COLUMNS} FROM stock_thresholds t ORDER BY t.model, t.version");
+
+    sqlx::query_as(&sql).fetch_all(pool).await
+}
+
+pub async fn fetch_threshold(
+    pool: &DbPool,
+    model: &str,
+    version: &str,
+) -> sqlx::Result<Option<StockThreshold>> {
+    let sql = format!(
+        "SELECT {THRESHOLD_COLUMNS} FROM stock_thresholds t WHERE t.model = $1 AND t.version = $2"
+    );
+
+    sqlx::query_as(&sql)
+        .bind(model)
+        .bind(version)
+        .fetch_optional(pool)
+        .await
+}
+
+// Creates or replaces the threshold, an alert already sent stays in force
+pub async fn set_threshold(
+    pool: &DbPool,
+    model: &str,
+    version: &str,
+    minimum: i32,
+    recovery: i32,
+) -> sqlx::Result<()> {
+    let sql =
+        "INSERT INTO stock_thresholds (model, version, minimum, recovery) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (model, version) DO UPDATE SET minimum = $3, recovery = $4";
+
+    sqlx::query(sql)
+        .bind(model)
+        .bind(version)
+        .bind(minimum)
+        .bind(recovery)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_threshold(pool: &DbPool, model: &str, version: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("DELETE FROM stock_thresholds WHERE model = $1 AND version = $2")
+        .bind(model)
+        .bind(version)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// The state only changes if nobody else changed it first, returns whether it did
+pub async fn set_alerting(
+    pool: &DbPool,
+    model: &str,
+    version: &str,
+    alerting: bool,
+    at: NaiveDateTime,
+) -> sqlx::Result<bool> {
+    let sql = if alerting {
+        "UPDATE stock_thresholds SET alerting = TRUE, alerted_at = $3
+        WHERE model = $1 AND version = $2 AND NOT alerting"
+    } else {
+        "UPDATE stock_thresholds SET alerting = FALSE
+        WHERE model = $1 AND version = $2 AND alerting"
+    };
+
+    let result = sqlx::query(sql)
+        .bind(model)
+        .bind(version)
+        .bind(at)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Compares the stock of the model/version with its threshold, to be called whenever
+// robots are created, removed or sold. A failed alert is logged, the change of
+// the stock stands.
+pub async fn check_stock(
+    stock: &dyn StockRepository,
+    mailer: &Arc<dyn Mailer>,
+    model: &str,
+    version: &str,
+) -> RepositoryResult<Option<StockEvent>> {
+    let Some(threshold) = stock.fetch_threshold(model, version).await? else {
+        return Ok(None);
+    };
+    let Some(event) = evaluate(
+        threshold.stock,
+        threshold.minimum,
+        threshold.recovery,
+        threshold.alerting,
+    ) else {
+        return Ok(None);
+    };
+
+    // One drop sends one alert
+    let alerting = event == StockEvent::Low;
+    if !stock
+        .set_alerting(model, version, alerting, Utc::now().naive_utc())
+        .await?
+    {
+        return Ok(None);
+    }
+
+    match event {
+        StockEvent::Low => {
+            println!(
+                "Low stock of {model}-{version}: {} left, minimum {}",
+                threshold.stock, threshold.minimum
+            );
+            notify_low_stock(mailer, &threshold).await;
+        }
+        StockEvent::Recovered => {
+            println!("Stock of {model}-{version} recovered: {}", threshold.stock)
+        }
+    }
+
+    Ok(Some(event))
+}
+
+async fn notify_low_stock(mailer: &Arc<dyn Mailer>, threshold: &StockThreshold) {
+    let recipients = alert_recipients();
+    let subject = format!("Low stock: {}-{}", threshold.model, threshold.version);
+    let body = format!(
+        "Hello!\nOnly {} robots of model {}, version {} are left in stock, the minimum is {}.\n\
+        No new alert will be sent until the stock is back to {}.",
+        threshold.stock, threshold.model, threshold.version, threshold.minimum, threshold.recovery
+    );
+
+    let email = Email::new(recipients, &subject, &body);
+    if let Err(e) = send_email(mailer.clone(), "alert", email).await {
+        eprintln!("Failed to send the low stock alert: {e}");
+    }
+}
+
+fn check_model_version(model: &str, version: &str) -> Result<(), AppError> {
+    if validate_model_version(model).is_err() || validate_model_version(version).is_err() {
+        return Err(AppError::bad_request(format!(
+            "Invalid model or version: {model}-{version}"
+        )));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stock/thresholds",
+    tag = "stock",
+    responses((status = 200, description = "Thresholds with the current stock", body = [StockThreshold]))
+)]
+pub async fn list_thresholds_handler(
+    State(repos): State<Repositories>,
+) -> Result<Json<Vec<StockThreshold>>, AppError> {
+    Ok(Json(repos.stock.list_thresholds().await?))
+}
+
+// Creates or replaces the threshold and evaluates the current stock against it
+#[utoipa::path(
+    put,
+    path = "/stock/thresholds/{model}/{version}",
+    tag = "stock",
+    params(
+        ("model" = String, Path, description = "Model, e.g. M1"),
+        ("version" = String, Path, description = "Version, e.g. V1"),
+    ),
+    request_body = ThresholdInput,
+    responses(
+        (status = 200, description = "The threshold with the current stock", body = StockThreshold),
+        (status = 400, description = "Invalid model, version or threshold", body = AppError),
+    )
+)]
+pub async fn set_threshold_handler(
+    State(repos): State<Repositories>,
+    AppPath((model, version)): AppPath<(String, String)>,
+    AppJson(input): AppJson<ThresholdInput>,
+) -> Result<Json<StockThreshold>, AppError> {
+    check_model_version(&model, &version)?;
+    input.validate()?;
+    let recovery = input.recovery.unwrap_or(input.minimum + STOCK_HYSTERESIS);
+    if recovery < input.minimum {
+        return Err(AppError::bad_request(format!(
+            "`recovery` ({recovery}) must not be below `minimum` ({})",
+            input.minimum
+        )));
+    }
+
+    repos
+        .stock
+        .set_threshold(&model, &version, input.minimum, recovery)
+        .await?;
+
+    repos.robots.check_stock(&model, &version).await?;
+
+    match repos.stock.fetch_threshold(&model, &version).await? {
+        Some(threshold) => Ok(Json(threshold)),
+        None => Err(AppError::not_found(format!(
+            "No threshold for {model}-{version}"
+        ))),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/stock/thresholds/{model}/{version}",
+    tag = "stock",
+    params(
+        ("model" = String, Path, description = "Model, e.g. M1"),
+        ("version" = String, Path, description = "Version, e.g. V1"),
+    ),
+    responses(
+        (status = 204, description = "Threshold removed"),
+        (status = 404, description = "No threshold for the model and version", body = AppError),
+    )
+)]
+pub async fn delete_threshold_handler(
+    State(repos): State<Repositories>,
+    AppPath((model, version)): AppPath<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    if repos.stock.delete_threshold(&model, &version).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::not_found(format!(
+            "No threshold for {model}-{version}"
+        )))
+    }
+}
@@ -0,0 +1,91 @@
+use std::any::Any;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::time::sleep;
+
+// Text of a panic payload, panics carry either a &str or a String
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+// Keeps a background worker running: a worker that panics is started again after `delay`,
+// a worker that returns is done. Returns the number of restarts.
+pub async fn supervise<F, Fut>(name: &str, delay: Duration, worker: F) -> u32
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut restarts = 0;
+
+    loop {
+        match tokio::spawn(worker()).await {
+            Ok(()) => {
+                println!("Worker {name} stopped");
+                return restarts;
+            }
+            Err(e) if e.is_panic() => {
+                let payload = e.into_panic();
+                eprintln!(
+                    "Worker {name} crashed: {}, restarting in {}s",
+                    panic_message(payload.as_ref()),
+                    delay.as_secs()
+                );
+            }
+            Err(e) => {
+                eprintln!("Worker {name} was cancelled: {e}");
+                return restarts;
+            }
+        }
+
+        restarts += 1;
+        sleep(delay).await;
+    }
+}
+
+// Time of the last round of a background worker, a worker that stops beating is stuck
+// or dead. It counts as beating from its creation, so a worker gets a grace period to start.
+#[derive(Clone)]
+pub struct Heartbeat {
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self {
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    // A heartbeat that stopped `ago`
+    #[cfg(test)]
+    pub fn stopped(ago: Duration) -> Self {
+        Self {
+            last: Arc::new(Mutex::new(Instant::now() - ago)),
+        }
+    }
+
+    pub fn beat(&self) {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    pub fn age(&self) -> Duration {
+        self.last
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .elapsed()
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
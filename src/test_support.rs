@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum_test_helper::TestClient;
+use chrono::{NaiveDateTime, Utc};
+#[cfg(not(feature = "sqlite"))]
+use sqlx::{Connection, Executor, PgConnection};
+
+use crate::db::{insert_returning_id, Database};
+#[cfg(not(feature = "sqlite"))]
+use crate::db_pool::database_url;
+use crate::db_pool::{connect_options, pool_options, DbPool};
+use crate::notification::MemoryMailer;
+use crate::repository::Repositories;
+use crate::router_with_state;
+use crate::state::AppState;
+
+// Numbers the databases of one test process
+static NEXT_DATABASE: AtomicUsize = AtomicUsize::new(0);
+
+// A migrated database of its own for one test, removed again when it is dropped:
+// a schema on Postgres, a temporary file on SQLite.
+// Every email the app sends, orders, stock alerts and scheduled reports, ends up in `mailer`.
+pub struct TestDb {
+    pub pool: DbPool,
+    pub mailer: Arc<MemoryMailer>,
+    name: String,
+}
+
+impl TestDb {
+    pub async fn new() -> anyhow::Result<Self> {
+        let name = format!(
+            "test_{}_{}",
+            std::process::id(),
+            NEXT_DATABASE.fetch_add(1, Ordering::SeqCst)
+        );
+        let pool = open_database(&name).await?;
+        Database::new(pool.clone()).setup_database().await?;
+
+        Ok(Self {
+            pool,
+            mailer: Arc::new(MemoryMailer::default()),
+            name,
+        })
+    }
+
+    pub fn db(&self) -> Database {
+        Database::with_mailer(self.pool.clone(), self.mailer.clone())
+    }
+
+    pub fn repos(&self) -> Repositories {
+        Repositories::database(self.pool.clone(), self.mailer.clone())
+    }
+
+    pub fn state(&self) -> AppState {
+        AppState::with_parts(self.pool.clone(), self.repos(), self.mailer.clone())
+    }
+
+    pub fn client(&self) -> TestClient {
+        TestClient::new(router_with_state(self.state()))
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        // Drop cannot be async and runs inside the test's runtime, where blocking on
+        // a future panics. The cleanup gets a runtime of its own on another thread,
+        // so a test cannot forget it and it also runs when an assertion fails.
+        let name = self.name.clone();
+        let dropped = std::thread::spawn(move || -> anyhow::Result<()> {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(drop_database(&name))?;
+            Ok(())
+        })
+        .join();
+        match dropped {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Failed to drop test database {}: {e}", self.name),
+            Err(_) => eprintln!("Failed to drop test database {}", self.name),
+        }
+    }
+}
+
+// Every connection of the pool only sees the new schema
+#[cfg(not(feature = "sqlite"))]
+async fn open_database(name: &str) -> Result<DbPool, sqlx::Error> {
+    let options = connect_options(&database_url())?;
+    let mut conn = PgConnection::connect_with(&options).await?;
+    conn.execute(format!("CREATE SCHEMA {name}").as_str())
+        .await?;
+    conn.close().await?;
+
+    let search_path = format!("SET search_path TO {name}");
+    pool_options()
+        .after_connect(move |conn| {
+            let search_path = search_path.clone();
+            Box::pin(async move {
+                conn.execute(search_path.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect_with(options)
+        .await
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn drop_database(name: &str) -> Result<(), sqlx::Error> {
+    let options = connect_options(&database_url())?;
+    let mut conn = PgConnection::connect_with(&options).await?;
+    conn.execute(format!("DROP SCHEMA IF EXISTS {name} CASCADE").as_str())
+        .await?;
+    conn.close().await
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("robots_{name}.db"))
+}
+
+#[cfg(feature = "sqlite")]
+async fn open_database(name: &str) -> Result<DbPool, sqlx::Error> {
+    let url = format!("sqlite:{}", sqlite_file(name).display());
+    pool_options().connect_with(connect_options(&url)?).await
+}
+
+// The write-ahead log and shared memory files go along with the database
+#[cfg(feature = "sqlite")]
+async fn drop_database(name: &str) -> Result<(), sqlx::Error> {
+    let file = sqlite_file(name);
+    for suffix in ["", "-wal", "-shm"] {
+        let path = format!("{}{suffix}", file.display());
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Parses "YYYY-MM-DD HH:MM:SS", seeds are written by hand so a typo is a bug in the test
+fn timestamp(value: &str) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .unwrap_or_else(|e| panic!("Invalid seed time {value}: {e}"))
+}
+
+// A robot in stock, created now unless set
+pub struct RobotSeed {
+    serial: String,
+    model: String,
+    version: String,
+    created: NaiveDateTime,
+}
+
+impl RobotSeed {
+    pub fn new(serial: &str, model: &str, version: &str) -> Self {
+        Self {
+            serial: serial.to_string(),
+            model: model.to_string(),
+            version: version.to_string(),
+            created: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn created(mut self, created: &str) -> Self {
+        self.created = timestamp(created);
+        self
+    }
+
+    // Returns the id of the robot
+    pub async fn insert(self, pool: &DbPool) -> Result<i32, sqlx::Error> {
+        let query = sqlx::query_scalar(
+            "INSERT INTO robots (serial, model, version, created) VALUES ($1, $2, $3, $4)
+            RETURNING id",
+        )
+        .bind(self.serial)
+        .bind(self.model)
+        .bind(self.version)
+        .bind(self.created);
+        insert_returning_id(query, pool)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+}
+
+// A customer whose name is the login and whose password is "pass" unless set
+pub struct CustomerSeed {
+    name: String,
+    email: String,
+    login: String,
+    password: String,
+}
+
+impl CustomerSeed {
+    pub fn new(login: &str) -> Self {
+        Self {
+            name: login.to_string(),
+            email: format!("{login}@example.com"),
+            login: login.to_string(),
+            password: "pass".to_string(),
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = password.to_string();
+        self
+    }
+
+    // Returns the id of the customer
+    pub async fn insert(self, pool: &DbPool) -> Result<i32, sqlx::Error> {
+        let query = sqlx::query_scalar(
+            "INSERT INTO customers (name, email, login, password) VALUES ($1, $2, $3, $4)
+            RETURNING id",
+        )
+        .bind(self.name)
+        .bind(self.email)
+        .bind(self.login)
+        .bind(self.password);
+        insert_returning_id(query, pool)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+}
+
+// An order of a customer, placed now unless set. It is either sold from stock
+// or waiting for a robot.
+pub struct OrderSeed {
+    login: String,
+    model: String,
+    version: String,
+    at: NaiveDateTime,
+}
+
+impl OrderSeed {
+    pub fn new(login: &str, model: &str, version: &str) -> Self {
+        Self {
+            login: login.to_string(),
+            model: model.to_string(),
+            version: version.to_string(),
+            at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn at(mut self, at: &str) -> Self {
+        self.at = timestamp(at);
+        self
+    }
+
+    // Sells the oldest robot in stock through Database::sell_robot_at, the query the
+    // order processor uses, and returns its id.
+    // Fails with RowNotFound if there is no robot in stock or no such customer.
+    pub async fn sold(self, pool: &DbPool) -> Result<i32, sqlx::Error> {
+        let robot_id = Database::new(pool.clone())
+            .sell_robot_at(&self.login, &self.model, &self.version, self.at)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        sqlx::query(
+            "INSERT INTO orders (customer_name, robot_model, order_date)
+            SELECT name, $2, $3 FROM customers WHERE login = $1",
+        )
+        .bind(&self.login)
+        .bind(format!("{}-{}", self.model, self.version))
+        .bind(self.at)
+        .execute(pool)
+        .await?;
+
+        Ok(robot_id)
+    }
+
+    pub async fn waiting(self, pool: &DbPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO waitlist (login, model, version, requested) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(self.login)
+        .bind(self.model)
+        .bind(self.version)
+        .bind(self.at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -1,19 +1,28 @@
 use super::*;
+use crate::constants::{DB_MAX_CONNECTIONS, SCHEMA_VERSION, WORKER_HEARTBEAT_TIMEOUT};
+use crate::notification::{Email, Mailer, MemoryMailer, SmtpMailer};
+use crate::processing::{CurrentOrder, OrderQueue};
+use crate::repository::Repositories;
 use crate::robot::Robot;
+use crate::supervisor::Heartbeat;
+use crate::test_support::{CustomerSeed, OrderSeed, RobotSeed, TestDb};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
 
 use axum::http;
-use http::header::CONTENT_TYPE;
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 
 use axum::http::StatusCode;
+use axum::routing::get;
 use axum_test_helper::TestClient;
 use chrono::Utc;
 // use lettre::transport::smtp::extension::Extension;
 
 #[tokio::test]
 async fn test_create_robot_valid() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
-    let client = TestClient::new(app);
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
 
     let robot = Robot {
         serial: "T0".to_string(),
@@ -29,8 +38,7 @@ async fn test_create_robot_valid() -> anyhow::Result<()> {
 
 #[tokio::test]
 async fn test_create_robot_invalid_serial() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
+    let app = router_with_state(AppState::in_memory());
     let client = TestClient::new(app);
 
     let robot = Robot {
@@ -47,8 +55,7 @@ async fn test_create_robot_invalid_serial() -> anyhow::Result<()> {
 
 #[tokio::test]
 async fn test_create_robot_invalid_model() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
+    let app = router_with_state(AppState::in_memory());
     let client = TestClient::new(app);
 
     let robot = Robot {
@@ -65,9 +72,8 @@ async fn test_create_robot_invalid_model() -> anyhow::Result<()> {
 
 #[tokio::test]
 async fn test_report_handler_success() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
-    let client = TestClient::new(app);
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
     // Send a GET request to the report_handler
     let res = client.get("/robots/report").send().await;
     // Check the status of the response - it should be 200 OK
@@ -89,9 +95,9 @@ async fn test_report_handler_success() -> anyhow::Result<()> {
 
 #[tokio::test]
 async fn test_remove_robot_valid() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
-    let client = TestClient::new(app);
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
 
     // Creating a robot with valid values
     let robot = Robot {
@@ -101,14 +107,8 @@ async fn test_remove_robot_valid() -> anyhow::Result<()> {
     };
 
     // Add robot to Database
-    let pool = get_pool().await?;
-    let current_date = Utc::now().to_rfc3339();
-    let statement = format!("INSERT INTO robots (serial, model, version, created) VALUES ($1, $2, $3, '{current_date}')");
-    sqlx::query(&statement)
-        .bind(&robot.serial)
-        .bind(&robot.model)
-        .bind(&robot.version)
-        .execute(&*pool)
+    RobotSeed::new(&robot.serial, &robot.model, &robot.version)
+        .insert(&pool)
         .await?;
 
     let res = client.post("/robots/remove").json(&robot).send().await;
@@ -119,9 +119,8 @@ async fn test_remove_robot_valid() -> anyhow::Result<()> {
 
 #[tokio::test]
 async fn test_remove_robot_not_found() -> anyhow::Result<()> {
-    let pool = get_pool().await?;
-    let app = create_router(pool);
-    let client = TestClient::new(app);
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
 
     // Trying to delete a robot that is not in the database
     let non_existent_robot = Robot {
@@ -138,3 +137,2097 @@ async fn test_remove_robot_not_found() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_report_handler_concurrent_downloads() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let (week, month) = tokio::join!(
+        client
+            .get("/robots/report?from=2023-10-09&to=2023-10-15")
+            .send(),
+        client
+            .get("/robots/report?from=2023-10-01&period=month")
+            .send(),
+    );
+
+    assert_eq!(
+        week.headers().get(CONTENT_DISPOSITION).unwrap().to_str()?,
+        "attachment; filename=\"robots_report_2023-10-09_2023-10-15.xlsx\""
+    );
+    assert_eq!(
+        month.headers().get(CONTENT_DISPOSITION).unwrap().to_str()?,
+        "attachment; filename=\"robots_report_2023-10-01_2023-10-31.xlsx\""
+    );
+    assert!(week.bytes().await.starts_with(b"PK\x03\x04"));
+    assert!(month.bytes().await.starts_with(b"PK\x03\x04"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_handler_inverted_range() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let res = client
+        .get("/robots/report?from=2023-10-15&to=2023-10-01")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_handler_period() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let res = client.get("/robots/report?period=quarter").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client.get("/robots/report?period=year").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Periods past the last date chrono knows
+    for url in [
+        "/robots/report?from=%2B262143-12-31",
+        "/robots/report?from=%2B262143-12-01&period=month",
+        "/robots?created_to=%2B262143-12-31",
+    ] {
+        let res = client.get(url).send().await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{url}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_report_range_resolution() {
+    use crate::period::{Period, RangeError, ReportQuery};
+    use chrono::NaiveDate;
+
+    let tz = chrono_tz::Europe::Moscow;
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let today = date("2023-10-17");
+
+    // Default is the last week including today, midnight in Moscow is 21:00 UTC
+    let range = ReportQuery::default().resolve(tz, today).unwrap();
+    assert_eq!(range.from, date("2023-10-11"));
+    assert_eq!(range.to, today);
+    assert_eq!(
+        range.start,
+        date("2023-10-10").and_hms_opt(21, 0, 0).unwrap()
+    );
+    assert_eq!(range.end, date("2023-10-17").and_hms_opt(21, 0, 0).unwrap());
+
+    let query = ReportQuery {
+        from: Some(date("2023-01-31")),
+        period: Some(Period::Month),
+        ..Default::default()
+    };
+    // February is shorter, the month ends on its last day
+    assert_eq!(query.resolve(tz, today).unwrap().to, date("2023-02-28"));
+    let query = ReportQuery {
+        from: Some(date("2023-01-15")),
+        period: Some(Period::Month),
+        ..Default::default()
+    };
+    assert_eq!(query.resolve(tz, today).unwrap().to, date("2023-02-14"));
+    let query = ReportQuery {
+        from: Some(date("2023-11-30")),
+        period: Some(Period::Quarter),
+        ..Default::default()
+    };
+    assert_eq!(query.resolve(tz, today).unwrap().to, date("2024-02-29"));
+
+    let query = ReportQuery {
+        from: Some(date("2023-10-15")),
+        to: Some(date("2023-10-01")),
+        period: None,
+    };
+    assert!(matches!(
+        query.resolve(tz, today),
+        Err(RangeError::Inverted { .. })
+    ));
+
+    let query = ReportQuery {
+        from: Some(date("2020-01-01")),
+        to: Some(date("2023-01-01")),
+        period: None,
+    };
+    assert!(matches!(
+        query.resolve(tz, today),
+        Err(RangeError::TooLong { .. })
+    ));
+
+    let query = ReportQuery {
+        from: Some(NaiveDate::MAX),
+        period: Some(Period::Week),
+        ..Default::default()
+    };
+    assert_eq!(query.resolve(tz, today), Err(RangeError::OutOfRange));
+    let query = ReportQuery {
+        to: Some(NaiveDate::MIN),
+        ..Default::default()
+    };
+    assert_eq!(query.resolve(tz, today), Err(RangeError::OutOfRange));
+}
+
+#[test]
+fn test_local_midnight_in_dst_gap() {
+    use crate::period::local_midnight_utc;
+    use chrono::NaiveDate;
+
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let utc = |s: &str| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+
+    // São Paulo went from 00:00 straight to 01:00 (-02:00)
+    let sao_paulo = chrono_tz::America::Sao_Paulo;
+    assert_eq!(
+        local_midnight_utc(sao_paulo, date("2018-11-04")),
+        Some(utc("2018-11-04 03:00"))
+    );
+    assert_eq!(
+        local_midnight_utc(sao_paulo, date("2018-11-03")),
+        Some(utc("2018-11-03 03:00"))
+    );
+
+    // Samoa skipped 2011-12-30, the day starts with the next one (+14:00)
+    let apia = chrono_tz::Pacific::Apia;
+    assert_eq!(
+        local_midnight_utc(apia, date("2011-12-30")),
+        Some(utc("2011-12-30 10:00"))
+    );
+}
+
+#[test]
+fn test_report_groups_by_full_model_name() {
+    use crate::period::ReportQuery;
+    use crate::report::ProductionReport;
+    use chrono::NaiveDate;
+
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let range = ReportQuery::default()
+        .resolve(chrono_tz::Europe::Moscow, date("2023-10-17"))
+        .unwrap();
+
+    let rows = vec![
+        ("R5".to_string(), "D2".to_string(), date("2023-10-17"), 3),
+        ("R2".to_string(), "D2".to_string(), date("2023-10-11"), 30),
+        ("R2".to_string(), "D2".to_string(), date("2023-10-12"), 2),
+        ("R2".to_string(), "A1".to_string(), date("2023-10-16"), 41),
+    ];
+    let report = ProductionReport::from_rows(range, rows, Default::default());
+
+    assert_eq!(report.days.len(), 7);
+    let models: Vec<_> = report.models.iter().map(|m| m.model.as_str()).collect();
+    assert_eq!(models, ["R2", "R5"]);
+
+    let r2 = &report.models[0];
+    let versions: Vec<_> = r2.versions.iter().map(|v| v.version.as_str()).collect();
+    assert_eq!(versions, ["A1", "D2"]);
+    assert_eq!(r2.versions[1].daily, [30, 2, 0, 0, 0, 0, 0]);
+    assert_eq!(r2.versions[1].total, 32);
+    assert_eq!(r2.total, 73);
+    assert_eq!(r2.daily_totals(7), [30, 2, 0, 0, 0, 41, 0]);
+    assert_eq!(report.total, 76);
+}
+
+#[tokio::test]
+async fn test_report_counts_factory_days() -> anyhow::Result<()> {
+    use crate::period::ReportQuery;
+    use crate::report::build_report;
+    use chrono::NaiveDate;
+
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+
+    // Around the end of summer time in Berlin and around midnight in Kolkata (+05:30), in UTC
+    for (serial, created) in [
+        ("T1001", "2023-10-28 21:30:00"),
+        ("T1002", "2023-10-28 22:30:00"),
+        ("T1003", "2023-10-29 22:30:00"),
+        ("T1004", "2023-10-29 23:15:00"),
+        ("T1005", "2023-10-28 18:20:00"),
+        ("T1006", "2023-10-28 18:40:00"),
+    ] {
+        RobotSeed::new(serial, "T1", "V1")
+            .created(created)
+            .insert(&pool)
+            .await?;
+    }
+
+    let query = ReportQuery {
+        from: Some(date("2023-10-28")),
+        to: Some(date("2023-10-30")),
+        period: None,
+    };
+    let daily =
+        |report: crate::report::ProductionReport| report.models[0].versions[0].daily.clone();
+
+    let berlin = query.resolve(chrono_tz::Europe::Berlin, date("2023-10-30"))?;
+    assert_eq!(
+        daily(build_report(&test_db.repos(), berlin).await?),
+        [3, 2, 1]
+    );
+
+    let kolkata = query.resolve(chrono_tz::Asia::Kolkata, date("2023-10-30"))?;
+    assert_eq!(
+        daily(build_report(&test_db.repos(), kolkata).await?),
+        [1, 3, 2]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_handler_formats() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let res = client
+        .get("/robots/report?from=2023-10-09&to=2023-10-15&format=csv")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(CONTENT_TYPE).unwrap().to_str()?,
+        "text/csv; charset=utf-8"
+    );
+    assert!(res.text().await.starts_with("model,version,date,quantity"));
+
+    let res = client
+        .get("/robots/report?period=day")
+        .header("accept", "application/json")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(json["days"].as_array().unwrap().len(), 1);
+    assert!(json["models"].is_array());
+
+    let res = client
+        .get("/robots/report")
+        .header("accept", "text/html;q=0.9, application/pdf")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .unwrap()
+        .to_str()?
+        .ends_with(".pdf\""));
+    assert!(res.bytes().await.starts_with(b"%PDF"));
+
+    let res = client
+        .get("/robots/report")
+        .header("accept", "text/html")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+
+    Ok(())
+}
+
+#[test]
+fn test_report_schedule_next_run() {
+    use crate::scheduler::{next_run, report_schedule};
+    use chrono::{Datelike, TimeZone, Timelike, Weekday};
+
+    let tz = chrono_tz::Europe::Moscow;
+    let schedule = report_schedule();
+
+    // Tuesday 2023-10-17 12:00 UTC, the next run is Monday 08:00 in Moscow
+    let after = Utc.with_ymd_and_hms(2023, 10, 17, 12, 0, 0).unwrap();
+    let next = next_run(&schedule, tz, after).unwrap();
+    assert_eq!(next.weekday(), Weekday::Mon);
+    assert_eq!(next.date_naive().to_string(), "2023-10-23");
+    assert_eq!((next.hour(), next.minute()), (8, 0));
+    assert_eq!(
+        next.with_timezone(&Utc),
+        Utc.with_ymd_and_hms(2023, 10, 23, 5, 0, 0).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_scheduled_report_email() -> anyhow::Result<()> {
+    use crate::scheduler::generate_scheduled_report;
+    use chrono::NaiveDate;
+
+    let test_db = TestDb::new().await?;
+    let mailer: Arc<dyn Mailer> = test_db.mailer.clone();
+    RobotSeed::new("W1001", "W1", "V1")
+        .created("2023-10-18 12:00:00")
+        .insert(&test_db.pool)
+        .await?;
+
+    // Run on Monday 2023-10-23, the report covers the week before
+    let run_date = NaiveDate::from_ymd_opt(2023, 10, 23).unwrap();
+    let id = generate_scheduled_report(&test_db.repos(), &mailer, chrono_tz::UTC, run_date).await?;
+
+    let sent = test_db.mailer.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, scheduler::report_recipients());
+    assert!(sent[0].body.contains("2023-10-16 - 2023-10-22"));
+    let attachment = sent[0].attachment.as_ref().unwrap();
+    assert_eq!(
+        attachment.filename,
+        "robots_report_2023-10-16_2023-10-22.xlsx"
+    );
+
+    // The email carries the archived report
+    let res = test_db.client().get(&format!("/reports/{id}")).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.bytes().await.to_vec(), attachment.content);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_archive() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    let res = client
+        .get("/robots/report?from=2023-10-09&to=2023-10-15&format=csv")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let id: i32 = res
+        .headers()
+        .get("x-report-id")
+        .unwrap()
+        .to_str()?
+        .parse()?;
+    let generated = res.bytes().await;
+
+    // The archive lists the report with its period
+    let res = client.get("/reports?kind=production").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let reports: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    let entry = reports
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|r| r["id"] == id)
+        .unwrap();
+    assert_eq!(entry["period_from"], "2023-10-09");
+    assert_eq!(entry["period_to"], "2023-10-15");
+    assert_eq!(entry["format"], "csv");
+
+    // Downloading returns exactly the generated bytes
+    let res = client.get(&format!("/reports/{id}")).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get(CONTENT_DISPOSITION).unwrap().to_str()?,
+        "attachment; filename=\"robots_report_2023-10-09_2023-10-15.csv\""
+    );
+    assert_eq!(res.bytes().await, generated);
+
+    // Archived reports cannot be changed or removed
+    let update = sqlx::query("UPDATE reports SET content = '' WHERE id = $1")
+        .bind(id)
+        .execute(&pool)
+        .await;
+    assert!(update.is_err());
+    let delete = sqlx::query("DELETE FROM reports WHERE id = $1")
+        .bind(id)
+        .execute(&pool)
+        .await;
+    assert!(delete.is_err());
+
+    let res = client.get("/reports/0").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sales_report() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+    let db = test_db.db();
+
+    CustomerSeed::new("sales_test").insert(&pool).await?;
+    for serial in ["S1001", "S1002"] {
+        RobotSeed::new(serial, "S1", "V1").insert(&pool).await?;
+    }
+
+    // One robot sold from stock, one customer served from the waitlist and one still waiting
+    assert_eq!(db.find_robot("S1", "V1").await?, 2);
+    OrderSeed::new("sales_test", "S1", "V1").sold(&pool).await?;
+    assert_eq!(db.find_robot("S1", "V1").await?, 1);
+    OrderSeed::new("sales_test", "S1", "V2")
+        .waiting(&pool)
+        .await?;
+    assert_eq!(db.mark_notified("sales_test", "S1", "V2").await?.len(), 1);
+    OrderSeed::new("sales_test", "S1", "V2")
+        .waiting(&pool)
+        .await?;
+
+    let res = client
+        .get("/reports/sales?period=day&format=json")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+
+    let models = report["models"].as_array().unwrap();
+    let figures = models.iter().find(|m| m["model"] == "S1").unwrap();
+    assert_eq!(figures["sold"], 1);
+    assert_eq!(figures["waiting"], 1);
+    assert_eq!(figures["requests"], 3);
+    assert_eq!(figures["fulfilled"], 2);
+    assert!(figures["average_wait_hours"].as_f64().unwrap() < 1.0);
+
+    let versions = report["versions"].as_array().unwrap();
+    let v2 = versions
+        .iter()
+        .find(|v| v["model"] == "S1" && v["version"] == "V2")
+        .unwrap();
+    assert_eq!(v2["fulfilment_rate"], 0.5);
+
+    let res = client.get("/reports/sales?period=day").send().await;
+    assert!(res
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .unwrap()
+        .to_str()?
+        .starts_with("attachment; filename=\"sales_report_"));
+    assert!(res.bytes().await.starts_with(b"PK\x03\x04"));
+
+    // The forecast history would start before the first day of the calendar
+    let res = client
+        .get("/reports/sales?from=-262144-01-03&to=-262144-01-10&format=json")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[test]
+fn test_forecast_methods() {
+    use crate::forecast::{exponential_smoothing, history_weeks, holt, moving_average};
+    use chrono::NaiveDate;
+
+    let history = [2, 4, 6, 8];
+    assert_eq!(moving_average(&history, 2, 3), [7.0, 7.5, 7.25]);
+    assert_eq!(moving_average(&[], 2, 1), [0.0]);
+
+    // level: 2 -> 3 -> 4.5 -> 6.25
+    assert_eq!(exponential_smoothing(&history, 0.5, 2), [6.25, 6.25]);
+
+    // A perfect trend is followed exactly and never goes below zero
+    assert_eq!(holt(&history, 0.5, 0.5, 2), [10.0, 12.0]);
+    assert_eq!(holt(&[8, 6, 4, 2], 0.5, 0.5, 3), [0.0, 0.0, 0.0]);
+
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    // Tuesday: the last full week is the previous one
+    assert_eq!(
+        history_weeks(date("2023-10-17"), 2),
+        Ok(vec![date("2023-10-02"), date("2023-10-09")])
+    );
+    // Sunday: its own week is complete
+    assert_eq!(
+        history_weeks(date("2023-10-15"), 1),
+        Ok(vec![date("2023-10-09")])
+    );
+    // Weeks before the first day of the calendar do not exist
+    assert_eq!(
+        history_weeks(NaiveDate::MIN, 1),
+        Err(crate::period::RangeError::OutOfRange)
+    );
+}
+
+#[tokio::test]
+async fn test_forecast_handler() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    // Two customers joined the waitlist a week ago
+    let week_ago = (Utc::now() - chrono::Duration::days(7)).format("%Y-%m-%d %H:%M:%S");
+    for login in ["forecast_a", "forecast_b"] {
+        OrderSeed::new(login, "F1", "V1")
+            .at(&week_ago.to_string())
+            .waiting(&pool)
+            .await?;
+    }
+
+    let res = client
+        .get("/analytics/forecast?model=F1&weeks=3&window=1")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let forecast: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(forecast["forecast_weeks"].as_array().unwrap().len(), 3);
+    let series = forecast["series"].as_array().unwrap();
+    assert_eq!(series.len(), 1);
+    assert_eq!(series[0]["history"].as_array().unwrap().len(), 12);
+    assert_eq!(
+        series[0]["history"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_i64().unwrap())
+            .sum::<i64>(),
+        2
+    );
+
+    let res = client.get("/analytics/forecast?alpha=1.5").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = client.get("/analytics/forecast?window=20").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[test]
+fn test_report_plan_vs_actual() {
+    use crate::period::ReportQuery;
+    use crate::plan::prorated_target;
+    use crate::report::ProductionReport;
+    use chrono::NaiveDate;
+    use std::collections::BTreeMap;
+
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let range = ReportQuery::default()
+        .resolve(chrono_tz::Europe::Moscow, date("2023-10-17"))
+        .unwrap();
+
+    // Four days of a two week plan fall into the report
+    assert_eq!(
+        prorated_target(
+            70,
+            date("2023-10-01"),
+            date("2023-10-14"),
+            range.from,
+            range.to
+        ),
+        20
+    );
+    assert_eq!(
+        prorated_target(
+            70,
+            date("2023-09-01"),
+            date("2023-09-14"),
+            range.from,
+            range.to
+        ),
+        0
+    );
+
+    let rows = vec![
+        ("R2".to_string(), "D2".to_string(), date("2023-10-11"), 32),
+        ("R2".to_string(), "A1".to_string(), date("2023-10-12"), 5),
+    ];
+    let targets = BTreeMap::from([
+        (("R2".to_string(), "D2".to_string()), 40),
+        (("R9".to_string(), "A1".to_string()), 5),
+    ]);
+    let report = ProductionReport::from_rows(range, rows, targets);
+
+    let r2 = &report.models[0];
+    assert_eq!(r2.versions[0].target, None);
+    assert!(!r2.versions[0].below_target);
+    assert_eq!(r2.versions[1].target, Some(40));
+    assert_eq!(r2.versions[1].attainment, Some(0.8));
+    assert!(r2.versions[1].below_target);
+    assert_eq!(r2.target, Some(40));
+
+    // Planned but not produced at all
+    let r9 = &report.models[1];
+    assert_eq!(r9.model, "R9");
+    assert_eq!(r9.total, 0);
+    assert_eq!(r9.attainment, Some(0.0));
+    assert!(r9.below_target);
+}
+
+#[tokio::test]
+async fn test_production_plans() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    for serial in ["P1901", "P1902"] {
+        RobotSeed::new(serial, "P1", "V1")
+            .created("2001-01-03 12:00:00")
+            .insert(&pool)
+            .await?;
+    }
+
+    let plan = serde_json::json!({
+        "model": "P1",
+        "version": "V1",
+        "period_start": "2001-01-01",
+        "period_end": "2001-01-07",
+        "target": 4
+    });
+    let res = client.post("/plans").json(&plan).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let created: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    let id = created["id"].as_i64().unwrap();
+    assert_eq!(created["actual"], 2);
+    assert_eq!(created["attainment"], 0.5);
+    assert_eq!(created["below_target"], true);
+
+    // Overlapping plans for the same version are rejected
+    let mut overlapping = plan.clone();
+    overlapping["period_start"] = "2001-01-05".into();
+    overlapping["period_end"] = "2001-01-10".into();
+    let res = client.post("/plans").json(&overlapping).send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let mut inverted = plan.clone();
+    inverted["period_end"] = "2000-12-31".into();
+    let res = client.post("/plans").json(&inverted).send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let mut updated = plan.clone();
+    updated["target"] = 2.into();
+    let res = client
+        .put(&format!("/plans/{id}"))
+        .json(&updated)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // Each plan counts only its own version and days
+    RobotSeed::new("P1903", "P1", "V1")
+        .created("2001-01-09 12:00:00")
+        .insert(&pool)
+        .await?;
+    let mut next_week = plan.clone();
+    next_week["period_start"] = "2001-01-08".into();
+    next_week["period_end"] = "2001-01-14".into();
+    let res = client.post("/plans").json(&next_week).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.get("/plans?model=P1&from=2001-01-01").send().await;
+    let plans: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(plans.as_array().unwrap().len(), 2);
+    assert_eq!(plans[0]["actual"], 2);
+    assert_eq!(plans[0]["attainment"], 1.0);
+    assert_eq!(plans[0]["below_target"], false);
+    assert_eq!(plans[1]["actual"], 1);
+
+    let res = client
+        .get("/robots/report?from=2001-01-01&to=2001-01-07&format=json")
+        .send()
+        .await;
+    let report: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    let p1 = report["models"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["model"] == "P1")
+        .unwrap();
+    assert_eq!(p1["versions"][0]["target"], 2);
+    assert_eq!(p1["versions"][0]["attainment"], 1.0);
+
+    let res = client.delete(&format!("/plans/{id}")).send().await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = client.get(&format!("/plans/{id}")).send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // Of overlapping plans created at once only one is stored
+    let plans = (0..5).map(|day| {
+        let pool = pool.clone();
+        let plan = crate::plan::PlanInput {
+            model: "P2".to_string(),
+            version: "V1".to_string(),
+            period_start: chrono::NaiveDate::from_ymd_opt(2001, 2, 1 + day).unwrap(),
+            period_end: chrono::NaiveDate::from_ymd_opt(2001, 2, 10).unwrap(),
+            target: 1,
+        };
+        tokio::spawn(async move { crate::plan::insert_plan(&pool, &plan).await })
+    });
+    let mut stored = 0;
+    for plan in plans.collect::<Vec<_>>() {
+        if plan.await??.is_some() {
+            stored += 1;
+        }
+    }
+    assert_eq!(stored, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_low_stock_alerts() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let res = client
+        .put("/stock/thresholds/L1/V1")
+        .json(&serde_json::json!({"minimum": 2, "recovery": 3}))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(body["stock"], 0);
+    assert_eq!(body["alerting"], true);
+    let sent = test_db.mailer.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].subject, "Low stock: L1-V1");
+    assert_eq!(sent[0].to, stock::alert_recipients());
+    assert!(sent[0].body.contains("the minimum is 2"));
+
+    let alerting = || async {
+        let res = client.get("/stock/thresholds").send().await;
+        let thresholds: serde_json::Value = serde_json::from_slice(&res.bytes().await).unwrap();
+        thresholds
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|t| t["model"] == "L1" && t["version"] == "V1")
+            .unwrap()["alerting"]
+            .as_bool()
+            .unwrap()
+    };
+
+    // The alert stays active until the stock is back to the recovery level
+    for (serial, expected) in [("L1001", true), ("L1002", true), ("L1003", false)] {
+        let res = client
+            .post("/robots/create")
+            .json(&serde_json::json!({"serial": serial, "model": "L1", "version": "V1"}))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(alerting().await, expected, "after creating {serial}");
+    }
+
+    // Dropping to the minimum is fine, dropping below it raises the alert again
+    for (serial, expected) in [("L1003", false), ("L1002", true)] {
+        let res = client
+            .post("/robots/remove")
+            .json(&serde_json::json!({"serial": serial, "model": "L1", "version": "V1"}))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(alerting().await, expected, "after removing {serial}");
+    }
+    // One alert per drop below the minimum
+    assert_eq!(test_db.mailer.sent().len(), 2);
+
+    let res = client
+        .put("/stock/thresholds/L1/V1")
+        .json(&serde_json::json!({"minimum": 2, "recovery": 1}))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = client.delete("/stock/thresholds/L1/V1").send().await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_robot_batch_import() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    // One invalid row rejects the whole batch in the default atomic mode
+    let rows = serde_json::json!([
+        {"serial": "0", "model": "B1", "version": "V1"},
+        {"serial": "0", "model": "B1", "version": "bad"},
+        {"serial": "B1x"}
+    ]);
+    let res = client.post("/robots/batch").json(&rows).send().await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["received"], 3);
+    assert_eq!(summary["created"].as_array().unwrap().len(), 0);
+    let failed: Vec<_> = summary["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["row"].as_i64().unwrap())
+        .collect();
+    assert_eq!(failed, [2, 3]);
+
+    let res = client
+        .post("/robots/batch?mode=partial")
+        .json(&rows)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["created"], serde_json::json!(["B1001"]));
+
+    let csv = "serial,model,version\n0,B1,V1\n0,B1,V2\nB1001,B1,V1\n";
+    let res = client
+        .post("/robots/batch?mode=partial")
+        .header(CONTENT_TYPE, "text/csv")
+        .body(csv)
+        .send()
+        .await;
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["created"], serde_json::json!(["B1002", "B1003"]));
+    assert_eq!(summary["errors"][0]["row"], 3);
+    assert_eq!(summary["errors"][0]["serial"], "B1001");
+
+    let ndjson = "{\"serial\":\"B1900\",\"model\":\"B1\",\"version\":\"V1\"}\n\n{\"serial\":\"0\",\"model\":\"B1\",\"version\":\"V1\"}\n";
+    let res = client
+        .post("/robots/batch")
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(ndjson)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["created"], serde_json::json!(["B1900", "B1004"]));
+
+    // Blank lines count, errors point at the line of the upload
+    let ndjson = "\n{\"serial\":\"0\",\"model\":\"B1\",\"version\":\"V1\"}\n\n{\"serial\":\"0\",\"model\":\"B1\"}\n";
+    let res = client
+        .post("/robots/batch?mode=partial")
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(ndjson)
+        .send()
+        .await;
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["created"], serde_json::json!(["B1006"]));
+    assert_eq!(summary["errors"][0]["row"], 4);
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM robots WHERE model = 'B1'")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(count, 6);
+
+    let res = client
+        .post("/robots/batch")
+        .header(CONTENT_TYPE, "text/plain")
+        .body("B1")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_robot_query() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    CustomerSeed::new("query_test")
+        .name("Query Test")
+        .insert(&pool)
+        .await?;
+    // Q1A02 and Q1B03 share the creation time, the id breaks the tie
+    for (serial, version, created) in [
+        ("Q1A01", "V1", "2002-03-01 10:00:00"),
+        ("Q1A02", "V1", "2002-03-02 10:00:00"),
+        ("Q1B03", "V2", "2002-03-02 10:00:00"),
+        ("Q1B04", "V2", "2002-03-03 10:00:00"),
+        ("Q1B05", "V2", "2002-03-04 10:00:00"),
+    ] {
+        RobotSeed::new(serial, "Q1", version)
+            .created(created)
+            .insert(&pool)
+            .await?;
+    }
+    // The oldest V1 robot is sold
+    OrderSeed::new("query_test", "Q1", "V1").sold(&pool).await?;
+
+    let serials = |page: &serde_json::Value| -> Vec<String> {
+        page["robots"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["serial"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let mut all = Vec::new();
+    let mut url = "/robots?model=Q1&order=asc&limit=2".to_string();
+    loop {
+        let res = client.get(&url).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+        all.extend(serials(&page));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => url = format!("/robots?model=Q1&order=asc&limit=2&cursor={cursor}"),
+            None => break,
+        }
+    }
+    assert_eq!(all, ["Q1A01", "Q1A02", "Q1B03", "Q1B04", "Q1B05"]);
+
+    let res = client
+        .get("/robots?model=Q1&sold=false&serial_prefix=Q1B&sort=serial&created_to=2002-03-03")
+        .send()
+        .await;
+    let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(serials(&page), ["Q1B04", "Q1B03"]);
+
+    let res = client.get("/robots?model=Q1&sold=true").send().await;
+    let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(serials(&page), ["Q1A01"]);
+
+    let res = client.get("/robots/Q1A01").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(robot["model"], "Q1");
+    assert_eq!(robot["sold"], true);
+    assert_eq!(robot["sale"]["customer_login"], "query_test");
+
+    let res = client.get("/robots/Q1B05").send().await;
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert!(robot["sale"].is_null());
+
+    let res = client.get("/robots/Q1X99").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client.get("/robots?cursor=zz").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = client.get("/robots?limit=0").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_robot_decommission_and_restore() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+    let db = test_db.db();
+
+    CustomerSeed::new("decommission_test").insert(&pool).await?;
+    for serial in ["D1001", "D1002", "D1003"] {
+        RobotSeed::new(serial, "D1", "V1").insert(&pool).await?;
+    }
+    OrderSeed::new("decommission_test", "D1", "V1")
+        .sold(&pool)
+        .await?;
+    assert_eq!(db.find_robot("D1", "V1").await?, 2);
+
+    // Removing the sold robot keeps its sale
+    set_api_tokens();
+    let res = client
+        .delete("/robots/D1001?reason=water%20damage&force=true")
+        .header("Authorization", "Bearer test-manager")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/robots/D1001").send().await;
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(robot["decommission_reason"], "water damage");
+    assert_eq!(robot["sale"]["customer_login"], "decommission_test");
+
+    let removal = serde_json::json!({"serial": "D1002", "model": "D1", "version": "V1"});
+    let res = client.post("/robots/remove").json(&removal).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(db.find_robot("D1", "V1").await?, 1);
+    let res = client.post("/robots/remove").json(&removal).send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = client.get("/robots?model=D1").send().await;
+    let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(page["robots"].as_array().unwrap().len(), 1);
+    let res = client
+        .get("/robots?model=D1&decommissioned=true")
+        .send()
+        .await;
+    let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(page["robots"].as_array().unwrap().len(), 2);
+
+    let res = client.post("/robots/D1002/restore").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert!(robot["decommissioned_at"].is_null());
+    assert_eq!(db.find_robot("D1", "V1").await?, 2);
+    let res = client.post("/robots/D1002/restore").send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // Past the retention window
+    sqlx::query("UPDATE robots SET decommissioned_at = $1 WHERE serial = 'D1001'")
+        .bind(Utc::now().naive_utc() - chrono::Duration::days(40))
+        .execute(&pool)
+        .await?;
+    let res = client.post("/robots/D1001/restore").send().await;
+    assert_eq!(res.status(), StatusCode::GONE);
+
+    Ok(())
+}
+
+// Every test sets the same tokens, so running them in parallel is fine
+fn set_api_tokens() {
+    std::env::set_var(
+        "API_TOKENS",
+        "test-manager:manager,test-technician:technician",
+    );
+}
+
+#[tokio::test]
+async fn test_remove_robot_by_serial() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+    set_api_tokens();
+
+    CustomerSeed::new("removal_test").insert(&pool).await?;
+    for (serial, created) in [
+        ("E1001", "2003-01-01 10:00:00"),
+        ("E1002", "2003-01-02 10:00:00"),
+        ("E1003", "2003-01-03 10:00:00"),
+    ] {
+        RobotSeed::new(serial, "E1", "V1")
+            .created(created)
+            .insert(&pool)
+            .await?;
+    }
+    // E1001 is sold, E1002 is kept for the waiting customer, E1003 is free
+    OrderSeed::new("removal_test", "E1", "V1")
+        .sold(&pool)
+        .await?;
+    OrderSeed::new("removal_test", "E1", "V1")
+        .waiting(&pool)
+        .await?;
+
+    let res = client
+        .delete("/robots/E1003?model=E1&version=V2")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = client.delete("/robots/E1001").send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let res = client.delete("/robots/E1002").send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let res = client
+        .delete("/robots/E1003?model=E1&version=V1")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(robot["serial"], "E1003");
+    assert_eq!(robot["decommission_reason"], "unspecified");
+    let res = client.delete("/robots/E1003").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // Forcing needs a manager
+    let res = client.delete("/robots/E1002?force=true").send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = client
+        .delete("/robots/E1002?force=true")
+        .header("Authorization", "Bearer test-technician")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client
+        .delete("/robots/E1002?force=true&reason=recalled")
+        .header("Authorization", "Bearer test-manager")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(robot["decommission_reason"], "recalled");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_responses() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    async fn error_body(res: axum_test_helper::TestResponse) -> serde_json::Value {
+        serde_json::from_slice(&res.bytes().await).expect("error body is JSON")
+    }
+
+    // Every invalid field is listed, without echoing the rejected value
+    let invalid = serde_json::json!({
+        "name": "Error Test",
+        "email": "not-an-email",
+        "login": "error_test",
+        "password": "x",
+    });
+    let res = client.post("/user/create").json(&invalid).send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body = error_body(res).await;
+    assert_eq!(body["code"], "validation_failed");
+    assert_eq!(body["fields"]["email"][0]["code"], "email");
+    assert_eq!(body["fields"]["password"][0]["code"], "length");
+    assert_eq!(body["fields"]["password"][0]["params"]["min"], 3);
+    assert!(body["fields"]["password"][0]["params"]
+        .get("value")
+        .is_none());
+    assert!(body["fields"].get("login").is_none());
+
+    let customer = serde_json::json!({
+        "name": "Error Test",
+        "email": "error_test@example.com",
+        "login": "error_test",
+        "password": "secret",
+    });
+    let res = client.post("/user/create").json(&customer).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.post("/user/create").json(&customer).send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(error_body(res).await["code"], "conflict");
+
+    // Rejections of the extractors
+    let res = client
+        .post("/user/create")
+        .header(CONTENT_TYPE, "application/json")
+        .body("{")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_body(res).await["code"], "bad_request");
+    let res = client.post("/robots/create").body("{}").send().await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(error_body(res).await["code"], "unsupported_media_type");
+    let res = client.get("/plans/first").send().await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_body(res).await["code"], "bad_request");
+
+    let res = client.get("/robots/XX999").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body = error_body(res).await;
+    assert_eq!(body["code"], "not_found");
+    assert_eq!(body["message"], "Robot XX999 not found");
+    let res = client.get("/no/such/endpoint").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(error_body(res).await["code"], "not_found");
+
+    let res = client.delete("/robots/XX999?force=true").send().await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_body(res).await["code"], "unauthorized");
+
+    // Database errors of the reports keep their status
+    test_db.pool.close().await;
+    for url in ["/robots/report", "/reports/sales", "/analytics/forecast"] {
+        let res = client.get(url).send().await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE, "{url}");
+        assert_eq!(error_body(res).await["code"], "unavailable");
+    }
+
+    Ok(())
+}
+
+async fn panicking_handler() -> &'static str {
+    panic!("handler failed")
+}
+
+#[tokio::test]
+async fn test_handler_panic_is_caught() -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/panic", get(panicking_handler))
+        .route("/fine", get(|| async { "fine" }))
+        .layer(CatchPanicLayer::custom(crate::error::panic_response));
+    let client = TestClient::new(app);
+
+    let res = client.get("/panic").send().await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(body["code"], "internal");
+    assert_eq!(body["message"], "Handler panicked: handler failed");
+
+    // The server keeps serving
+    let res = client.get("/fine").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_worker_supervisor_restarts_crashed_worker() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static RUNS: AtomicU32 = AtomicU32::new(0);
+    // Crashes twice, then finishes
+    let worker = || async {
+        if RUNS.fetch_add(1, Ordering::SeqCst) < 2 {
+            panic!("worker failed");
+        }
+    };
+
+    let restarts = supervisor::supervise("test worker", Duration::ZERO, worker).await;
+    assert_eq!(restarts, 2);
+    assert_eq!(RUNS.load(Ordering::SeqCst), 3);
+}
+
+// A mail server that takes its time to accept an email
+struct SlowMailer;
+
+impl Mailer for SlowMailer {
+    fn send(&self, _email: &Email) -> Result<(), anyhow::Error> {
+        std::thread::sleep(Duration::from_secs(2));
+        Ok(())
+    }
+
+    fn check(&self) -> Result<String, anyhow::Error> {
+        Ok("Mail server reachable".to_string())
+    }
+}
+
+#[tokio::test]
+async fn test_orders_accepted_while_notifying() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let mailer: Arc<dyn Mailer> = Arc::new(SlowMailer);
+    let state = AppState::with_parts(
+        pool.clone(),
+        Repositories::database(pool.clone(), mailer.clone()),
+        mailer,
+    );
+    let client = TestClient::new(router_with_state(state.clone()));
+
+    CustomerSeed::new("slow_test").insert(&pool).await?;
+    let order = |model: &str| serde_json::json!({"login": "slow_test", "password": "pass", "model": model, "version": "V1"});
+    let res = client.post("/robots/order").json(&order("K1")).send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    RobotSeed::new("K1001", "K1", "V1").insert(&pool).await?;
+
+    // The queue is not locked while the customer is emailed
+    let processing = tokio::spawn({
+        let orders = state.orders.clone();
+        async move { orders.process().await }
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let res = tokio::time::timeout(
+        Duration::from_secs(1),
+        client.post("/robots/order").json(&order("K2")).send(),
+    )
+    .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    processing.await?;
+    let orders = state.orders.orders.lock().await;
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].model, "K2");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_order_processing_errors() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let client = test_db.client();
+
+    CustomerSeed::new("order_test").insert(&pool).await?;
+    RobotSeed::new("O1001", "O1", "V1").insert(&pool).await?;
+
+    let order = |login: &str| serde_json::json!({"login": login, "password": "pass", "model": "O1", "version": "V1"});
+    let res = client
+        .post("/robots/order")
+        .json(&order("nobody_here"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = client
+        .post("/robots/order")
+        .json(&order("order_test"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    // The only robot is sold, the next order waits
+    let res = client
+        .post("/robots/order")
+        .json(&order("order_test"))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // An order whose customer has gone is dropped instead of stopping the processor
+    let queue = OrderQueue::new(
+        Repositories::database(pool, test_db.mailer.clone()),
+        test_db.mailer.clone(),
+    );
+    queue.orders.lock().await.push_back(CurrentOrder {
+        login: "nobody_here".to_string(),
+        password: "pass".to_string(),
+        model: "O1".to_string(),
+        version: "V1".to_string(),
+    });
+    queue.orders.lock().await.push_back(CurrentOrder {
+        login: "order_test".to_string(),
+        password: "pass".to_string(),
+        model: "O1".to_string(),
+        version: "V1".to_string(),
+    });
+    queue.process().await;
+    let orders = queue.orders.lock().await;
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].login, "order_test");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_requests_share_one_pool() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let app = router_with_state(test_db.state());
+
+    // Far more requests than connections, they wait for a free one instead of opening their own
+    let mut requests = tokio::task::JoinSet::new();
+    for _ in 0..40 {
+        let client = TestClient::new(app.clone());
+        requests.spawn(async move { client.get("/robots?limit=1").send().await.status() });
+    }
+    while let Some(status) = requests.join_next().await {
+        assert_eq!(status?, StatusCode::OK);
+    }
+    assert!(pool.size() <= constants::DB_MAX_CONNECTIONS);
+
+    Ok(())
+}
+
+// Robots, customers and orders work without a database
+#[tokio::test]
+async fn test_in_memory_router() -> anyhow::Result<()> {
+    let client = TestClient::new(router_with_state(AppState::in_memory()));
+
+    let customer = serde_json::json!({
+        "name": "Memory Test", "email": "memory@example.com",
+        "login": "memory_test", "password": "pass"
+    });
+    let res = client.post("/user/create").json(&customer).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.post("/user/create").json(&customer).send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    for serial in ["0", "0", "HAND1"] {
+        let robot = Robot {
+            serial: serial.to_string(),
+            model: "M1".to_string(),
+            version: "V1".to_string(),
+        };
+        let res = client.post("/robots/create").json(&robot).send().await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+    let res = client.get("/robots/M1002").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let order = serde_json::json!({
+        "login": "memory_test", "password": "pass", "model": "M1", "version": "V1"
+    });
+    let res = client.post("/robots/order").json(&order).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/robots/M1001").send().await;
+    let robot: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(robot["sale"]["customer_name"], "Memory Test");
+
+    let wrong_password = serde_json::json!({
+        "login": "memory_test", "password": "wrong", "model": "M1", "version": "V1"
+    });
+    let res = client
+        .post("/robots/order")
+        .json(&wrong_password)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let removal = serde_json::json!({"serial": "HAND1", "model": "M1", "version": "V1"});
+    let res = client.post("/robots/remove").json(&removal).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.post("/robots/HAND1/restore").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // A robot made after the order is reserved for the waiting customer
+    let waiting = serde_json::json!({
+        "login": "memory_test", "password": "pass", "model": "M1", "version": "V2"
+    });
+    let res = client.post("/robots/order").json(&waiting).send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let robot = Robot {
+        serial: "0".to_string(),
+        model: "M1".to_string(),
+        version: "V2".to_string(),
+    };
+    let res = client.post("/robots/create").json(&robot).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.delete("/robots/M1004").send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let mut serials = Vec::new();
+    let mut url = "/robots?sort=serial&order=asc&limit=2".to_string();
+    loop {
+        let res = client.get(&url).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+        for robot in page["robots"].as_array().unwrap() {
+            serials.push(robot["serial"].as_str().unwrap().to_string());
+        }
+        match page["next_cursor"].as_str() {
+            Some(cursor) => url = format!("/robots?sort=serial&order=asc&limit=2&cursor={cursor}"),
+            None => break,
+        }
+    }
+    assert_eq!(serials, ["HAND1", "M1001", "M1002", "M1004"]);
+    let res = client.get("/robots?sold=false").send().await;
+    let page: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(page["robots"].as_array().unwrap().len(), 3);
+
+    Ok(())
+}
+
+// Batch imports, stock thresholds, plans, reports and their archive work without a database
+#[tokio::test]
+async fn test_in_memory_reports() -> anyhow::Result<()> {
+    let mailer = Arc::new(MemoryMailer::default());
+    let state = AppState::with_parts(
+        db_pool::lazy_pool(),
+        Repositories::in_memory(mailer.clone()),
+        mailer.clone(),
+    );
+    let client = TestClient::new(router_with_state(state));
+
+    let batch = serde_json::json!([
+        {"serial": "0", "model": "B7", "version": "V1"},
+        {"serial": "0", "model": "B7", "version": "V1"},
+        {"serial": "0", "model": "B7", "version": "V1"},
+    ]);
+    let res = client.post("/robots/batch").json(&batch).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let summary: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(summary["created"].as_array().unwrap().len(), 3);
+
+    // Three robots are below the minimum, the alert goes out once
+    let threshold = serde_json::json!({"minimum": 5});
+    let res = client
+        .put("/stock/thresholds/B7/V1")
+        .json(&threshold)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let stored: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(stored["stock"], 3);
+    assert_eq!(stored["alerting"], true);
+    assert_eq!(mailer.sent().len(), 1);
+    let res = client.delete("/stock/thresholds/B7/V1").send().await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = client.delete("/stock/thresholds/B7/V1").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let today = Utc::now()
+        .with_timezone(&crate::period::factory_timezone())
+        .date_naive();
+    let plan = serde_json::json!({
+        "model": "B7", "version": "V1", "period_start": today, "period_end": today, "target": 3
+    });
+    let res = client.post("/plans").json(&plan).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let created: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(created["actual"], 3);
+    let res = client.post("/plans").json(&plan).send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let res = client
+        .get("/robots/report?period=day&format=json")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let id = res
+        .headers()
+        .get("x-report-id")
+        .unwrap()
+        .to_str()?
+        .to_string();
+    let report: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(report["models"][0]["versions"][0]["total"], 3);
+    assert_eq!(report["models"][0]["versions"][0]["target"], 3);
+    let res = client.get(&format!("/reports/{id}")).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client
+        .get("/reports/sales?period=day&format=json")
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/reports").send().await;
+    let reports: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(reports.as_array().unwrap().len(), 2);
+
+    let res = client.get("/analytics/forecast").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+// Orders a robot, waits for the next one and gets the email once it is made
+async fn order_until_notified(
+    state: AppState,
+    mailer: &MemoryMailer,
+    login: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    let client = TestClient::new(router_with_state(state.clone()));
+    let order = serde_json::json!({
+        "login": login, "password": password, "model": "N1", "version": "V1"
+    });
+    let robot = serde_json::json!({"serial": "0", "model": "N1", "version": "V1"});
+
+    let res = client.post("/robots/create").json(&robot).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.post("/robots/order").json(&order).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/robots/N1001").send().await;
+    let sold: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+    assert_eq!(sold["sale"]["customer_name"], "Flow Test");
+
+    // Out of stock, the order waits and nobody is emailed yet
+    let res = client.post("/robots/order").json(&order).send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    state.orders.process().await;
+    assert_eq!(state.orders.orders.lock().await.len(), 1);
+    assert!(mailer.sent().is_empty());
+
+    // The next robot is kept for the waiting customer
+    let res = client.post("/robots/create").json(&robot).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.delete("/robots/N1002").send().await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    state.orders.process().await;
+    assert!(state.orders.orders.lock().await.is_empty());
+    let sent = mailer.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, [format!("{login}@example.com")]);
+    assert_eq!(sent[0].subject, constants::ORDER_AVAILABLE_SUBJECT);
+    assert!(sent[0].body.contains("Flow Test"));
+    assert!(sent[0].body.contains("N1"));
+
+    // Every customer is emailed once
+    state.orders.process().await;
+    assert_eq!(mailer.sent().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_order_waitlist_notification() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+
+    CustomerSeed::new("flow_test")
+        .name("Flow Test")
+        .password("secret")
+        .insert(&pool)
+        .await?;
+    order_until_notified(test_db.state(), &test_db.mailer, "flow_test", "secret").await?;
+
+    let notified: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM waitlist WHERE login = 'flow_test' AND notified IS NOT NULL",
+    )
+    .fetch_one(&pool)
+    .await?;
+    assert_eq!(notified, 1);
+    let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders WHERE robot_model = 'N1-V1'")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(orders, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_sales() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+
+    for serial in ["C1001", "C1002", "C1003"] {
+        RobotSeed::new(serial, "C1", "V1").insert(&pool).await?;
+    }
+    let logins: Vec<String> = (0..8).map(|i| format!("concurrent_{i}")).collect();
+    for login in &logins {
+        CustomerSeed::new(login).insert(&pool).await?;
+    }
+
+    // More orders than robots at once, every robot goes to exactly one of them.
+    // An order that loses all its attempts fails without selling anything.
+    let sales = logins.iter().map(|login| {
+        let db = test_db.db();
+        let login = login.clone();
+        tokio::spawn(async move { db.sell_robot(&login, "C1", "V1").await })
+    });
+    let mut sold = 0;
+    for sale in sales.collect::<Vec<_>>() {
+        sold += sale.await?.unwrap_or(0);
+    }
+    assert_eq!(sold, 3);
+
+    let sales: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sold")
+        .fetch_one(&pool)
+        .await?;
+    let robots: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT robot_id) FROM sold")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!((sales, robots), (3, 3));
+
+    // The index refuses a second sale of a robot however it is written
+    let robot_id: i32 = sqlx::query_scalar("SELECT robot_id FROM sold LIMIT 1")
+        .fetch_one(&pool)
+        .await?;
+    let again = sqlx::query(
+        "INSERT INTO sold (robot_id, customer_id, sold_date)
+        SELECT $1, customer_id, sold_date FROM sold WHERE robot_id = $1",
+    )
+    .bind(robot_id)
+    .execute(&pool)
+    .await;
+    assert!(again.is_err_and(|e| crate::error::is_unique_violation(&e)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sale_and_decommission_race() -> anyhow::Result<()> {
+    use crate::inventory::{decommission_robot, RemovalQuery};
+
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let repos = Repositories::database(pool.clone(), test_db.mailer.clone());
+
+    CustomerSeed::new("race_test").insert(&pool).await?;
+    // A robot is either sold or decommissioned, never both
+    for round in 0..10 {
+        let serial = format!("G{}", 1001 + round);
+        RobotSeed::new(&serial, "G1", "V1").insert(&pool).await?;
+
+        let sale = tokio::spawn({
+            let db = test_db.db();
+            async move { db.sell_robot("race_test", "G1", "V1").await }
+        });
+        let removal = tokio::spawn({
+            let robots = repos.robots.clone();
+            async move { decommission_robot(robots.as_ref(), &serial, &RemovalQuery::default()).await }
+        });
+        let sold = sale.await?.unwrap_or(0);
+        let removed = removal.await?.is_ok();
+        assert!(sold + u64::from(removed) <= 1, "round {round}");
+    }
+
+    let both: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM robots r WHERE r.decommissioned_at IS NOT NULL
+        AND EXISTS (SELECT 1 FROM sold s WHERE s.robot_id = r.id)",
+    )
+    .fetch_one(&pool)
+    .await?;
+    assert_eq!(both, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_in_memory_order_waitlist_notification() -> anyhow::Result<()> {
+    let mailer = Arc::new(MemoryMailer::default());
+    let state = AppState::with_parts(
+        db_pool::lazy_pool(),
+        Repositories::in_memory(mailer.clone()),
+        mailer.clone(),
+    );
+
+    let client = TestClient::new(router_with_state(state.clone()));
+    let customer = serde_json::json!({
+        "name": "Flow Test", "email": "flow_test@example.com",
+        "login": "flow_test", "password": "secret"
+    });
+    let res = client.post("/user/create").json(&customer).send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    order_until_notified(state, &mailer, "flow_test", "secret").await
+}
+
+#[tokio::test]
+async fn test_databases_are_isolated() -> anyhow::Result<()> {
+    let (first, second) = (TestDb::new().await?, TestDb::new().await?);
+
+    RobotSeed::new("I1001", "I1", "V1")
+        .insert(&first.pool)
+        .await?;
+    assert_eq!(first.db().find_robot("I1", "V1").await?, 1);
+    assert_eq!(second.db().find_robot("I1", "V1").await?, 0);
+
+    Ok(())
+}
+
+// Method and path of every operation in a route table of api.rs, in OpenAPI notation
+fn routed_operations(operations: Vec<api::Operation>) -> BTreeSet<(String, String)> {
+    operations
+        .into_iter()
+        .map(|operation| {
+            let path: Vec<String> = operation
+                .path
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{param}}}"),
+                    None => segment.to_string(),
+                })
+                .collect();
+            (operation.method.as_str().to_lowercase(), path.join("/"))
+        })
+        .collect()
+}
+
+fn schema_refs(value: &serde_json::Value, refs: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match value.as_str() {
+                    Some(target) if key == "$ref" => refs.push(target.to_string()),
+                    _ => schema_refs(value, refs),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter().for_each(|v| schema_refs(v, refs)),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn test_openapi_matches_routes() -> anyhow::Result<()> {
+    let client = TestClient::new(router_with_state(AppState::in_memory()));
+
+    for (url, doc, server, operations) in [
+        (
+            "/api/v1/openapi.json",
+            openapi::v1(),
+            "/api/v1",
+            api::v1_operations(),
+        ),
+        (
+            "/api/v2/openapi.json",
+            openapi::v2(),
+            "/api/v2",
+            api::v2_operations(),
+        ),
+    ] {
+        let res = client.get(url).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let spec: serde_json::Value = serde_json::from_slice(&res.bytes().await)?;
+        assert_eq!(spec, serde_json::to_value(doc)?);
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        assert_eq!(spec["servers"][0]["url"], server);
+
+        let documented: BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .filter(|key| ["get", "post", "put", "delete", "patch"].contains(&key.as_str()))
+                    .map(move |method| (method.clone(), path.clone()))
+            })
+            .collect();
+        assert_eq!(documented, routed_operations(operations), "{url}");
+
+        let schemas = &spec["components"]["schemas"];
+        let mut refs = Vec::new();
+        schema_refs(&spec, &mut refs);
+        for target in refs {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                schemas.get(name).is_some(),
+                "{target} is not defined in {url}"
+            );
+        }
+        for (schema, field) in [
+            ("Robot", "serial"),
+            ("Customer", "email"),
+            ("CurrentOrder", "login"),
+        ] {
+            assert!(schemas[schema]["properties"].get(field).is_some());
+        }
+    }
+
+    let v2 = openapi::v2();
+    let created = serde_json::to_value(&v2.paths.paths["/robots/create"])?;
+    let response = &created["post"]["responses"]["201"]["content"]["application/json"];
+    assert_eq!(
+        response["schema"]["$ref"],
+        "#/components/schemas/CreatedRobot"
+    );
+
+    let res = client.get("/docs/").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.text().await.contains("swagger"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_versions() -> anyhow::Result<()> {
+    let client = TestClient::new(router_with_state(AppState::in_memory()));
+    let robot = Robot {
+        serial: "0".to_string(),
+        model: "R2".to_string(),
+        version: "D2".to_string(),
+    };
+
+    let res = client
+        .post("/api/v1/robots/create")
+        .json(&robot)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert!(res.headers().get("deprecation").is_none());
+    assert!(res.bytes().await.is_empty());
+
+    let res = client
+        .post("/api/v2/robots/create")
+        .json(&robot)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert!(res.headers().get("deprecation").is_none());
+    let created: serde_json::Value = res.json().await;
+    assert_eq!(created["serial"], "R2002");
+    assert_eq!(created["model"], "R2");
+    assert!(created["created"].is_string());
+
+    // The unversioned path is the deprecated alias of v1
+    let res = client.post("/robots/create").json(&robot).send().await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers()["deprecation"], "@1792281600");
+    assert_eq!(
+        res.headers()[http::header::LINK],
+        "</api/v1/robots/create>; rel=\"successor-version\""
+    );
+    assert!(res.bytes().await.is_empty());
+
+    // Errors of the legacy paths are deprecated as well
+    let res = client.get("/robots/X9999").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.headers()["deprecation"], "@1792281600");
+
+    // So is the document from before versioning, it describes v1
+    let res = client.get("/openapi.json").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["deprecation"], "@1792281600");
+    assert_eq!(
+        res.headers()[http::header::LINK],
+        "</api/v1/openapi.json>; rel=\"successor-version\""
+    );
+    let spec: serde_json::Value = res.json().await;
+    assert_eq!(spec, serde_json::to_value(openapi::v1())?);
+
+    // Routes whose payloads did not change are shared by all versions
+    for url in ["/api/v1/robots", "/api/v2/robots", "/robots"] {
+        let res = client.get(url).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page: serde_json::Value = res.json().await;
+        assert_eq!(page["robots"].as_array().unwrap().len(), 3, "{url}");
+    }
+
+    let res = client.get("/api/v3/robots").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(res.headers().get("deprecation").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_and_version() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let client = test_db.client();
+
+    let res = client.get("/health/live").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client.get("/health/ready").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let readiness: serde_json::Value = res.json().await;
+    assert_eq!(readiness["ready"], true);
+    let checks: Vec<&str> = readiness["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        checks,
+        ["database", "migrations", "order_processor", "notifier"]
+    );
+
+    // Without SMTP_SERVER the placeholder mail server is not checked
+    if std::env::var("SMTP_SERVER").is_err() {
+        assert_eq!(SmtpMailer.check()?, "Skipped, SMTP_SERVER is not set");
+    }
+
+    let res = client.get("/version").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let version: serde_json::Value = res.json().await;
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert!(!version["git_hash"].as_str().unwrap().is_empty());
+    assert_eq!(version["schema_version"], SCHEMA_VERSION);
+    assert_eq!(version["applied_schema_version"], SCHEMA_VERSION);
+
+    Ok(())
+}
+
+// A mail server that cannot be reached
+struct UnreachableMailer;
+
+impl Mailer for UnreachableMailer {
+    fn send(&self, _email: &Email) -> Result<(), anyhow::Error> {
+        anyhow::bail!("Connection refused")
+    }
+
+    fn check(&self) -> Result<String, anyhow::Error> {
+        anyhow::bail!("Connection refused")
+    }
+}
+
+#[tokio::test]
+async fn test_not_ready() -> anyhow::Result<()> {
+    let test_db = TestDb::new().await?;
+    let pool = test_db.pool.clone();
+    let mailer: Arc<dyn Mailer> = Arc::new(UnreachableMailer);
+    let mut state = AppState::with_parts(
+        pool.clone(),
+        Repositories::database(pool.clone(), mailer.clone()),
+        mailer,
+    );
+    state.heartbeat = Heartbeat::stopped(Duration::from_secs(WORKER_HEARTBEAT_TIMEOUT + 1));
+    sqlx::query("DROP TABLE schema_version")
+        .execute(&pool)
+        .await?;
+    let client = TestClient::new(router_with_state(state));
+
+    let res = client.get("/health/ready").send().await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let readiness: serde_json::Value = res.json().await;
+    assert_eq!(readiness["ready"], false);
+    let failed: Vec<&str> = readiness["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|check| check["ok"] == false)
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(failed, ["migrations", "order_processor", "notifier"]);
+
+    // Liveness does not depend on anything
+    let res = client.get("/health/live").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client.get("/version").send().await;
+    let version: serde_json::Value = res.json().await;
+    assert!(version["applied_schema_version"].is_null());
+
+    Ok(())
+}
+
+// Value of one series in the Prometheus text format, 0 if it is not there
+fn metric_value(metrics: &str, series: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .map_or(0.0, |value| value.parse().unwrap())
+}
+
+// Metrics are shared by every test of the process, so each assertion uses
+// models of its own or only checks that a count went up
+#[tokio::test]
+async fn test_metrics() -> anyhow::Result<()> {
+    let mailer = Arc::new(MemoryMailer::default());
+    let state = AppState::with_parts(
+        db_pool::lazy_pool(),
+        Repositories::in_memory(mailer.clone()),
+        mailer.clone(),
+    );
+    let client = TestClient::new(router_with_state(state.clone()));
+
+    let customer = serde_json::json!({
+        "name": "Metrics Test", "email": "metrics_test@example.com",
+        "login": "metrics_test", "password": "secret"
+    });
+    let res = client
+        .post("/api/v1/user/create")
+        .json(&customer)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let robot = serde_json::json!({"serial": "0", "model": "Q7", "version": "V1"});
+    for url in ["/api/v1/robots/create", "/robots/create"] {
+        let res = client.post(url).json(&robot).send().await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+    let res = client
+        .post("/api/v1/robots/remove")
+        .json(&serde_json::json!({
+            "serial": "Q7001", "model": "Q7", "version": "V1"
+        }))
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/api/v1/robots/Q7002").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get("/no/such/path").send().await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let order = serde_json::json!({
+        "login": "metrics_test", "password": "secret", "model": "Q8", "version": "V1"
+    });
+    let res = client
+        .post("/api/v1/robots/order")
+        .json(&order)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = client.get("/metrics").send().await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()[CONTENT_TYPE]
+        .to_str()?
+        .starts_with("text/plain"));
+    let metrics = res.text().await;
+    assert_eq!(
+        metric_value(&metrics, r#"robots_created_total{model="Q7"}"#),
+        2.0
+    );
+    assert_eq!(
+        metric_value(&metrics, r#"robots_removed_total{model="Q7"}"#),
+        1.0
+    );
+    assert_eq!(metric_value(&metrics, r#"waitlist_depth{model="Q8"}"#), 1.0);
+    for series in [
+        r#"http_requests_total{method="POST",route="/api/v1/robots/create",status="201"}"#,
+        r#"http_requests_total{method="POST",route="/robots/create",status="201"}"#,
+        r#"http_requests_total{method="GET",route="unmatched",status="404"}"#,
+        r#"http_request_duration_seconds_count{method="GET",route="/api/v1/robots/:serial"}"#,
+    ] {
+        assert!(metric_value(&metrics, series) >= 1.0, "{series}");
+    }
+    assert_eq!(
+        metric_value(&metrics, "db_pool_max_connections"),
+        f64::from(DB_MAX_CONNECTIONS)
+    );
+    let fulfilled = metric_value(&metrics, "order_fulfilment_seconds_count");
+    let sent = metric_value(&metrics, r#"emails_total{kind="order",outcome="sent"}"#);
+
+    // A robot for the waiting customer, who is notified by the order processor
+    let robot = serde_json::json!({"serial": "0", "model": "Q8", "version": "V1"});
+    let res = client
+        .post("/api/v1/robots/create")
+        .json(&robot)
+        .send()
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    state.orders.process().await;
+    assert_eq!(mailer.sent().len(), 1);
+
+    let metrics = client.get("/metrics").send().await.text().await;
+    assert_eq!(metric_value(&metrics, r#"waitlist_depth{model="Q8"}"#), 0.0);
+    assert!(metric_value(&metrics, "order_fulfilment_seconds_count") > fulfilled);
+    assert!(metric_value(&metrics, r#"emails_total{kind="order",outcome="sent"}"#) > sent);
+
+    Ok(())
+}
@@ -1,65 +1,61 @@
-use anyhow::Result;
-use axum::Extension;
-use axum::{extract::Json, http::StatusCode};
+use axum::extract::State;
+use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use utoipa::ToSchema;
 use validator::Validate;
 use validator_derive::Validate;
 
-#[derive(Debug, Deserialize, Serialize, Validate)]
+use crate::error::{AppError, AppJson};
+use crate::repository::Repositories;
+
+#[derive(Debug, Clone, Deserialize, Serialize, Validate, ToSchema)]
 pub struct Customer {
     #[validate(length(min = 1))]
+    #[schema(min_length = 1)]
     pub name: String,
     #[validate(email)]
+    #[schema(example = "ann@example.com")]
     pub email: String,
     #[validate(length(min = 3))]
+    #[schema(min_length = 3)]
     pub login: String,
     #[validate(length(min = 3))]
+    #[schema(min_length = 3, format = Password)]
     pub password: String,
 }
 
-impl Customer {
-    async fn insert(&self, pool: &sqlx::Pool<sqlx::Postgres>) -> Result<u64, sqlx::Error> {
-        let statement =
-            ("INSERT INTO customers (name, email, login, password) VALUES ($1, $2, $3, $4)")
-                .to_string();
-
-        sqlx::query(&statement)
-            .bind(&self.name)
-            .bind(&self.email)
-            .bind(&self.login)
-            .bind(&self.password)
-            .execute(pool)
-            .await
-            .map(|result| result.rows_affected())
-    }
-}
-
 // in Axum 0.6.0 and later, the extractor that consumes the request body
 // must be last in the list of route handler arguments.
-// This means that Json<Customer> must be the last argument in the route handler
+// This means that Json<Customer> must be the last argument in the route handler.
+// A taken email or login is a 409 conflict.
+#[utoipa::path(
+    post,
+    path = "/user/create",
+    tag = "customers",
+    request_body = Customer,
+    responses(
+        (status = 200, description = "Customer registered"),
+        (status = 400, description = "Invalid fields", body = AppError),
+        (status = 409, description = "The email or login is taken", body = AppError),
+    )
+)]
 pub async fn create_customer(
-    (Extension(pool), Json(customer)): (Extension<PgPool>, Json<Customer>),
-) -> Result<StatusCode, StatusCode> {
-    if customer.validate().is_err() {
-        return Err(StatusCode::BAD_REQUEST);
-    }
+    State(repos): State<Repositories>,
+    AppJson(customer): AppJson<Customer>,
+) -> Result<StatusCode, AppError> {
+    customer.validate()?;
 
-    match customer.insert(&pool).await {
-        Ok(rows_affected) if rows_affected > 0 => {
+    match repos.customers.insert_customer(&customer).await {
+        Ok(()) => {
             println!("User has been added");
             Ok(StatusCode::OK)
         }
-        Ok(_) => {
-            println!("User was not added");
-            Err(StatusCode::NOT_FOUND)
-        }
         Err(e) => {
             eprintln!(
                 "An error occurred while inserting user into the database: {}",
                 e
             );
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err(e.into())
         }
     }
 }
@@ -0,0 +1,49 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::error::{AppError, AppJson};
+use crate::repository::Repositories;
+use crate::robot::Robot;
+
+// Request and response types of /api/v2 that differ from v1, with their handlers.
+// Every other endpoint is shared with v1, see api.rs.
+
+// v1 answers /robots/create with an empty body, v2 returns the stored robot
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedRobot {
+    #[schema(example = "R2001")]
+    pub serial: String,
+    #[schema(example = "R2")]
+    pub model: String,
+    #[schema(example = "D2")]
+    pub version: String,
+    pub created: NaiveDateTime,
+}
+
+#[utoipa::path(
+    post,
+    path = "/robots/create",
+    tag = "robots",
+    request_body = Robot,
+    responses(
+        (status = 201, description = "Robot created", body = CreatedRobot),
+        (status = 400, description = "Invalid serial, model or version", body = AppError),
+    )
+)]
+pub async fn create_robot_handler(
+    State(repos): State<Repositories>,
+    AppJson(robot): AppJson<Robot>,
+) -> Result<(StatusCode, Json<CreatedRobot>), AppError> {
+    let (serial, created) = robot.store_robot(repos.robots.as_ref()).await?;
+    let created = CreatedRobot {
+        serial,
+        model: robot.model,
+        version: robot.version,
+        created,
+    };
+    Ok((StatusCode::CREATED, Json(created)))
+}